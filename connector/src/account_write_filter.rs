@@ -1,26 +1,65 @@
 use crate::{
-    chain_data::{AccountData, ChainData, ChainDataMetrics, SlotData},
+    chain_data::{
+        AccountData, AccountState, ChainData, ChainDataMetrics, HistoryRetention, SlotData,
+    },
     metrics::Metrics,
-    AccountWrite, SlotUpdate,
+    queue,
+    shutdown::CancellationToken,
+    AccountFilter, AccountWrite, QueuePolicy, SlotUpdate,
 };
 
 use async_trait::async_trait;
 use log::*;
 use solana_sdk::{account::WritableAccount, pubkey::Pubkey, stake_history::Epoch};
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{HashMap, HashSet},
     sync::Arc,
     time::{Duration, Instant},
 };
 
 #[async_trait]
 pub trait AccountWriteSink {
-    async fn process(&self, pubkey: &Pubkey, account: &AccountData) -> Result<(), String>;
+    /// Called with the newest live state of a matched account, which may be a close
+    async fn process(&self, pubkey: &Pubkey, account: AccountState<'_>) -> Result<(), String>;
+}
+
+/// Matches whole classes of accounts, for routes that can't list their pubkeys up front
+#[derive(Clone)]
+pub enum AccountMatcher {
+    /// Accounts owned by the program that pass all `account_filters`, like a memcmp on
+    /// the discriminator of an anchor account or a data size. On sliced writes only the
+    /// memcmps on bytes within the slice can pass, see `AccountFilter::matches_slice`.
+    Owner {
+        program_id: Pubkey,
+        account_filters: Vec<AccountFilter>,
+    },
+    /// Accounts that the predicate accepts a write of
+    Predicate(Arc<dyn Fn(&AccountWrite) -> bool + Send + Sync>),
+}
+
+impl AccountMatcher {
+    pub fn matches(&self, account_write: &AccountWrite) -> bool {
+        match self {
+            AccountMatcher::Owner {
+                program_id,
+                account_filters,
+            } => {
+                account_write.owner == *program_id
+                    && account_filters
+                        .iter()
+                        .all(|f| f.matches_slice(&account_write.data, account_write.data_slice))
+            }
+            AccountMatcher::Predicate(predicate) => predicate(account_write),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct AccountWriteRoute {
     pub matched_pubkeys: Vec<Pubkey>,
+    /// Accounts with a write that matches one of these are routed here from then on,
+    /// also once they are closed
+    pub matchers: Vec<AccountMatcher>,
     pub sink: Arc<dyn AccountWriteSink + Send + Sync>,
     pub timeout_interval: Duration,
 }
@@ -34,27 +73,34 @@ struct AccountWriteRecord {
 
 pub fn init(
     routes: Vec<AccountWriteRoute>,
+    queue_policy: &QueuePolicy,
+    history_retention: Option<HistoryRetention>,
     metrics_sender: Metrics,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<(
     async_channel::Sender<AccountWrite>,
     async_channel::Sender<SlotUpdate>,
 )> {
     let (account_write_queue_sender, account_write_queue_receiver) =
-        async_channel::unbounded::<AccountWrite>();
+        queue::channel::<AccountWrite>(queue_policy);
 
     // Slot updates flowing from the outside into this processing thread. From
     // there the AccountWriteRoute::sink() callback is triggered.
-    let (slot_queue_sender, slot_queue_receiver) = async_channel::unbounded::<SlotUpdate>();
+    let (slot_queue_sender, slot_queue_receiver) = queue::channel::<SlotUpdate>(queue_policy);
 
     let mut chain_data = ChainData::new();
+    chain_data.set_history_retention(history_retention);
     let mut chain_data_metrics = ChainDataMetrics::new(&metrics_sender);
 
     let mut last_updated = HashMap::<String, AccountWriteRecord>::new();
 
-    let all_queue_pks: BTreeSet<Pubkey> = routes
+    // Pubkeys whose live write changed and that still need to be passed to the sinks
+    let mut pending_pubkeys = HashSet::<Pubkey>::new();
+
+    // Pubkeys of each route, grows as writes of new accounts match the route's matchers
+    let mut route_pubkeys: Vec<HashSet<Pubkey>> = routes
         .iter()
-        .flat_map(|r| r.matched_pubkeys.iter())
-        .copied()
+        .map(|r| r.matched_pubkeys.iter().copied().collect())
         .collect();
 
     // update handling thread, reads both slots and account updates
@@ -62,18 +108,29 @@ pub fn init(
         loop {
             tokio::select! {
                 Ok(account_write) = account_write_queue_receiver.recv() => {
-                    if !all_queue_pks.contains(&account_write.pubkey) {
+                    let mut is_routed = false;
+                    for (route, pubkeys) in routes.iter().zip(route_pubkeys.iter_mut()) {
+                        if pubkeys.contains(&account_write.pubkey) {
+                            is_routed = true;
+                        } else if route.matchers.iter().any(|m| m.matches(&account_write)) {
+                            debug!("account matched a route {:?}", account_write.pubkey);
+                            pubkeys.insert(account_write.pubkey);
+                            is_routed = true;
+                        }
+                    }
+                    if !is_routed {
                         trace!("account write skipped {:?}", account_write.pubkey);
                         continue;
                     } else {
                         trace!("account write processed {:?}", account_write.pubkey);
                     }
 
-                    chain_data.update_account(
+                    let changed = chain_data.update_account(
                         account_write.pubkey,
                         AccountData {
                             slot: account_write.slot,
                             write_version: account_write.write_version,
+                            data_slice: account_write.data_slice,
                             account: WritableAccount::create(
                                 account_write.lamports,
                                 account_write.data.clone(),
@@ -83,19 +140,26 @@ pub fn init(
                             ),
                         },
                     );
+                    if changed {
+                        pending_pubkeys.insert(account_write.pubkey);
+                    }
                 }
                 Ok(slot_update) = slot_queue_receiver.recv() => {
                     trace!("slot update processed {:?}", slot_update);
-                    chain_data.update_slot(SlotData {
+                    let changed = chain_data.update_slot(SlotData {
                         slot: slot_update.slot,
                         parent: slot_update.parent,
                         status: slot_update.status,
                         chain: 0,
                     });
-
+                    pending_pubkeys.extend(changed);
+                }
+                _ = shutdown.cancelled() => {
+                    info!("shutting down account write filter...");
+                    break;
                 }
                 else => {
-                    warn!("channels closed, filter shutting down pks={all_queue_pks:?}");
+                    warn!("channels closed, filter shutting down routes={}", routes.len());
                     break;
                 }
 
@@ -103,10 +167,16 @@ pub fn init(
 
             chain_data_metrics.report(&chain_data);
 
-            for route in routes.iter() {
-                for pk in route.matched_pubkeys.iter() {
-                    match chain_data.account(pk) {
-                        Ok(account_info) => {
+            // pubkeys that were throttled or failed in a sink stay pending
+            let mut retry_pubkeys = HashSet::<Pubkey>::new();
+            for (route, pubkeys) in routes.iter().zip(route_pubkeys.iter()) {
+                for pk in pending_pubkeys.iter() {
+                    if !pubkeys.contains(pk) {
+                        continue;
+                    }
+                    match chain_data.account_state(pk) {
+                        Ok(account_state) => {
+                            let account_info = account_state.write();
                             let pk_b58 = pk.to_string();
                             if let Some(record) = last_updated.get(&pk_b58) {
                                 let is_unchanged = account_info.slot == record.slot
@@ -115,11 +185,14 @@ pub fn init(
                                     record.timestamp.elapsed() < route.timeout_interval;
                                 if is_unchanged || is_throttled {
                                     trace!("skipped is_unchanged={is_unchanged} is_throttled={is_throttled} pk={pk_b58}");
+                                    if !is_unchanged {
+                                        retry_pubkeys.insert(*pk);
+                                    }
                                     continue;
                                 }
                             };
 
-                            match route.sink.process(pk, account_info).await {
+                            match route.sink.process(pk, account_state).await {
                                 Ok(()) => {
                                     // todo: metrics
                                     last_updated.insert(
@@ -133,6 +206,7 @@ pub fn init(
                                 }
                                 Err(skip_reason) => {
                                     debug!("sink process skipped reason={skip_reason} pk={pk_b58}");
+                                    retry_pubkeys.insert(*pk);
                                     // todo: metrics
                                 }
                             }
@@ -144,6 +218,7 @@ pub fn init(
                     }
                 }
             }
+            pending_pubkeys = retry_pubkeys;
         }
     });
 
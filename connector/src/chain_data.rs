@@ -1,16 +1,35 @@
 use {
-    solana_sdk::account::{AccountSharedData, ReadableAccount},
+    futures::FutureExt,
+    log::*,
+    serde_derive::{Deserialize, Serialize},
+    solana_sdk::account::{AccountSharedData, ReadableAccount, WritableAccount},
     solana_sdk::pubkey::Pubkey,
-    std::collections::HashMap,
+    std::{
+        collections::{BTreeMap, HashMap, HashSet, VecDeque},
+        fs::File,
+        io::{BufReader, BufWriter, Write},
+        path::Path,
+        sync::{Arc, RwLock},
+        time::{Duration, Instant},
+    },
+    tokio::task::JoinHandle,
 };
 
-use crate::metrics::*;
+use crate::{metrics::*, shutdown::CancellationToken, ChainDataPersistenceConfig, DataSlice};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// Commitment of a slot, ordered from the least to the most committed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum SlotStatus {
-    Rooted,
-    Confirmed,
     Processed,
+    Confirmed,
+    Rooted,
+}
+
+impl SlotStatus {
+    /// Whether a slot with this status has at least the `required` commitment
+    pub fn reaches(&self, required: SlotStatus) -> bool {
+        *self >= required
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -25,6 +44,8 @@ pub struct SlotData {
 pub struct AccountData {
     pub slot: u64,
     pub write_version: u64,
+    /// The range of the account data that `account` holds, None if it is the full data
+    pub data_slice: Option<DataSlice>,
     pub account: AccountSharedData,
 }
 
@@ -32,8 +53,64 @@ impl AccountData {
     pub fn is_newer_than(&self, slot: u64, write_version: u64) -> bool {
         (self.slot > slot) || (self.slot == slot && self.write_version > write_version)
     }
+
+    /// Whether this write closed the account
+    pub fn is_deleted(&self) -> bool {
+        self.account.lamports() == 0
+    }
+}
+
+/// Newest live state of an account
+#[derive(Clone, Copy, Debug)]
+pub enum AccountState<'a> {
+    Live(&'a AccountData),
+    /// The account was closed by this write
+    Deleted(&'a AccountData),
+}
+
+impl<'a> AccountState<'a> {
+    /// The write that produced this state
+    pub fn write(&self) -> &'a AccountData {
+        match self {
+            AccountState::Live(write) => write,
+            AccountState::Deleted(write) => write,
+        }
+    }
 }
 
+/// Live write of an account before and after a reorg, as (slot, write_version)
+#[derive(Clone, Debug)]
+pub struct ReorgAccountChange {
+    pub pubkey: Pubkey,
+    pub old: Option<(u64, u64)>,
+    pub new: Option<(u64, u64)>,
+}
+
+/// The best chain switched to a fork that doesn't contain the previous best slot
+#[derive(Clone, Debug)]
+pub struct ReorgEvent {
+    pub old_tip: u64,
+    pub new_tip: u64,
+    /// Newest slot that both chains share
+    pub common_ancestor: u64,
+    /// Accounts whose live write changed due to the switch, that is from or to a write
+    /// after the common ancestor. Changes from rooting in the same update are left out.
+    pub accounts: Vec<ReorgAccountChange>,
+}
+
+/// How much rooted history to keep beyond the newest rooted write of each account
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryRetention {
+    /// Keep rooted writes from this many slots before the newest rooted slot
+    Slots(u64),
+    /// Keep the newest rooted writes as long as their data fits into this many bytes
+    Bytes(usize),
+}
+
+/// Number of reorg events that are kept until someone takes them
+const MAX_PENDING_REORG_EVENTS: usize = 100;
+
 /// Track slots and account writes
 ///
 /// - use account() to retrieve the current best data for an account.
@@ -43,11 +120,21 @@ pub struct ChainData {
     slots: HashMap<u64, SlotData>,
     /// writes to accounts, only the latest rooted write an newer are retained
     accounts: HashMap<Pubkey, Vec<AccountData>>,
+    /// pubkeys that have a write in the slot, for all writes in `accounts`
+    writes_by_slot: BTreeMap<u64, HashSet<Pubkey>>,
     newest_rooted_slot: u64,
     newest_processed_slot: u64,
     best_chain_slot: u64,
     account_versions_stored: usize,
     account_bytes_stored: usize,
+    /// reorgs not yet retrieved with take_reorg_events(), only kept if enabled
+    reorg_events: VecDeque<ReorgEvent>,
+    reorg_events_enabled: bool,
+    reorg_count: u64,
+    history_retention: Option<HistoryRetention>,
+    /// rooted writes older than the newest rooted write of their account
+    history_versions_stored: usize,
+    history_bytes_stored: usize,
 }
 
 impl ChainData {
@@ -55,15 +142,145 @@ impl ChainData {
         Self {
             slots: HashMap::new(),
             accounts: HashMap::new(),
+            writes_by_slot: BTreeMap::new(),
             newest_rooted_slot: 0,
             newest_processed_slot: 0,
             best_chain_slot: 0,
             account_versions_stored: 0,
             account_bytes_stored: 0,
+            reorg_events: VecDeque::new(),
+            reorg_events_enabled: false,
+            reorg_count: 0,
+            history_retention: None,
+            history_versions_stored: 0,
+            history_bytes_stored: 0,
+        }
+    }
+
+    /// Keep rooted account history for `account_at_slot()`, None keeps only the newest
+    /// rooted write. Takes effect when the next slot is rooted.
+    pub fn set_history_retention(&mut self, retention: Option<HistoryRetention>) {
+        self.history_retention = retention;
+    }
+
+    /// Keep a `ReorgEvent` for every reorg until `take_reorg_events()` takes it. Off by
+    /// default, reorgs are only counted then.
+    pub fn enable_reorg_events(&mut self) {
+        self.reorg_events_enabled = true;
+    }
+
+    /// Add a slot update, returns the pubkeys whose live write changed because of it
+    ///
+    /// That covers writes becoming live or dead due to a fork switch as well as writes
+    /// that were dropped because a slot got rooted.
+    pub fn update_slot(&mut self, new_slot: SlotData) -> Vec<Pubkey> {
+        // Only writes in slots >= newest_rooted_slot can change liveness, older slots
+        // are not tracked and always live. Slot updates for old slots may arrive late though.
+        let updated_slot = new_slot.slot;
+        let mut candidate_pubkeys: HashSet<Pubkey> = self
+            .writes_by_slot
+            .range(self.newest_rooted_slot..)
+            .flat_map(|(_, pubkeys)| pubkeys.iter().copied())
+            .collect();
+        if let Some(pubkeys) = self.writes_by_slot.get(&updated_slot) {
+            candidate_pubkeys.extend(pubkeys);
+        }
+        let candidates: Vec<(Pubkey, Option<(u64, u64)>)> = candidate_pubkeys
+            .into_iter()
+            .map(|pubkey| {
+                let old = self
+                    .accounts
+                    .get(&pubkey)
+                    .and_then(|writes| self.live_write_version(writes));
+                (pubkey, old)
+            })
+            .collect();
+
+        let old_tip = self.best_chain_slot;
+        self.apply_slot_update(new_slot);
+
+        let changes: Vec<ReorgAccountChange> = candidates
+            .into_iter()
+            .filter_map(|(pubkey, old)| {
+                let new = self
+                    .accounts
+                    .get(&pubkey)
+                    .and_then(|writes| self.live_write_version(writes));
+                (new != old).then_some(ReorgAccountChange { pubkey, old, new })
+            })
+            .collect();
+        let changed_pubkeys = changes.iter().map(|c| c.pubkey).collect();
+
+        let new_tip = self.best_chain_slot;
+        if old_tip != 0 && new_tip != old_tip {
+            if let Some(common_ancestor) = self.fork_point(old_tip, new_tip) {
+                self.reorg_count += 1;
+                if self.reorg_events_enabled {
+                    if self.reorg_events.len() >= MAX_PENDING_REORG_EVENTS {
+                        warn!("dropping reorg event, nobody is taking them");
+                        self.reorg_events.pop_front();
+                    }
+                    let on_forks = |write: Option<(u64, u64)>| {
+                        write.map_or(false, |(slot, _)| slot > common_ancestor)
+                    };
+                    self.reorg_events.push_back(ReorgEvent {
+                        old_tip,
+                        new_tip,
+                        common_ancestor,
+                        accounts: changes
+                            .into_iter()
+                            .filter(|c| on_forks(c.old) || on_forks(c.new))
+                            .collect(),
+                    });
+                }
+            }
+        }
+
+        changed_pubkeys
+    }
+
+    /// If `new_tip` is not a descendant of `old_tip`, the newest slot both chains share
+    ///
+    /// Returns None when the new chain is not known far enough to tell.
+    fn fork_point(&self, old_tip: u64, new_tip: u64) -> Option<u64> {
+        // walk the new chain down to the height of the old tip
+        let mut new_chain = HashSet::new();
+        let mut slot = new_tip;
+        while slot > old_tip {
+            new_chain.insert(slot);
+            slot = self.slots.get(&slot).and_then(|s| s.parent)?;
+        }
+        if slot == old_tip {
+            return None;
+        }
+
+        // it's a fork: collect the rest of the new chain and find where the old one meets it
+        loop {
+            new_chain.insert(slot);
+            match self.slots.get(&slot).and_then(|s| s.parent) {
+                Some(parent) => slot = parent,
+                None => break,
+            }
+        }
+        let mut slot = old_tip;
+        loop {
+            if new_chain.contains(&slot) {
+                return Some(slot);
+            }
+            match self.slots.get(&slot).and_then(|s| s.parent) {
+                Some(parent) => slot = parent,
+                // the chains meet below what is tracked, which is rooted
+                None => return Some(self.newest_rooted_slot),
+            }
         }
     }
 
-    pub fn update_slot(&mut self, new_slot: SlotData) {
+    /// Reorgs that happened since the last call, oldest first, see `enable_reorg_events()`
+    pub fn take_reorg_events(&mut self) -> Vec<ReorgEvent> {
+        self.reorg_events.drain(..).collect()
+    }
+
+    fn apply_slot_update(&mut self, new_slot: SlotData) {
         let new_processed_head = new_slot.slot > self.newest_processed_slot;
         if new_processed_head {
             self.newest_processed_slot = new_slot.slot;
@@ -120,38 +337,75 @@ impl ChainData {
 
         if new_rooted_head {
             // for each account, preserve only writes > newest_rooted_slot, or the newest
-            // rooted write
+            // rooted write, plus the rooted history that the retention asks for
             self.account_versions_stored = 0;
             self.account_bytes_stored = 0;
+            self.history_versions_stored = 0;
+            self.history_bytes_stored = 0;
+
+            let oldest_history_slot = match self.history_retention {
+                None => u64::MAX,
+                Some(HistoryRetention::Slots(slots)) => {
+                    self.newest_rooted_slot.saturating_sub(slots)
+                }
+                // trimmed to the budget below
+                Some(HistoryRetention::Bytes(_)) => 0,
+            };
 
-            for (_, writes) in self.accounts.iter_mut() {
+            self.accounts.retain(|pubkey, writes| {
+                let is_rooted_write = |w: &AccountData| {
+                    is_rooted_write(
+                        &self.slots,
+                        self.newest_rooted_slot,
+                        self.best_chain_slot,
+                        w,
+                    )
+                };
                 let newest_rooted_write = writes
                     .iter()
                     .rev()
-                    .find(|w| {
-                        w.slot <= self.newest_rooted_slot
-                            && self
-                                .slots
-                                .get(&w.slot)
-                                .map(|s| {
-                                    // sometimes we seem not to get notifications about slots
-                                    // getting rooted, hence assume non-uncle slots < newest_rooted_slot
-                                    // are rooted too
-                                    s.status == SlotStatus::Rooted
-                                        || s.chain == self.best_chain_slot
-                                })
-                                // preserved account writes for deleted slots <= newest_rooted_slot
-                                // are expected to be rooted
-                                .unwrap_or(true)
-                    })
+                    .find(|w| is_rooted_write(w))
                     .map(|w| w.slot)
                     // no rooted write found: produce no effect, since writes > newest_rooted_slot are retained anyway
                     .unwrap_or(self.newest_rooted_slot + 1);
-                writes
-                    .retain(|w| w.slot == newest_rooted_write || w.slot > self.newest_rooted_slot);
+                writes.retain(|w| {
+                    let keep = w.slot == newest_rooted_write
+                        || w.slot > self.newest_rooted_slot
+                        || (w.slot < newest_rooted_write
+                            && w.slot >= oldest_history_slot
+                            && is_rooted_write(w));
+                    if !keep {
+                        unindex_write(&mut self.writes_by_slot, w.slot, pubkey);
+                    }
+                    keep
+                });
+
+                // a rooted close without newer writes: the account is gone for good
+                if writes
+                    .last()
+                    .map(|w| w.slot == newest_rooted_write && w.is_deleted())
+                    .unwrap_or(false)
+                {
+                    for w in writes.iter() {
+                        unindex_write(&mut self.writes_by_slot, w.slot, pubkey);
+                    }
+                    return false;
+                }
+
                 self.account_versions_stored += writes.len();
                 self.account_bytes_stored +=
-                    writes.iter().map(|w| w.account.data().len()).sum::<usize>()
+                    writes.iter().map(|w| w.account.data().len()).sum::<usize>();
+                for w in writes.iter().filter(|w| w.slot < newest_rooted_write) {
+                    self.history_versions_stored += 1;
+                    self.history_bytes_stored += w.account.data().len();
+                }
+                true
+            });
+
+            if let Some(HistoryRetention::Bytes(budget)) = self.history_retention {
+                if self.history_bytes_stored > budget {
+                    self.trim_history(budget);
+                }
             }
 
             // now it's fine to drop any slots before the new rooted head
@@ -160,12 +414,83 @@ impl ChainData {
         }
     }
 
-    pub fn update_account(&mut self, pubkey: Pubkey, account: AccountData) {
+    /// Drop the oldest history writes until the history data fits into `budget` bytes
+    fn trim_history(&mut self, budget: usize) {
+        // history writes are the ones before the newest rooted write of their account
+        let slots = &self.slots;
+        let (newest_rooted_slot, best_chain_slot) = (self.newest_rooted_slot, self.best_chain_slot);
+        let history_boundary = |writes: &[AccountData]| {
+            writes
+                .iter()
+                .rev()
+                .find(|w| is_rooted_write(slots, newest_rooted_slot, best_chain_slot, w))
+                .map(|w| w.slot)
+                .unwrap_or(0)
+        };
+
+        // find the oldest slot whose history still fits, newest slots first
+        let mut sizes: Vec<(u64, usize)> = self
+            .accounts
+            .values()
+            .flat_map(|writes| {
+                let boundary = history_boundary(writes);
+                writes
+                    .iter()
+                    .filter(move |w| w.slot < boundary)
+                    .map(|w| (w.slot, w.account.data().len()))
+            })
+            .collect();
+        sizes.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+        let mut total = 0;
+        let mut oldest_kept_slot = 0;
+        for (slot, bytes) in sizes {
+            total += bytes;
+            if total > budget {
+                oldest_kept_slot = slot + 1;
+                break;
+            }
+        }
+
+        for (pubkey, writes) in self.accounts.iter_mut() {
+            let boundary = history_boundary(writes);
+            writes.retain(|w| {
+                let dropped = w.slot < boundary && w.slot < oldest_kept_slot;
+                if dropped {
+                    unindex_write(&mut self.writes_by_slot, w.slot, pubkey);
+                    self.account_versions_stored -= 1;
+                    self.account_bytes_stored -= w.account.data().len();
+                    self.history_versions_stored -= 1;
+                    self.history_bytes_stored -= w.account.data().len();
+                }
+                !dropped
+            });
+        }
+    }
+
+    /// Add an account write, returns true if it changed the live write of the account
+    pub fn update_account(&mut self, pubkey: Pubkey, account: AccountData) -> bool {
+        let previous = self
+            .accounts
+            .get(&pubkey)
+            .and_then(|writes| self.live_write_version(writes));
+        self.insert_account_write(pubkey, account);
+        let current = self
+            .accounts
+            .get(&pubkey)
+            .and_then(|writes| self.live_write_version(writes));
+        current != previous
+    }
+
+    fn insert_account_write(&mut self, pubkey: Pubkey, account: AccountData) {
         use std::collections::hash_map::Entry;
         match self.accounts.entry(pubkey) {
             Entry::Vacant(v) => {
                 self.account_versions_stored += 1;
                 self.account_bytes_stored += account.account.data().len();
+                self.writes_by_slot
+                    .entry(account.slot)
+                    .or_default()
+                    .insert(pubkey);
                 v.insert(vec![account]);
             }
             Entry::Occupied(o) => {
@@ -185,6 +510,10 @@ impl ChainData {
                 } else {
                     self.account_versions_stored += 1;
                     self.account_bytes_stored += account.account.data().len();
+                    self.writes_by_slot
+                        .entry(account.slot)
+                        .or_default()
+                        .insert(pubkey);
                     v.insert(pos, account);
                 }
             }
@@ -204,6 +533,72 @@ impl ChainData {
             .unwrap_or(write.slot <= self.newest_rooted_slot || write.slot > self.best_chain_slot)
     }
 
+    /// Ref to the write of the pubkey that was current at `slot`
+    ///
+    /// Looks at writes on the chain that leads to `slot`. Rooted writes older than the
+    /// newest rooted write are only available if a history retention is set.
+    pub fn account_at_slot<'a>(
+        &'a self,
+        pubkey: &Pubkey,
+        slot: u64,
+    ) -> anyhow::Result<&'a AccountData> {
+        // unrooted slots on the chain leading to `slot`
+        let mut chain = HashSet::new();
+        let mut chain_slot = slot;
+        while chain_slot > self.newest_rooted_slot && chain.insert(chain_slot) {
+            match self.slots.get(&chain_slot).and_then(|s| s.parent) {
+                Some(parent) => chain_slot = parent,
+                None => break,
+            }
+        }
+
+        let writes = self
+            .accounts
+            .get(pubkey)
+            .ok_or_else(|| anyhow::anyhow!("account {} not found", pubkey))?;
+        // writes are ordered by slot, the first write at or before `slot` that is on its
+        // chain is the one that was current
+        let first_after = writes.partition_point(|w| w.slot <= slot);
+        writes[..first_after]
+            .iter()
+            .rev()
+            .find(|w| {
+                if w.slot > self.newest_rooted_slot {
+                    chain.contains(&w.slot)
+                } else {
+                    self.is_account_write_live(w)
+                }
+            })
+            .filter(|w| !w.is_deleted())
+            .ok_or_else(|| anyhow::anyhow!("account {} has no data at slot {}", pubkey, slot))
+    }
+
+    /// Commitment that the slot of a live write has reached
+    fn write_status(&self, write: &AccountData) -> SlotStatus {
+        if write.slot <= self.newest_rooted_slot {
+            // live writes at or before the newest rooted slot are on the rooted chain,
+            // even if the slot's own rooted notification never arrived
+            return SlotStatus::Rooted;
+        }
+        self.slots
+            .get(&write.slot)
+            .map(|s| s.status)
+            .unwrap_or(SlotStatus::Processed)
+    }
+
+    fn is_account_write_at_commitment(&self, write: &AccountData, status: SlotStatus) -> bool {
+        self.is_account_write_live(write) && self.write_status(write).reaches(status)
+    }
+
+    /// (slot, write_version) of the most recent live write
+    fn live_write_version(&self, writes: &[AccountData]) -> Option<(u64, u64)> {
+        writes
+            .iter()
+            .rev()
+            .find(|w| self.is_account_write_live(w))
+            .map(|w| (w.slot, w.write_version))
+    }
+
     /// Cloned snapshot of all the most recent live writes per pubkey
     pub fn accounts_snapshot(&self) -> HashMap<Pubkey, AccountData> {
         self.accounts
@@ -212,29 +607,92 @@ impl ChainData {
                 let latest_good_write = writes
                     .iter()
                     .rev()
-                    .find(|w| self.is_account_write_live(w))?;
+                    .find(|w| self.is_account_write_live(w))
+                    .filter(|w| !w.is_deleted())?;
                 Some((*pubkey, latest_good_write.clone()))
             })
             .collect()
     }
 
-    /// Ref to the most recent live write of the pubkey
+    /// Ref to the most recent live write of the pubkey, fails if the account was closed
     pub fn account<'a>(&'a self, pubkey: &Pubkey) -> anyhow::Result<&'a AccountData> {
-        self.accounts
+        match self.account_state(pubkey)? {
+            AccountState::Live(write) => Ok(write),
+            AccountState::Deleted(write) => Err(anyhow::anyhow!(
+                "account {} was deleted in slot {}",
+                pubkey,
+                write.slot
+            )),
+        }
+    }
+
+    /// The most recent live write of the pubkey, telling closed accounts apart
+    pub fn account_state<'a>(&'a self, pubkey: &Pubkey) -> anyhow::Result<AccountState<'a>> {
+        let write = self
+            .accounts
             .get(pubkey)
             .ok_or_else(|| anyhow::anyhow!("account {} not found", pubkey))?
             .iter()
             .rev()
             .find(|w| self.is_account_write_live(w))
-            .ok_or_else(|| anyhow::anyhow!("account {} has no live data", pubkey))
+            .ok_or_else(|| anyhow::anyhow!("account {} has no live data", pubkey))?;
+        Ok(if write.is_deleted() {
+            AccountState::Deleted(write)
+        } else {
+            AccountState::Live(write)
+        })
     }
 
+    /// Most recent live writes, skipping closed accounts
     pub fn iter_accounts<'a>(&'a self) -> impl Iterator<Item = (&'a Pubkey, &'a AccountData)> {
         self.accounts.iter().filter_map(|(pk, writes)| {
             writes
                 .iter()
                 .rev()
                 .find(|w| self.is_account_write_live(w))
+                .filter(|w| !w.is_deleted())
+                .map(|latest_write| (pk, latest_write))
+        })
+    }
+
+    /// Cloned snapshot of the most recent live writes per pubkey whose slot reached `status`
+    pub fn accounts_snapshot_at_commitment(
+        &self,
+        status: SlotStatus,
+    ) -> HashMap<Pubkey, AccountData> {
+        self.iter_accounts_at_commitment(status)
+            .map(|(pubkey, write)| (*pubkey, write.clone()))
+            .collect()
+    }
+
+    /// Ref to the most recent live write of the pubkey whose slot reached `status`
+    ///
+    /// With `SlotStatus::Processed` this is the same as `account()`.
+    pub fn account_at_commitment<'a>(
+        &'a self,
+        pubkey: &Pubkey,
+        status: SlotStatus,
+    ) -> anyhow::Result<&'a AccountData> {
+        self.accounts
+            .get(pubkey)
+            .ok_or_else(|| anyhow::anyhow!("account {} not found", pubkey))?
+            .iter()
+            .rev()
+            .find(|w| self.is_account_write_at_commitment(w, status))
+            .filter(|w| !w.is_deleted())
+            .ok_or_else(|| anyhow::anyhow!("account {} has no {:?} data", pubkey, status))
+    }
+
+    pub fn iter_accounts_at_commitment<'a>(
+        &'a self,
+        status: SlotStatus,
+    ) -> impl Iterator<Item = (&'a Pubkey, &'a AccountData)> {
+        self.accounts.iter().filter_map(move |(pk, writes)| {
+            writes
+                .iter()
+                .rev()
+                .find(|w| self.is_account_write_at_commitment(w, status))
+                .filter(|w| !w.is_deleted())
                 .map(|latest_write| (pk, latest_write))
         })
     }
@@ -262,6 +720,156 @@ impl ChainData {
     pub fn newest_rooted_slot(&self) -> u64 {
         self.newest_rooted_slot
     }
+
+    pub fn history_writes_count(&self) -> usize {
+        self.history_versions_stored
+    }
+
+    pub fn history_bytes(&self) -> usize {
+        self.history_bytes_stored
+    }
+
+    /// Total number of reorgs seen
+    pub fn reorg_count(&self) -> u64 {
+        self.reorg_count
+    }
+
+    /// Write the newest rooted write of every account and the newest rooted slot to `path`
+    ///
+    /// Unrooted writes are not saved, they may still be dropped by a fork.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.persisted().write(path)
+    }
+
+    /// Copy of the state that `save()` writes
+    fn persisted(&self) -> PersistedChainData {
+        let accounts =
+            self.accounts
+                .iter()
+                .filter_map(|(pubkey, writes)| {
+                    let write = writes.iter().rev().find(|w| {
+                        w.slot <= self.newest_rooted_slot && self.is_account_write_live(w)
+                    })?;
+                    if write.is_deleted() {
+                        return None;
+                    }
+                    Some(PersistedAccount {
+                        pubkey: *pubkey,
+                        slot: write.slot,
+                        write_version: write.write_version,
+                        data_slice: write.data_slice,
+                        lamports: write.account.lamports(),
+                        owner: *write.account.owner(),
+                        executable: write.account.executable(),
+                        rent_epoch: write.account.rent_epoch(),
+                        data: write.account.data().to_vec(),
+                    })
+                })
+                .collect();
+        PersistedChainData {
+            newest_rooted_slot: self.newest_rooted_slot,
+            accounts,
+        }
+    }
+
+    /// Restore the state written by `save()`
+    ///
+    /// The loaded accounts are treated as rooted at their slot. Newer writes, like the
+    /// ones from the next live snapshot, replace them as usual.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let persisted: PersistedChainData = bincode::deserialize_from(reader)?;
+
+        let mut chain = Self::new();
+        chain.apply_slot_update(SlotData {
+            slot: persisted.newest_rooted_slot,
+            parent: None,
+            status: SlotStatus::Rooted,
+            chain: 0,
+        });
+        for account in persisted.accounts {
+            chain.insert_account_write(
+                account.pubkey,
+                AccountData {
+                    slot: account.slot,
+                    write_version: account.write_version,
+                    data_slice: account.data_slice,
+                    account: AccountSharedData::create(
+                        account.lamports,
+                        account.data,
+                        account.owner,
+                        account.executable,
+                        account.rent_epoch,
+                    ),
+                },
+            );
+        }
+        Ok(chain)
+    }
+}
+
+/// Whether a write is on the rooted chain, given the slots of a `ChainData`
+fn is_rooted_write(
+    slots: &HashMap<u64, SlotData>,
+    newest_rooted_slot: u64,
+    best_chain_slot: u64,
+    write: &AccountData,
+) -> bool {
+    write.slot <= newest_rooted_slot
+        && slots
+            .get(&write.slot)
+            .map(|s| {
+                // sometimes we seem not to get notifications about slots
+                // getting rooted, hence assume non-uncle slots < newest_rooted_slot
+                // are rooted too
+                s.status == SlotStatus::Rooted || s.chain == best_chain_slot
+            })
+            // preserved account writes for deleted slots <= newest_rooted_slot
+            // are expected to be rooted
+            .unwrap_or(true)
+}
+
+/// Remove `pubkey` from the writes of `slot` in a `ChainData::writes_by_slot` index
+fn unindex_write(writes_by_slot: &mut BTreeMap<u64, HashSet<Pubkey>>, slot: u64, pubkey: &Pubkey) {
+    if let Some(pubkeys) = writes_by_slot.get_mut(&slot) {
+        pubkeys.remove(pubkey);
+        if pubkeys.is_empty() {
+            writes_by_slot.remove(&slot);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedAccount {
+    pubkey: Pubkey,
+    slot: u64,
+    write_version: u64,
+    data_slice: Option<DataSlice>,
+    lamports: u64,
+    owner: Pubkey,
+    executable: bool,
+    rent_epoch: u64,
+    data: Vec<u8>,
+}
+
+/// File format of `ChainData::save()`
+#[derive(Serialize, Deserialize)]
+struct PersistedChainData {
+    newest_rooted_slot: u64,
+    accounts: Vec<PersistedAccount>,
+}
+
+impl PersistedChainData {
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        // write to a temporary file first, so a crash never leaves a truncated file behind
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
 }
 
 pub struct ChainDataMetrics {
@@ -269,6 +877,9 @@ pub struct ChainDataMetrics {
     accounts_stored: MetricU64,
     account_versions_stored: MetricU64,
     account_bytes_stored: MetricU64,
+    reorgs: MetricU64,
+    history_versions_stored: MetricU64,
+    history_bytes_stored: MetricU64,
 }
 
 impl ChainDataMetrics {
@@ -283,6 +894,13 @@ impl ChainDataMetrics {
             ),
             account_bytes_stored: metrics
                 .register_u64("chaindata_account_bytes_stored".into(), MetricType::Gauge),
+            reorgs: metrics.register_u64("chaindata_reorgs".into(), MetricType::Counter),
+            history_versions_stored: metrics.register_u64(
+                "chaindata_history_versions_stored".into(),
+                MetricType::Gauge,
+            ),
+            history_bytes_stored: metrics
+                .register_u64("chaindata_history_bytes_stored".into(), MetricType::Gauge),
         }
     }
 
@@ -292,6 +910,10 @@ impl ChainDataMetrics {
         self.account_versions_stored
             .set(chain.account_writes_count() as u64);
         self.account_bytes_stored.set(chain.account_bytes() as u64);
+        self.reorgs.set(chain.reorg_count());
+        self.history_versions_stored
+            .set(chain.history_writes_count() as u64);
+        self.history_bytes_stored.set(chain.history_bytes() as u64);
     }
 
     pub fn spawn_report_job(
@@ -311,3 +933,114 @@ impl ChainDataMetrics {
         });
     }
 }
+
+/// Periodically saves a `ChainData` according to a `ChainDataPersistenceConfig`
+pub struct ChainDataPersistence {
+    path: String,
+    interval: Duration,
+    last_save: Instant,
+    /// The save that was started last, it runs on the blocking thread pool
+    running_save: Option<JoinHandle<()>>,
+    saves: MetricU64,
+    save_errors: MetricU64,
+}
+
+impl ChainDataPersistence {
+    pub fn new(config: &ChainDataPersistenceConfig, metrics: &Metrics) -> Self {
+        Self {
+            path: config.path.clone(),
+            interval: Duration::from_secs(config.interval_secs),
+            last_save: Instant::now(),
+            running_save: None,
+            saves: metrics.register_u64("chaindata_saves".into(), MetricType::Counter),
+            save_errors: metrics.register_u64("chaindata_save_errors".into(), MetricType::Counter),
+        }
+    }
+
+    /// Load the saved state, or start out empty if there is none
+    pub fn load(&self) -> ChainData {
+        match ChainData::load(Path::new(&self.path)) {
+            Ok(chain) => {
+                info!(
+                    "loaded {} accounts at rooted slot {} from {}",
+                    chain.accounts_count(),
+                    chain.newest_rooted_slot(),
+                    self.path
+                );
+                chain
+            }
+            Err(err) => {
+                warn!("could not load chain data from {}: {:?}", self.path, err);
+                ChainData::new()
+            }
+        }
+    }
+
+    /// Save now, after a save that is still running in the background
+    pub async fn save(&mut self, chain: &ChainData) {
+        self.save_persisted(chain.persisted()).await;
+    }
+
+    async fn save_persisted(&mut self, persisted: PersistedChainData) {
+        if let Some(running_save) = self.running_save.take() {
+            let _ = running_save.await;
+        }
+        let _ = self.spawn_save(persisted).await;
+    }
+
+    /// Start a save if the configured interval has passed since the last save and no
+    /// save is running anymore
+    ///
+    /// Only copying the state happens here, the file is written on the blocking thread
+    /// pool so that the caller can go on processing updates.
+    pub fn save_if_due(&mut self, chain: &ChainData) {
+        if self.last_save.elapsed() < self.interval {
+            return;
+        }
+        if let Some(running_save) = self.running_save.as_mut() {
+            if running_save.now_or_never().is_none() {
+                return;
+            }
+            self.running_save = None;
+        }
+        self.running_save = Some(self.spawn_save(chain.persisted()));
+    }
+
+    fn spawn_save(&mut self, persisted: PersistedChainData) -> JoinHandle<()> {
+        self.last_save = Instant::now();
+        let path = self.path.clone();
+        let mut saves = self.saves.clone();
+        let mut save_errors = self.save_errors.clone();
+        tokio::task::spawn_blocking(move || match persisted.write(Path::new(&path)) {
+            Ok(()) => saves.increment(),
+            Err(err) => {
+                error!("could not save chain data to {}: {:?}", path, err);
+                save_errors.increment();
+            }
+        })
+    }
+
+    /// Save `chain` every interval, and a last time on shutdown. The task ends after
+    /// that last save.
+    pub fn spawn_persist_job(
+        mut self,
+        chain: Arc<RwLock<ChainData>>,
+        shutdown: CancellationToken,
+    ) -> JoinHandle<()> {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.cancelled() => {
+                        // the lock is only held while copying the state
+                        let persisted = chain.read().unwrap().persisted();
+                        self.save_persisted(persisted).await;
+                        break;
+                    }
+                }
+                self.save_if_due(&chain.read().unwrap());
+            }
+        })
+    }
+}
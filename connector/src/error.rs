@@ -0,0 +1,94 @@
+use solana_sdk::pubkey::Pubkey;
+use std::time::Duration;
+use yellowstone_grpc_proto::tonic::{self, Code, Status};
+
+/// Errors returned by the sources
+///
+/// Connections are retried on most errors, the variant tells why a connection failed.
+/// `Config` and `ChannelClosed` can't be fixed by retrying and end the source.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectorError {
+    /// Connecting to or streaming from a server failed
+    #[error("transport error: {0}")]
+    Transport(String),
+    /// The server rejected the credentials
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    /// The snapshot is older than the slot it needs to cover, like the first slot that
+    /// the stream has all writes for
+    #[error("snapshot for slot {snapshot_slot} is older than the required slot {min_slot}")]
+    SnapshotTooOld { snapshot_slot: u64, min_slot: u64 },
+    /// A snapshot request failed or returned something unexpected
+    #[error("snapshot failed: {0}")]
+    Snapshot(String),
+    /// A write arrived with a lower write version than an earlier write of the same account
+    #[error("write to {pubkey} in slot {slot} has write version {write_version}, expected at least {expected}")]
+    OutOfOrderWrite {
+        pubkey: Pubkey,
+        slot: u64,
+        write_version: u64,
+        expected: u64,
+    },
+    /// A write arrived for a slot that is too far behind the rooted slot to be handled
+    #[error("received a write for slot {slot}, too far behind the rooted slot {max_rooted_slot}")]
+    LateWrite { slot: u64, max_rooted_slot: u64 },
+    /// The server didn't send anything, not even pings
+    #[error("no message received in {0:?}")]
+    IdleTimeout(Duration),
+    /// The server sent an update that can't be decoded
+    #[error("invalid update: {0}")]
+    InvalidUpdate(String),
+    /// The consumer fell behind and writes were dropped, see `QueuePolicy::FailFast`
+    #[error("account write queue overflowed, starting over with a new snapshot")]
+    QueueOverflow,
+    #[error("invalid config: {0}")]
+    Config(String),
+    /// The consumer of the updates went away
+    #[error("{0} channel closed")]
+    ChannelClosed(&'static str),
+}
+
+impl ConnectorError {
+    /// Short name of the variant, for metric names
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConnectorError::Transport(_) => "transport",
+            ConnectorError::Auth(_) => "auth",
+            ConnectorError::SnapshotTooOld { .. } => "snapshot_too_old",
+            ConnectorError::Snapshot(_) => "snapshot",
+            ConnectorError::OutOfOrderWrite { .. } => "out_of_order_write",
+            ConnectorError::LateWrite { .. } => "late_write",
+            ConnectorError::IdleTimeout(_) => "idle_timeout",
+            ConnectorError::InvalidUpdate(_) => "invalid_update",
+            ConnectorError::QueueOverflow => "queue_overflow",
+            ConnectorError::Config(_) => "config",
+            ConnectorError::ChannelClosed(_) => "channel_closed",
+        }
+    }
+
+    /// Whether reconnecting can't help
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            ConnectorError::Config(_) | ConnectorError::ChannelClosed(_)
+        )
+    }
+}
+
+impl From<Status> for ConnectorError {
+    fn from(status: Status) -> Self {
+        match status.code() {
+            Code::Unauthenticated | Code::PermissionDenied => {
+                ConnectorError::Auth(status.message().to_owned())
+            }
+            _ => ConnectorError::Transport(status.to_string()),
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for ConnectorError {
+    fn from(err: tonic::transport::Error) -> Self {
+        // the Display impl leaves out the cause
+        ConnectorError::Transport(format!("{:?}", err))
+    }
+}
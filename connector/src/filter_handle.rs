@@ -0,0 +1,152 @@
+//! Change the filters of a running source.
+//!
+//! `grpc_plugin_source::process_events` and `replay_source::process_events` return a
+//! `FilterHandle`. The grpc source sends every change to the connected geyser plugins as
+//! a new subscribe request on their existing streams and only the added accounts and
+//! programs get snapshotted. Connections that are made later subscribe with the current
+//! filters. The replay source applies changes to the writes it replays next.
+//!
+//! Only the account and program ids can change. The account filters, transaction filter
+//! and data slice stay as the source was started with.
+
+use log::*;
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+use crate::{error::ConnectorError, AccountWrite, FilterConfig};
+
+#[derive(Clone)]
+pub struct FilterHandle {
+    sender: Arc<Mutex<watch::Sender<FilterConfig>>>,
+}
+
+impl FilterHandle {
+    pub(crate) fn new(filter_config: FilterConfig) -> (Self, watch::Receiver<FilterConfig>) {
+        let (sender, receiver) = watch::channel(filter_config);
+        (
+            Self {
+                sender: Arc::new(Mutex::new(sender)),
+            },
+            receiver,
+        )
+    }
+
+    /// The filters currently in use
+    pub fn filter_config(&self) -> FilterConfig {
+        self.sender.lock().unwrap().borrow().clone()
+    }
+
+    pub fn add_account_ids(&self, account_ids: &[String]) {
+        self.update(|filter_config| add(&mut filter_config.account_ids, account_ids));
+    }
+
+    pub fn remove_account_ids(&self, account_ids: &[String]) {
+        self.update(|filter_config| {
+            filter_config
+                .account_ids
+                .retain(|id| !account_ids.contains(id))
+        });
+    }
+
+    pub fn add_program_ids(&self, program_ids: &[String]) {
+        self.update(|filter_config| add(&mut filter_config.program_ids, program_ids));
+    }
+
+    pub fn remove_program_ids(&self, program_ids: &[String]) {
+        self.update(|filter_config| {
+            filter_config
+                .program_ids
+                .retain(|id| !program_ids.contains(id))
+        });
+    }
+
+    /// Replace the account and program ids with those of `filter_config`
+    ///
+    /// Returns a `Config` error, without changing anything, if `filter_config` differs
+    /// from the filters in use in anything but the ids.
+    pub fn set_filter_config(&self, filter_config: FilterConfig) -> Result<(), ConnectorError> {
+        let sender = self.sender.lock().unwrap();
+        {
+            let current = sender.borrow();
+            if filter_config.account_filters != current.account_filters
+                || filter_config.transaction_filter != current.transaction_filter
+                || filter_config.data_slice != current.data_slice
+            {
+                return Err(ConnectorError::Config(
+                    "only the account and program ids of a running source can change".into(),
+                ));
+            }
+        }
+        // fails once the source stopped, nobody is left to apply the change
+        let _ = sender.send(filter_config);
+        Ok(())
+    }
+
+    fn update(&self, change: impl FnOnce(&mut FilterConfig)) {
+        let sender = self.sender.lock().unwrap();
+        let mut filter_config = sender.borrow().clone();
+        change(&mut filter_config);
+        // fails once the source stopped, nobody is left to apply the change
+        let _ = sender.send(filter_config);
+    }
+}
+
+fn add(ids: &mut Vec<String>, added: &[String]) {
+    for id in added {
+        if !ids.contains(id) {
+            ids.push(id.clone());
+        }
+    }
+}
+
+/// The account and program ids of a `FilterConfig`, parsed once instead of for every write
+pub(crate) struct FilterSelection {
+    filter_config: FilterConfig,
+    account_ids: HashSet<Pubkey>,
+    program_ids: HashSet<Pubkey>,
+}
+
+impl FilterSelection {
+    pub fn new(filter_config: FilterConfig) -> Self {
+        let parse = |ids: &[String]| {
+            ids.iter()
+                .filter_map(|id| match Pubkey::from_str(id) {
+                    Ok(pubkey) => Some(pubkey),
+                    Err(_) => {
+                        warn!("ignoring invalid pubkey {} in the filters", id);
+                        None
+                    }
+                })
+                .collect()
+        };
+        Self {
+            account_ids: parse(&filter_config.account_ids),
+            program_ids: parse(&filter_config.program_ids),
+            filter_config,
+        }
+    }
+
+    pub fn filter_config(&self) -> &FilterConfig {
+        &self.filter_config
+    }
+
+    /// Whether the ids select the account, without any ids every account is selected
+    pub fn selects(&self, pubkey: &Pubkey, owner: &Pubkey) -> bool {
+        (self.filter_config.account_ids.is_empty() && self.filter_config.program_ids.is_empty())
+            || self.account_ids.contains(pubkey)
+            || self.program_ids.contains(owner)
+    }
+
+    /// Whether the ids select the account and it passes the account filters
+    pub fn selects_write(&self, write: &AccountWrite) -> bool {
+        self.selects(&write.pubkey, &write.owner)
+            && self
+                .filter_config
+                .matches_account_filters(&write.data, write.data_slice)
+    }
+}
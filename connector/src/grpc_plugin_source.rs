@@ -1,119 +1,458 @@
-use futures::stream::once;
+use futures::stream::FuturesUnordered;
 use jsonrpc_core::futures::StreamExt;
 
 use solana_account_decoder::UiAccount;
 use solana_client::rpc_response::OptionalContext;
-use solana_sdk::{account::Account, pubkey::Pubkey};
+use solana_sdk::{
+    account::Account, hash::Hash, instruction::CompiledInstruction, pubkey::Pubkey,
+    signature::Signature,
+};
 
-use futures::{future, future::FutureExt};
+use futures::{
+    channel::mpsc,
+    future::{self, FusedFuture, Future, FutureExt},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    sync::watch,
+};
 use yellowstone_grpc_proto::tonic::{
-    metadata::MetadataValue,
-    transport::{Certificate, Channel, ClientTlsConfig, Identity},
+    codec::CompressionEncoding,
+    metadata::{Ascii, MetadataValue},
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Uri},
     Request,
 };
 
 use log::*;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::{collections::HashMap, env, str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::Read,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
 use yellowstone_grpc_proto::prelude::{
-    geyser_client::GeyserClient, subscribe_update, SubscribeRequest,
-    SubscribeRequestFilterAccounts, SubscribeRequestFilterSlots, SubscribeUpdate,
-    SubscribeUpdateSlotStatus,
+    geyser_client::GeyserClient, subscribe_request_filter_accounts_filter,
+    subscribe_request_filter_accounts_filter_memcmp, subscribe_update::UpdateOneof,
+    SubscribeRequest, SubscribeRequestFilterAccounts, SubscribeRequestFilterAccountsFilter,
+    SubscribeRequestFilterAccountsFilterMemcmp, SubscribeRequestFilterBlocksMeta,
+    SubscribeRequestFilterSlots, SubscribeRequestFilterTransactions, SubscribeUpdate,
+    SubscribeUpdateBlockMeta, SubscribeUpdateSlotStatus, SubscribeUpdateTransaction,
 };
 
-use crate::snapshot::{get_snapshot_gma, get_snapshot_gpa};
-use crate::FilterConfig;
+use crate::error::ConnectorError;
+use crate::filter_handle::{FilterHandle, FilterSelection};
+use crate::queue::{AccountWriteQueue, ResnapshotSignal};
+use crate::rpc_connection::RpcConnection;
+use crate::secrets::{config_file, config_value, redact_url};
+use crate::snapshot::{
+    decode_snapshot_accounts, filter_gma_accounts, get_snapshot, get_snapshot_gma,
+    get_snapshot_gpa, gma_data_slice, program_pubkeys, KnownAccounts,
+};
+use crate::source_consistency::ConsistencyChecker;
+use crate::source_latency::DeliveryTracker;
 use crate::{
     chain_data::SlotStatus,
-    metrics::{MetricType, Metrics},
-    AccountWrite, GrpcSourceConfig, SlotUpdate, SnapshotSourceConfig, SourceConfig, TlsConfig,
+    metrics::{MetricType, MetricU64, Metrics},
+    shutdown::CancellationToken,
+    AccountDataCompression, AccountWrite, BlockMetaUpdate, DataSlice, GrpcCompression,
+    GrpcSourceConfig, SlotUpdate, SnapshotSourceConfig, SourceConfig, TlsConfig, TransactionUpdate,
 };
+use crate::{AccountFilter, FilterConfig};
 
 struct SnapshotData {
     slot: u64,
-    accounts: Vec<(String, Option<UiAccount>)>,
+    accounts: Vec<(Pubkey, Option<Account>)>,
+    /// Programs that the snapshot lists all accounts of, known accounts of them that are
+    /// missing were closed
+    complete_program_ids: HashSet<Pubkey>,
+    /// The range of the account data that the snapshot holds
+    data_slice: Option<DataSlice>,
 }
 enum Message {
-    GrpcUpdate(SubscribeUpdate),
+    GrpcUpdate {
+        /// Index of the grpc source the update came from
+        source: usize,
+        received_at: Instant,
+        update: SubscribeUpdate,
+    },
     Snapshot(SnapshotData),
+    /// A grpc source stopped on an error that reconnecting can't fix
+    SourceStopped {
+        source: usize,
+        err: ConnectorError,
+    },
 }
 
-async fn feed_data_geyser(
-    grpc_config: &GrpcSourceConfig,
-    tls_config: Option<ClientTlsConfig>,
-    snapshot_config: &SnapshotSourceConfig,
-    filter_config: &FilterConfig,
-    sender: async_channel::Sender<Message>,
-) -> anyhow::Result<()> {
-    let connection_string = match &grpc_config.connection_string.chars().next().unwrap() {
-        '$' => env::var(&grpc_config.connection_string[1..])
-            .expect("reading connection string from env"),
-        _ => grpc_config.connection_string.clone(),
-    };
-    let rpc_http_url = match &snapshot_config.rpc_http_url.chars().next().unwrap() {
-        '$' => env::var(&snapshot_config.rpc_http_url[1..])
-            .expect("reading connection string from env"),
-        _ => snapshot_config.rpc_http_url.clone(),
-    };
-    info!("connecting {}", connection_string);
-    let endpoint = Channel::from_shared(connection_string)?;
-    let channel = if let Some(tls) = tls_config {
-        endpoint.tls_config(tls)?
-    } else {
-        endpoint
-    }
-    .connect()
-    .await?;
-    let token: Option<MetadataValue<_>> = match &grpc_config.token {
-        Some(token) => match token.chars().next().unwrap() {
-            '$' => Some(
-                env::var(&token[1..])
-                    .expect("reading token from env")
-                    .parse()?,
-            ),
-            _ => Some(token.clone().parse()?),
-        },
-        None => None,
-    };
-    let mut client = GeyserClient::with_interceptor(channel, move |mut req: Request<()>| {
-        if let Some(token) = &token {
-            req.metadata_mut().insert("x-token", token.clone());
-        }
-        Ok(req)
-    });
+fn make_accounts_filters(
+    account_filters: &[AccountFilter],
+) -> Vec<SubscribeRequestFilterAccountsFilter> {
+    use subscribe_request_filter_accounts_filter::Filter;
+    use subscribe_request_filter_accounts_filter_memcmp::Data;
+    account_filters
+        .iter()
+        .map(|f| SubscribeRequestFilterAccountsFilter {
+            filter: Some(match f {
+                AccountFilter::Memcmp { offset, bytes } => {
+                    Filter::Memcmp(SubscribeRequestFilterAccountsFilterMemcmp {
+                        offset: *offset,
+                        data: Some(Data::Base58(bytes.clone())),
+                    })
+                }
+                AccountFilter::DataSize(size) => Filter::Datasize(*size),
+            }),
+        })
+        .collect()
+}
 
-    // If account_ids are provided, snapshot will be gMA. If only program_ids, then only the first id will be snapshot
-    // TODO: handle this better
-    if filter_config.program_ids.len() > 1 {
-        warn!("only one program id is supported for gPA snapshots")
+fn make_transaction_update(update: SubscribeUpdateTransaction) -> Option<TransactionUpdate> {
+    let info = update.transaction?;
+    if info.signature.len() != 64 {
+        return None;
     }
+    let message = info.transaction?.message?;
+    let meta = info.meta?;
+
+    let account_keys = message
+        .account_keys
+        .into_iter()
+        .chain(meta.loaded_writable_addresses.into_iter())
+        .chain(meta.loaded_readonly_addresses.into_iter())
+        .map(Pubkey::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let instructions = message
+        .instructions
+        .into_iter()
+        .map(|ix| CompiledInstruction {
+            program_id_index: ix.program_id_index as u8,
+            accounts: ix.accounts,
+            data: ix.data,
+        })
+        .collect();
+
+    Some(TransactionUpdate {
+        slot: update.slot,
+        signature: Signature::new(&info.signature),
+        index: info.index,
+        is_vote: info.is_vote,
+        is_failed: meta.err.is_some(),
+        account_keys,
+        instructions,
+        log_messages: meta.log_messages,
+    })
+}
+
+fn make_block_meta_update(
+    update: SubscribeUpdateBlockMeta,
+    parent: Option<u64>,
+) -> Option<BlockMetaUpdate> {
+    Some(BlockMetaUpdate {
+        slot: update.slot,
+        blockhash: Hash::from_str(&update.blockhash).ok()?,
+        block_time: update.block_time.map(|t| t.timestamp),
+        block_height: update.block_height.map(|h| h.block_height),
+        parent,
+    })
+}
+
+fn make_subscribe_request(
+    filter_config: &FilterConfig,
+    subscribe_block_meta: bool,
+) -> SubscribeRequest {
     let mut accounts = HashMap::new();
     accounts.insert(
         "client".to_owned(),
         SubscribeRequestFilterAccounts {
             account: filter_config.account_ids.clone(),
             owner: filter_config.program_ids.clone(),
-            filters: vec![],
+            filters: make_accounts_filters(&filter_config.account_filters),
         },
     );
     let mut slots = HashMap::new();
     slots.insert("client".to_owned(), SubscribeRequestFilterSlots {});
     let blocks = HashMap::new();
-    let blocks_meta = HashMap::new();
-    let transactions = HashMap::new();
+    let mut blocks_meta = HashMap::new();
+    if subscribe_block_meta {
+        blocks_meta.insert("client".to_owned(), SubscribeRequestFilterBlocksMeta {});
+    }
+    let mut transactions = HashMap::new();
+    if let Some(transaction_filter) = &filter_config.transaction_filter {
+        transactions.insert(
+            "client".to_owned(),
+            SubscribeRequestFilterTransactions {
+                vote: transaction_filter.vote,
+                failed: transaction_filter.failed,
+                signature: None,
+                account_include: transaction_filter.account_include.clone(),
+                account_exclude: transaction_filter.account_exclude.clone(),
+            },
+        );
+    }
 
-    let request = SubscribeRequest {
+    SubscribeRequest {
         accounts,
         blocks,
         blocks_meta,
         slots,
         transactions,
+    }
+}
+
+/// TCP connection that counts the bytes read from it, to measure the bandwidth used
+/// by a geyser connection after transport compression
+struct CountingTcpStream {
+    inner: TcpStream,
+    bytes_received: MetricU64,
+}
+
+impl AsyncRead for CountingTcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let received = buf.filled().len() - filled_before;
+        self.bytes_received.add(received as u64);
+        result
+    }
+}
+
+impl AsyncWrite for CountingTcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn connect_counting(
+    uri: Uri,
+    bytes_received: MetricU64,
+) -> std::io::Result<CountingTcpStream> {
+    let host = uri.host().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("no host in {}", uri),
+        )
+    })?;
+    let default_port = if uri.scheme_str() == Some("https") {
+        443
+    } else {
+        80
+    };
+    let inner = TcpStream::connect((host, uri.port_u16().unwrap_or(default_port))).await?;
+    inner.set_nodelay(true)?;
+    Ok(CountingTcpStream {
+        inner,
+        bytes_received,
+    })
+}
+
+fn decompress_account_data(
+    compression: AccountDataCompression,
+    data: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    match compression {
+        AccountDataCompression::Zstd => {
+            zstd::stream::read::Decoder::new(data)?.read_to_end(&mut decompressed)?
+        }
+        AccountDataCompression::Lz4 => lz4::Decoder::new(data)?.read_to_end(&mut decompressed)?,
+    };
+    Ok(decompressed)
+}
+
+/// Accounts and programs that were added to the filters of a connection and still
+/// need a snapshot
+#[derive(Default)]
+struct AddedFilters {
+    account_ids: HashSet<String>,
+    program_ids: HashSet<String>,
+    /// First slot that all writes of the added accounts are received for, known once
+    /// a slot is rooted after subscribing to them
+    first_full_slot: Option<u64>,
+}
+
+impl AddedFilters {
+    fn is_empty(&self) -> bool {
+        self.account_ids.is_empty() && self.program_ids.is_empty()
+    }
+
+    /// Track the change from `old` to `new`, all additions wait for a new first full slot
+    fn update(&mut self, old: &FilterConfig, new: &FilterConfig) {
+        let added = |old_ids: &[String], new_ids: &[String]| -> Vec<String> {
+            new_ids
+                .iter()
+                .filter(|id| !old_ids.contains(id))
+                .cloned()
+                .collect()
+        };
+        self.account_ids
+            .extend(added(&old.account_ids, &new.account_ids));
+        self.account_ids.retain(|id| new.account_ids.contains(id));
+        self.program_ids
+            .extend(added(&old.program_ids, &new.program_ids));
+        self.program_ids.retain(|id| new.program_ids.contains(id));
+        self.first_full_slot = None;
+    }
+
+    /// Whether earlier writes to the account in this slot may not have been received
+    fn may_miss_writes(&self, slot: u64, pubkey: &Pubkey, owner: &Pubkey) -> bool {
+        if self.is_empty() || self.first_full_slot.map_or(false, |s| slot >= s) {
+            return false;
+        }
+        self.account_ids.contains(&pubkey.to_string())
+            || self.program_ids.contains(&owner.to_string())
+    }
+
+    /// Filter config that selects only the added accounts and programs
+    fn filter_config(&self, current: &FilterConfig) -> FilterConfig {
+        FilterConfig {
+            account_ids: self.account_ids.iter().cloned().collect(),
+            program_ids: self.program_ids.iter().cloned().collect(),
+            account_filters: current.account_filters.clone(),
+            transaction_filter: None,
+            data_slice: current.data_slice,
+        }
+    }
+}
+
+/// Snapshot of accounts and programs that were added to a running connection, at the
+/// slot of the older of its gMA and gPA parts
+async fn get_snapshot_added(
+    rpc_http: RpcConnection,
+    filter_config: FilterConfig,
+) -> Result<SnapshotData, ConnectorError> {
+    let snapshot_error = |err: anyhow::Error| ConnectorError::Snapshot(format!("{:#}", err));
+    let mut snapshot = SnapshotData {
+        slot: u64::MAX,
+        accounts: vec![],
+        complete_program_ids: program_pubkeys(&filter_config.program_ids),
+        data_slice: filter_config.data_slice,
     };
+    if !filter_config.account_ids.is_empty() {
+        let gma_filter = FilterConfig {
+            program_ids: vec![],
+            ..filter_config.clone()
+        };
+        let (slot, accounts) = get_snapshot(rpc_http.clone(), &gma_filter)
+            .await
+            .map_err(snapshot_error)?;
+        snapshot.slot = snapshot.slot.min(slot);
+        snapshot
+            .accounts
+            .extend(decode_snapshot_accounts(accounts)?);
+    }
+    if !filter_config.program_ids.is_empty() {
+        let gpa_filter = FilterConfig {
+            account_ids: vec![],
+            ..filter_config
+        };
+        let (slot, accounts) = get_snapshot(rpc_http, &gpa_filter)
+            .await
+            .map_err(snapshot_error)?;
+        snapshot.slot = snapshot.slot.min(slot);
+        snapshot
+            .accounts
+            .extend(decode_snapshot_accounts(accounts)?);
+    }
+    Ok(snapshot)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn feed_data_geyser(
+    source_index: usize,
+    grpc_config: &GrpcSourceConfig,
+    connection: &GrpcConnection,
+    snapshot_config: &SnapshotSourceConfig,
+    rpc_http: &RpcConnection,
+    mut filter_updates: watch::Receiver<FilterConfig>,
+    subscribe_block_meta: bool,
+    sender: async_channel::Sender<Message>,
+    resnapshot: ResnapshotSignal,
+    metrics_sender: &Metrics,
+) -> Result<(), ConnectorError> {
+    let resnapshot_generation = resnapshot.generation();
+
+    let bytes_received = metrics_sender.register_u64(
+        format!("grpc_source_{}_bytes_received", grpc_config.name),
+        MetricType::Counter,
+    );
+    let mut metric_account_data_bytes_received = metrics_sender.register_u64(
+        format!(
+            "grpc_source_{}_account_data_bytes_received",
+            grpc_config.name
+        ),
+        MetricType::Counter,
+    );
+    let mut metric_account_data_bytes = metrics_sender.register_u64(
+        format!("grpc_source_{}_account_data_bytes", grpc_config.name),
+        MetricType::Counter,
+    );
+    let mut metric_slot_gaps = metrics_sender.register_u64(
+        format!("grpc_source_{}_slot_gaps", grpc_config.name),
+        MetricType::Counter,
+    );
+    // too old snapshots are retried on the same connection, count them with the other errors
+    let mut metric_snapshot_too_old = metrics_sender.register_u64(
+        format!("grpc_source_{}_errors_snapshot_too_old", grpc_config.name),
+        MetricType::Counter,
+    );
+    // invalid account updates are skipped and corrected by a new snapshot, same for them
+    let mut metric_invalid_update = metrics_sender.register_u64(
+        format!("grpc_source_{}_errors_invalid_update", grpc_config.name),
+        MetricType::Counter,
+    );
+
+    info!("connecting {}", redact_url(&connection.connection_string));
+    let channel = connection
+        .endpoint
+        .clone()
+        .connect_with_connector(tower::service_fn(move |uri: Uri| {
+            connect_counting(uri, bytes_received.clone())
+        }))
+        .await?;
+    let token = connection.token.clone();
+    let mut client = GeyserClient::with_interceptor(channel, move |mut req: Request<()>| {
+        if let Some(token) = &token {
+            req.metadata_mut().insert("x-token", token.clone());
+        }
+        Ok(req)
+    });
+    if let Some(compression) = grpc_config.compression {
+        client = client.accept_compressed(match compression {
+            GrpcCompression::Gzip => CompressionEncoding::Gzip,
+        });
+    }
+
+    // If account_ids are provided, snapshot will be gMA. If only program_ids, then every
+    // program id is snapshot with gPA and the results are merged.
+    let filter_config = filter_updates.borrow().clone();
+    let request = make_subscribe_request(&filter_config, subscribe_block_meta);
     info!("Going to send request: {:?}", request);
 
-    let response = client.subscribe(once(async move { request })).await?;
+    // The stream stays open to send new requests when the filters change
+    let (request_sender, request_receiver) = mpsc::unbounded();
+    request_sender
+        .unbounded_send(request)
+        .expect("receiver is alive");
+    let response = client.subscribe(request_receiver).await?;
     let mut update_stream = response.into_inner();
 
     // We can't get a snapshot immediately since the finalized snapshot would be for a
@@ -131,6 +470,22 @@ async fn feed_data_geyser(
     // If a snapshot should be performed when ready.
     let mut snapshot_needed = true;
 
+    // The slot that the snapshot must at least be for: the first full slot, or a slot
+    // with missed writes
+    let mut snapshot_min_slot: u64 = u64::MAX;
+
+    // When the last complete snapshot was sent, None while one is pending
+    let mut last_snapshot_at: Option<Instant> = None;
+    let resnapshot_interval = snapshot_config
+        .resnapshot_interval_secs
+        .map(Duration::from_secs);
+
+    // Processed slots seen on this connection, to notice a parent that was never reported
+    let mut processed_slots = BTreeSet::<u64>::new();
+
+    // Writes were missed, because of a slot gap or skipped invalid updates: take a new snapshot
+    let mut writes_missed = false;
+
     // The highest "rooted" slot that has been seen.
     let mut max_rooted_slot = 0;
 
@@ -148,7 +503,27 @@ async fn feed_data_geyser(
     let mut rooted_to_finalized_slots = 30;
 
     let mut snapshot_gma = future::Fuse::terminated();
-    let mut snapshot_gpa = future::Fuse::terminated();
+    let mut snapshot_gpa = FuturesUnordered::new();
+
+    // Filters that the pending snapshot is for
+    let mut snapshot_filter = filter_config.clone();
+
+    // Program ids that the pending snapshot is for, without duplicates
+    let mut gpa_snapshot_programs: HashSet<String> =
+        snapshot_filter.program_ids.iter().cloned().collect();
+
+    // Program ids that still need a gPA snapshot to be requested. Programs are retried
+    // individually if their snapshot turns out to be too old.
+    let mut gpa_snapshot_pending = gpa_snapshot_programs.clone();
+
+    // gPA snapshots that were recent enough, waiting for the other programs to arrive
+    let mut gpa_snapshots = HashMap::<String, SnapshotData>::new();
+
+    // Filters in use on the stream, and what was added since the connection started
+    let mut current_filter = filter_config.clone();
+    let mut selection = FilterSelection::new(filter_config.clone());
+    let mut added_filters = AddedFilters::default();
+    let mut added_snapshot = future::Fuse::terminated();
 
     // The plugin sends a ping every 5s or so
     let fatal_idle_timeout = Duration::from_secs(60);
@@ -176,29 +551,79 @@ async fn feed_data_geyser(
     loop {
         tokio::select! {
             update = update_stream.next() => {
-                use subscribe_update::UpdateOneof;
-                let mut update = update.ok_or(anyhow::anyhow!("geyser plugin has closed the stream"))??;
-                match update.update_oneof.as_mut().expect("invalid grpc") {
+                let mut update = update.ok_or_else(|| ConnectorError::Transport("geyser plugin has closed the stream".into()))??;
+                let received_at = Instant::now();
+                if resnapshot.generation() != resnapshot_generation {
+                    return Err(ConnectorError::QueueOverflow);
+                }
+                let update_oneof = update.update_oneof.as_mut().ok_or_else(|| ConnectorError::InvalidUpdate("update without content".into()))?;
+                match update_oneof {
                     UpdateOneof::Slot(slot_update) => {
                         let status = slot_update.status;
+                        if status == SubscribeUpdateSlotStatus::Processed as i32 {
+                            // every processed slot's parent must have been processed before
+                            if let (Some(parent), Some(&oldest_slot)) = (slot_update.parent, processed_slots.iter().next()) {
+                                if parent > oldest_slot && !processed_slots.contains(&parent) {
+                                    warn!("grpc source {} never reported slot {}, the parent of slot {}, taking a new snapshot", grpc_config.name, parent, slot_update.slot);
+                                    metric_slot_gaps.increment();
+                                    writes_missed = true;
+                                    snapshot_min_slot = snapshot_min_slot.max(parent);
+                                }
+                            }
+                            processed_slots.insert(slot_update.slot);
+                        }
                         if status == SubscribeUpdateSlotStatus::Finalized as i32 {
                             if first_full_slot == u64::MAX {
                                 // TODO: is this equivalent to before? what was highesy_write_slot?
                                 first_full_slot = slot_update.slot + 1;
+                                snapshot_min_slot = first_full_slot;
                             }
                             if slot_update.slot > max_rooted_slot {
                                 max_rooted_slot = slot_update.slot;
 
                                 // drop data for slots that are well beyond rooted
-                                slot_pubkey_writes.retain(|&k, _| k >= max_rooted_slot - max_out_of_order_slots);
+                                slot_pubkey_writes.retain(|&k, _| k >= max_rooted_slot.saturating_sub(max_out_of_order_slots));
+                                processed_slots = processed_slots.split_off(&max_rooted_slot.saturating_sub(max_out_of_order_slots));
+                            }
+
+                            let resnapshot_due = match (resnapshot_interval, last_snapshot_at) {
+                                (Some(interval), Some(at)) => at.elapsed() >= interval,
+                                _ => false,
+                            };
+                            if resnapshot_due || writes_missed {
+                                // start over, also if a snapshot is pending: it may be too old now
+                                writes_missed = false;
+                                last_snapshot_at = None;
+                                snapshot_needed = true;
+                                snapshot_filter = current_filter.clone();
+                                gpa_snapshot_programs = snapshot_filter.program_ids.iter().cloned().collect();
+                                gpa_snapshot_pending = gpa_snapshot_programs.clone();
+                                gpa_snapshots.clear();
+                                snapshot_gma = future::Fuse::terminated();
+                                snapshot_gpa = FuturesUnordered::new();
                             }
 
-                            if snapshot_needed && max_rooted_slot - rooted_to_finalized_slots > first_full_slot {
+                            if snapshot_needed && max_rooted_slot.saturating_sub(rooted_to_finalized_slots) > snapshot_min_slot {
                                 snapshot_needed = false;
-                                if !filter_config.account_ids.is_empty() {
-                                    snapshot_gma = tokio::spawn(get_snapshot_gma(rpc_http_url.clone(), filter_config.account_ids.clone())).fuse();
-                                } else if !filter_config.program_ids.is_empty() {
-                                    snapshot_gpa = tokio::spawn(get_snapshot_gpa(rpc_http_url.clone(), filter_config.program_ids[0].clone())).fuse();
+                                if !snapshot_filter.account_ids.is_empty() {
+                                    snapshot_gma = tokio::spawn(get_snapshot_gma(rpc_http.clone(), snapshot_filter.account_ids.clone(), gma_data_slice(&snapshot_filter))).fuse();
+                                } else {
+                                    for program_id in gpa_snapshot_pending.drain() {
+                                        let snapshot = get_snapshot_gpa(rpc_http.clone(), program_id.clone(), snapshot_filter.account_filters.clone(), snapshot_filter.data_slice);
+                                        snapshot_gpa.push(tokio::spawn(snapshot.map(|r| (program_id, r))));
+                                    }
+                                }
+                            }
+
+                            if !added_filters.is_empty() {
+                                match added_filters.first_full_slot {
+                                    None => added_filters.first_full_slot = Some(slot_update.slot + 1),
+                                    Some(added_first_full_slot) => {
+                                        if added_snapshot.is_terminated() && max_rooted_slot.saturating_sub(rooted_to_finalized_slots) > added_first_full_slot {
+                                            let snapshot = get_snapshot_added(rpc_http.clone(), added_filters.filter_config(&current_filter));
+                                            added_snapshot = tokio::spawn(snapshot).fuse();
+                                        }
+                                    }
                                 }
                             }
                         }
@@ -212,21 +637,50 @@ async fn feed_data_geyser(
 
                         if info.slot > newest_write_slot {
                             newest_write_slot = info.slot;
-                        } else if max_rooted_slot > 0 && info.slot < max_rooted_slot - max_out_of_order_slots {
-                            anyhow::bail!("received write {} slots back from max rooted slot {}", max_rooted_slot - info.slot, max_rooted_slot);
+                        } else if max_rooted_slot > 0 && info.slot < max_rooted_slot.saturating_sub(max_out_of_order_slots) {
+                            return Err(ConnectorError::LateWrite { slot: info.slot, max_rooted_slot });
                         }
 
                         let pubkey_writes = slot_pubkey_writes.entry(info.slot).or_default();
-                        let mut write = match info.account.clone() {
+                        let write = match info.account.as_mut() {
                             Some(x) => x,
                             None => {
-                                // TODO: handle error
+                                warn!("grpc source {} sent an account update without account in slot {}, taking a new snapshot", grpc_config.name, info.slot);
+                                metric_invalid_update.increment();
+                                writes_missed = true;
+                                snapshot_min_slot = snapshot_min_slot.max(info.slot);
                                 continue;
                             },
                         };
 
-                        let pubkey_bytes = Pubkey::try_from(write.pubkey).unwrap().to_bytes();
-                        let write_version_mapping = pubkey_writes.entry(pubkey_bytes).or_insert(WriteVersion {
+                        metric_account_data_bytes_received.add(write.data.len() as u64);
+                        if let Some(compression) = grpc_config.account_data_compression {
+                            write.data = match decompress_account_data(compression, &write.data) {
+                                Ok(data) => data,
+                                Err(err) => {
+                                    warn!("grpc source {} sent undecodable account data in slot {}, taking a new snapshot: {}", grpc_config.name, info.slot, err);
+                                    metric_invalid_update.increment();
+                                    writes_missed = true;
+                                    snapshot_min_slot = snapshot_min_slot.max(info.slot);
+                                    continue;
+                                },
+                            };
+                        }
+                        metric_account_data_bytes.add(write.data.len() as u64);
+
+                        let pubkey = Pubkey::try_from(write.pubkey.as_slice())
+                            .map_err(|_| ConnectorError::InvalidUpdate(format!("invalid account pubkey in slot {}", info.slot)))?;
+                        let owner = Pubkey::try_from(write.owner.as_slice())
+                            .map_err(|_| ConnectorError::InvalidUpdate(format!("invalid owner of {} in slot {}", pubkey, info.slot)))?;
+                        if !selection.selects(&pubkey, &owner) {
+                            // removed from the filters, the plugin may not have applied the new request yet
+                            continue;
+                        }
+                        if added_filters.may_miss_writes(info.slot, &pubkey, &owner) {
+                            // Same as before first_full_slot, for the accounts that were just added
+                            continue;
+                        }
+                        let write_version_mapping = pubkey_writes.entry(pubkey.to_bytes()).or_insert(WriteVersion {
                             global: write.write_version,
                             slot: 1, // write version 0 is reserved for snapshots
                         });
@@ -235,7 +689,12 @@ async fn feed_data_geyser(
                         // If this is not the case, logic here does not work correctly because
                         // a later write could arrive first.
                         if write.write_version < write_version_mapping.global {
-                            anyhow::bail!("unexpected write version: got {}, expected >= {}", write.write_version, write_version_mapping.global);
+                            return Err(ConnectorError::OutOfOrderWrite {
+                                pubkey,
+                                slot: info.slot,
+                                write_version: write.write_version,
+                                expected: write_version_mapping.global,
+                            });
                         }
 
                         // Rewrite the update to use the local write version and bump it
@@ -247,123 +706,283 @@ async fn feed_data_geyser(
                     UpdateOneof::BlockMeta(_) => {},
                     UpdateOneof::Ping(_) => {},
                 }
-                sender.send(Message::GrpcUpdate(update)).await.expect("send success");
+                sender.send(Message::GrpcUpdate { source: source_index, received_at, update }).await
+                    .map_err(|_| ConnectorError::ChannelClosed("grpc update"))?;
             },
             snapshot = &mut snapshot_gma => {
-                let snapshot = snapshot??;
-                info!("snapshot is for slot {}, first full slot was {}", snapshot.context.slot, first_full_slot);
-                if snapshot.context.slot >= first_full_slot {
-                    let accounts: Vec<(String, Option<UiAccount>)> = filter_config.account_ids.iter().zip(snapshot.value).map(|x| (x.0.clone(), x.1)).collect();
+                let snapshot = snapshot
+                    .map_err(|err| ConnectorError::Snapshot(err.to_string()))?
+                    .map_err(|err| ConnectorError::Snapshot(format!("{:#}", err)))?;
+                info!("snapshot is for slot {}, expected {} minimum", snapshot.context.slot, snapshot_min_slot);
+                if snapshot.context.slot >= snapshot_min_slot {
+                    let accounts: Vec<(String, Option<UiAccount>)> = snapshot_filter.account_ids.iter().zip(snapshot.value).map(|x| (x.0.clone(), x.1)).collect();
+                    let accounts = decode_snapshot_accounts(filter_gma_accounts(&snapshot_filter, accounts))?;
                     sender
                     .send(Message::Snapshot(SnapshotData {
                         accounts,
                         slot: snapshot.context.slot,
+                        complete_program_ids: HashSet::new(),
+                        data_slice: snapshot_filter.data_slice,
                     }))
                     .await
-                    .expect("send success");
+                    .map_err(|_| ConnectorError::ChannelClosed("grpc update"))?;
+                    last_snapshot_at = Some(Instant::now());
                 } else {
-                    info!(
-                        "snapshot is too old: has slot {}, expected {} minimum",
-                        snapshot.context.slot,
-                        first_full_slot
-                    );
+                    let err = ConnectorError::SnapshotTooOld {
+                        snapshot_slot: snapshot.context.slot,
+                        min_slot: snapshot_min_slot,
+                    };
+                    info!("{}", err);
+                    metric_snapshot_too_old.increment();
                     // try again in another 10 slots
                     snapshot_needed = true;
                     rooted_to_finalized_slots += 10;
                 }
             },
-            snapshot = &mut snapshot_gpa => {
-                let snapshot = snapshot??;
+            Some(snapshot) = snapshot_gpa.next() => {
+                let (program_id, snapshot) = snapshot.map_err(|err| ConnectorError::Snapshot(err.to_string()))?;
+                let snapshot = snapshot.map_err(|err| ConnectorError::Snapshot(format!("{:#}", err)))?;
                 if let OptionalContext::Context(snapshot_data) = snapshot {
-                    info!("snapshot is for slot {}, first full slot was {}", snapshot_data.context.slot, first_full_slot);
-                    if snapshot_data.context.slot >= first_full_slot {
-                        let accounts: Vec<(String, Option<UiAccount>)> = snapshot_data.value.iter().map(|x| {
-                            let deref = x.clone();
-                            (deref.pubkey, Some(deref.account))
-                        }).collect();
-                        sender
-                        .send(Message::Snapshot(SnapshotData {
+                    info!("snapshot for program {} is for slot {}, expected {} minimum", program_id, snapshot_data.context.slot, snapshot_min_slot);
+                    if snapshot_data.context.slot >= snapshot_min_slot {
+                        let accounts = decode_snapshot_accounts(snapshot_data.value.into_iter().map(|x| (x.pubkey, Some(x.account))).collect())?;
+                        gpa_snapshots.insert(program_id, SnapshotData {
                             accounts,
                             slot: snapshot_data.context.slot,
-                        }))
-                        .await
-                        .expect("send success");
+                            complete_program_ids: HashSet::new(),
+                            data_slice: snapshot_filter.data_slice,
+                        });
+
+                        // once every program has a recent enough snapshot, merge them into one
+                        // at the slot of the oldest of them
+                        if gpa_snapshots.len() == gpa_snapshot_programs.len() {
+                            let slot = gpa_snapshots.values().map(|s| s.slot).min().expect("not empty");
+                            let accounts = gpa_snapshots.drain().flat_map(|(_, s)| s.accounts).collect();
+                            sender
+                            .send(Message::Snapshot(SnapshotData {
+                                accounts,
+                                slot,
+                                complete_program_ids: program_pubkeys(&snapshot_filter.program_ids),
+                                data_slice: snapshot_filter.data_slice,
+                            }))
+                            .await
+                            .map_err(|_| ConnectorError::ChannelClosed("grpc update"))?;
+                            last_snapshot_at = Some(Instant::now());
+                        }
                     } else {
-                        info!(
-                            "snapshot is too old: has slot {}, expected {} minimum",
-                            snapshot_data.context.slot,
-                            first_full_slot
-                        );
-                        // try again in another 10 slots
-                        snapshot_needed = true;
-                        rooted_to_finalized_slots += 10;
+                        let err = ConnectorError::SnapshotTooOld {
+                            snapshot_slot: snapshot_data.context.slot,
+                            min_slot: snapshot_min_slot,
+                        };
+                        info!("snapshot for program {}: {}", program_id, err);
+                        metric_snapshot_too_old.increment();
+                        // try again in another 10 slots, only bump once if several programs are too old
+                        if !snapshot_needed {
+                            snapshot_needed = true;
+                            rooted_to_finalized_slots += 10;
+                        }
+                        gpa_snapshot_pending.insert(program_id);
                     }
                 } else {
-                    anyhow::bail!("bad snapshot format");
+                    return Err(ConnectorError::Snapshot("bad snapshot format".into()));
+                }
+            },
+            snapshot = &mut added_snapshot => {
+                let snapshot = snapshot.map_err(|err| ConnectorError::Snapshot(err.to_string()))??;
+                // the snapshot is only requested once the first full slot is known
+                let added_first_full_slot = added_filters.first_full_slot.unwrap_or(u64::MAX);
+                info!("snapshot of added filters is for slot {}, first full slot was {}", snapshot.slot, added_first_full_slot);
+                if snapshot.slot >= added_first_full_slot {
+                    added_filters = AddedFilters::default();
+                    sender
+                    .send(Message::Snapshot(snapshot))
+                    .await
+                    .map_err(|_| ConnectorError::ChannelClosed("grpc update"))?;
+                } else {
+                    let err = ConnectorError::SnapshotTooOld {
+                        snapshot_slot: snapshot.slot,
+                        min_slot: added_first_full_slot,
+                    };
+                    info!("snapshot of added filters: {}", err);
+                    metric_snapshot_too_old.increment();
+                    // try again in another 10 slots
+                    rooted_to_finalized_slots += 10;
+                }
+            },
+            Ok(()) = filter_updates.changed() => {
+                let new_filter = filter_updates.borrow().clone();
+                // the handle only lets the ids change, see FilterHandle::set_filter_config
+                if new_filter.account_ids == current_filter.account_ids && new_filter.program_ids == current_filter.program_ids {
+                    continue;
                 }
+                selection = FilterSelection::new(new_filter.clone());
+                added_filters.update(&current_filter, &new_filter);
+                // a snapshot in flight doesn't cover the latest additions
+                added_snapshot = future::Fuse::terminated();
+
+                let request = make_subscribe_request(&new_filter, subscribe_block_meta);
+                info!("Going to send updated request: {:?}", request);
+                request_sender
+                    .unbounded_send(request)
+                    .map_err(|_| ConnectorError::Transport("subscribe request stream closed".into()))?;
+                current_filter = new_filter;
             },
             _ = tokio::time::sleep(fatal_idle_timeout) => {
-                anyhow::bail!("geyser plugin hasn't sent a message in too long");
+                return Err(ConnectorError::IdleTimeout(fatal_idle_timeout));
             }
         }
     }
 }
 
-fn make_tls_config(config: &TlsConfig) -> ClientTlsConfig {
-    let server_root_ca_cert = match &config.ca_cert_path.chars().next().unwrap() {
-        '$' => env::var(&config.ca_cert_path[1..])
-            .expect("reading server root ca cert from env")
-            .into_bytes(),
-        _ => std::fs::read(&config.ca_cert_path).expect("reading server root ca cert from file"),
-    };
+fn make_tls_config(config: &TlsConfig) -> Result<ClientTlsConfig, ConnectorError> {
+    let server_root_ca_cert = config_file(&config.ca_cert_path, "server root ca cert")?;
     let server_root_ca_cert = Certificate::from_pem(server_root_ca_cert);
-    let client_cert = match &config.client_cert_path.chars().next().unwrap() {
-        '$' => env::var(&config.client_cert_path[1..])
-            .expect("reading client cert from env")
-            .into_bytes(),
-        _ => std::fs::read(&config.client_cert_path).expect("reading client cert from file"),
-    };
-    let client_key = match &config.client_key_path.chars().next().unwrap() {
-        '$' => env::var(&config.client_key_path[1..])
-            .expect("reading client key from env")
-            .into_bytes(),
-        _ => std::fs::read(&config.client_key_path).expect("reading client key from file"),
-    };
+    let client_cert = config_file(&config.client_cert_path, "client cert")?;
+    let client_key = config_file(&config.client_key_path, "client key")?;
     let client_identity = Identity::from_pem(client_cert, client_key);
-    let domain_name = match &config.domain_name.chars().next().unwrap() {
-        '$' => env::var(&config.domain_name[1..]).expect("reading domain name from env"),
-        _ => config.domain_name.clone(),
-    };
-    ClientTlsConfig::new()
+    let domain_name = config_value(&config.domain_name, "domain name")?;
+    Ok(ClientTlsConfig::new()
         .ca_certificate(server_root_ca_cert)
         .identity(client_identity)
-        .domain_name(domain_name)
+        .domain_name(domain_name))
 }
 
-pub async fn process_events(
-    config: &SourceConfig,
+/// Connection settings of a grpc source, resolved once at startup so that a bad config
+/// fails immediately instead of on every reconnect
+#[derive(Clone)]
+struct GrpcConnection {
+    connection_string: String,
+    endpoint: Endpoint,
+    token: Option<MetadataValue<Ascii>>,
+}
+
+fn make_grpc_connection(config: &GrpcSourceConfig) -> Result<GrpcConnection, ConnectorError> {
+    let invalid = |what: &str, err: &dyn std::fmt::Debug| {
+        ConnectorError::Config(format!(
+            "invalid {} for grpc source {}: {:?}",
+            what, config.name, err
+        ))
+    };
+
+    let connection_string = config_value(&config.connection_string, "connection string")?;
+    let endpoint = Channel::from_shared(connection_string.clone())
+        .map_err(|err| invalid("connection string", &err))?;
+
+    // Make TLS config if configured
+    let tls_config = match &config.tls {
+        Some(tls) => Some(make_tls_config(tls)?),
+        None if connection_string.starts_with("https") => Some(ClientTlsConfig::new()),
+        None => None,
+    };
+    let endpoint = match tls_config {
+        Some(tls) => endpoint
+            .tls_config(tls)
+            .map_err(|err| invalid("tls config", &err))?,
+        None => endpoint,
+    };
+
+    let token = match &config.token {
+        Some(token) => Some(
+            config_value(token, "token")?
+                .parse()
+                .map_err(|err| invalid("token", &err))?,
+        ),
+        None => None,
+    };
+
+    Ok(GrpcConnection {
+        connection_string,
+        endpoint,
+        token,
+    })
+}
+
+/// Stream account writes, slots, transactions and block meta from the configured grpc
+/// sources into the queues
+///
+/// Returns a handle for changing the filters while running and the future that runs
+/// the source. The future completes once `shutdown` is cancelled, or with the error
+/// that stopped the last of the grpc sources.
+#[allow(clippy::too_many_arguments)]
+pub fn process_events<'a>(
+    config: &'a SourceConfig,
     filter_config: &FilterConfig,
     account_write_queue_sender: async_channel::Sender<AccountWrite>,
     slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+    block_meta_queue_sender: Option<async_channel::Sender<BlockMetaUpdate>>,
     metrics_sender: Metrics,
-    exit: Arc<AtomicBool>,
+    shutdown: CancellationToken,
+) -> (
+    FilterHandle,
+    impl Future<Output = Result<(), ConnectorError>> + 'a,
 ) {
+    // Only subscribe to transactions if someone is listening for them
+    let mut filter_config = filter_config.clone();
+    if transaction_queue_sender.is_none() {
+        filter_config.transaction_filter = None;
+    }
+
+    let (filter_handle, filter_updates) = FilterHandle::new(filter_config);
+    let events = forward_events(
+        config,
+        filter_updates,
+        account_write_queue_sender,
+        slot_queue_sender,
+        transaction_queue_sender,
+        block_meta_queue_sender,
+        metrics_sender,
+        shutdown,
+    );
+    (filter_handle, events)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn forward_events(
+    config: &SourceConfig,
+    filter_updates: watch::Receiver<FilterConfig>,
+    account_write_queue_sender: async_channel::Sender<AccountWrite>,
+    slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+    block_meta_queue_sender: Option<async_channel::Sender<BlockMetaUpdate>>,
+    metrics_sender: Metrics,
+    shutdown: CancellationToken,
+) -> Result<(), ConnectorError> {
+    let subscribe_block_meta = block_meta_queue_sender.is_some();
+    // This version of the geyser subscription can't slice account data, so the full data
+    // arrives and is cut down here. Snapshots are sliced by the RPC node.
+    let data_slice = filter_updates.borrow().data_slice;
+
+    // Check the whole config before connecting anywhere
+    let connections = config
+        .grpc_sources
+        .iter()
+        .map(make_grpc_connection)
+        .collect::<Result<Vec<_>, _>>()?;
+    let rpc_http = RpcConnection::new(
+        &config.snapshot.rpc_http_url,
+        &config.snapshot.rpc_http_connection,
+    )?;
+
+    // Lets the account write queue make the connections start over with a new snapshot
+    let resnapshot = ResnapshotSignal::default();
+
     // Subscribe to geyser
     let (msg_sender, msg_receiver) = async_channel::bounded::<Message>(config.dedup_queue_size);
-    for grpc_source in config.grpc_sources.clone() {
+    for (source_index, (grpc_source, connection)) in config
+        .grpc_sources
+        .clone()
+        .into_iter()
+        .zip(connections)
+        .enumerate()
+    {
         let msg_sender = msg_sender.clone();
-        let snapshot_source = config.snapshot.clone();
+        let resnapshot = resnapshot.clone();
+        let snapshot_config = config.snapshot.clone();
+        let rpc_http = rpc_http.clone();
         let metrics_sender = metrics_sender.clone();
-        let f = filter_config.clone();
-
-        // Make TLS config if configured
-        let tls_config = grpc_source.tls.as_ref().map(make_tls_config).or_else(|| {
-            if grpc_source.connection_string.starts_with("https") {
-                Some(ClientTlsConfig::new())
-            } else {
-                None
-            }
-        });
+        let filter_updates = filter_updates.clone();
+        let shutdown = shutdown.clone();
 
         tokio::spawn(async move {
             let mut metric_retries = metrics_sender.register_u64(
@@ -377,31 +996,58 @@ pub async fn process_events(
             loop {
                 metric_connected.set(true);
                 let out = feed_data_geyser(
+                    source_index,
                     &grpc_source,
-                    tls_config.clone(),
-                    &snapshot_source,
-                    &f,
+                    &connection,
+                    &snapshot_config,
+                    &rpc_http,
+                    filter_updates.clone(),
+                    subscribe_block_meta,
                     msg_sender.clone(),
+                    resnapshot.clone(),
+                    &metrics_sender,
                 );
-                let result = out.await;
-                assert!(result.is_err());
-                if let Err(err) = result {
-                    warn!(
-                        "error during communication with the geyser plugin. retrying. {:?}",
-                        err
-                    );
-                }
-
+                let err = tokio::select! {
+                    out = out => match out {
+                        Ok(()) => unreachable!("feed_data_geyser only returns on errors"),
+                        Err(err) => err,
+                    },
+                    _ = shutdown.cancelled() => break,
+                };
                 metric_connected.set(false);
+                metrics_sender
+                    .register_u64(
+                        format!("grpc_source_{}_errors_{}", grpc_source.name, err.kind()),
+                        MetricType::Counter,
+                    )
+                    .increment();
+                if err.is_fatal() {
+                    warn!("grpc source {} stopped: {}", grpc_source.name, err);
+                    let _ = msg_sender
+                        .send(Message::SourceStopped {
+                            source: source_index,
+                            err,
+                        })
+                        .await;
+                    break;
+                }
+                warn!(
+                    "error during communication with the geyser plugin. retrying. {}",
+                    err
+                );
                 metric_retries.increment();
 
-                tokio::time::sleep(std::time::Duration::from_secs(
-                    grpc_source.retry_connection_sleep_secs,
-                ))
-                .await;
+                let retry_sleep = Duration::from_secs(grpc_source.retry_connection_sleep_secs);
+                tokio::select! {
+                    _ = tokio::time::sleep(retry_sleep) => {}
+                    _ = shutdown.cancelled() => break,
+                }
             }
         });
     }
+    // the channel closes once every source task ended
+    drop(msg_sender);
+    let mut running_sources = config.grpc_sources.len();
 
     // slot -> (pubkey -> write_version)
     //
@@ -413,6 +1059,32 @@ pub async fn process_events(
     // Number of slots to retain in latest_write
     let latest_write_retention = 50;
 
+    // slot -> signatures, to skip transactions that a different server has already sent
+    let mut latest_transactions = HashMap::<u64, HashSet<Signature>>::new();
+
+    // Slots that block meta was already sent for
+    let mut latest_block_meta = HashSet::<u64>::new();
+
+    // slot -> parent, as seen on the slot stream. Used to fill in the parent of block meta updates.
+    let mut slot_parents = HashMap::<u64, u64>::new();
+
+    // Used to notice accounts that were closed while no stream reported it
+    let mut known_accounts = KnownAccounts::default();
+
+    let mut account_write_queue = AccountWriteQueue::new(
+        config.queue_policy.clone(),
+        account_write_queue_sender,
+        resnapshot,
+        &metrics_sender,
+        "grpc",
+    );
+
+    let mut delivery_tracker = DeliveryTracker::new(&config.grpc_sources, &metrics_sender);
+
+    let mut consistency_checker = config.consistency_check.as_ref().map(|check_config| {
+        ConsistencyChecker::new(check_config.clone(), &config.grpc_sources, &metrics_sender)
+    });
+
     let mut metric_account_writes =
         metrics_sender.register_u64("grpc_account_writes".into(), MetricType::Counter);
     let mut metric_account_queue =
@@ -421,66 +1093,141 @@ pub async fn process_events(
         metrics_sender.register_u64("grpc_dedup_queue".into(), MetricType::Gauge);
     let mut metric_slot_queue =
         metrics_sender.register_u64("grpc_slot_update_queue".into(), MetricType::Gauge);
+    let mut metric_slot_queue_high_water = metrics_sender.register_u64(
+        "grpc_slot_update_queue_high_water".into(),
+        MetricType::Gauge,
+    );
     let mut metric_slot_updates =
         metrics_sender.register_u64("grpc_slot_updates".into(), MetricType::Counter);
     let mut metric_snapshots =
         metrics_sender.register_u64("grpc_snapshots".into(), MetricType::Counter);
     let mut metric_snapshot_account_writes =
         metrics_sender.register_u64("grpc_snapshot_account_writes".into(), MetricType::Counter);
+    let mut metric_snapshot_accounts_corrected = metrics_sender.register_u64(
+        "grpc_snapshot_accounts_corrected".into(),
+        MetricType::Counter,
+    );
+    let mut metric_transaction_updates =
+        metrics_sender.register_u64("grpc_transaction_updates".into(), MetricType::Counter);
+    let mut metric_transaction_queue =
+        metrics_sender.register_u64("grpc_transaction_update_queue".into(), MetricType::Gauge);
+    let mut metric_block_meta_updates =
+        metrics_sender.register_u64("grpc_block_meta_updates".into(), MetricType::Counter);
+    let mut metric_block_meta_queue =
+        metrics_sender.register_u64("grpc_block_meta_update_queue".into(), MetricType::Gauge);
 
     loop {
-        if exit.load(Ordering::Relaxed) {
+        if shutdown.is_cancelled() {
             warn!("shutting down grpc_plugin_source...");
             break;
         }
 
         metric_dedup_queue.set(msg_receiver.len() as u64);
-        let msg = msg_receiver.recv().await.expect("sender must not close");
+        let msg = if account_write_queue.has_pending() {
+            // coalesced writes need to move on once the consumer catches up
+            match tokio::time::timeout(Duration::from_millis(10), msg_receiver.recv()).await {
+                Ok(msg) => msg,
+                Err(_) => {
+                    account_write_queue.flush()?;
+                    continue;
+                }
+            }
+        } else {
+            tokio::select! {
+                msg = msg_receiver.recv() => msg,
+                _ = shutdown.cancelled() => continue,
+            }
+        };
+        let msg = match msg {
+            Ok(msg) => msg,
+            // the source tasks only end on shutdown or after reporting why they stopped
+            Err(_) if shutdown.is_cancelled() => break,
+            Err(_) => return Err(ConnectorError::ChannelClosed("grpc update")),
+        };
         match msg {
-            Message::GrpcUpdate(update) => {
-                use subscribe_update::UpdateOneof;
-                match update.update_oneof.expect("invalid grpc") {
+            Message::GrpcUpdate {
+                source,
+                received_at,
+                update,
+            } => {
+                match &update.update_oneof {
+                    Some(UpdateOneof::Account(info)) => {
+                        if let Some(write) = &info.account {
+                            if let Ok(pubkey) = Pubkey::try_from(write.pubkey.as_slice()) {
+                                delivery_tracker.record_account_write(
+                                    source,
+                                    received_at,
+                                    info.slot,
+                                    pubkey,
+                                    write.write_version,
+                                );
+                            }
+                        }
+                    }
+                    Some(UpdateOneof::Slot(slot_update)) => delivery_tracker.record_slot(
+                        source,
+                        received_at,
+                        slot_update.slot,
+                        slot_update.status,
+                    ),
+                    _ => {}
+                }
+
+                if let Some(checker) = consistency_checker.as_mut() {
+                    checker.observe(source, &update);
+                    if checker.is_quarantined(source) {
+                        continue;
+                    }
+                }
+
+                // feed_data_geyser only forwards updates with content and valid pubkeys
+                let update_oneof = match update.update_oneof {
+                    Some(x) => x,
+                    None => continue,
+                };
+                match update_oneof {
                     UpdateOneof::Account(info) => {
+                        // feed_data_geyser skips account updates without account
                         let update = match info.account.clone() {
                             Some(x) => x,
-                            None => {
-                                // TODO: handle error
-                                continue;
-                            }
+                            None => continue,
+                        };
+                        let (pubkey, owner) = match (
+                            Pubkey::try_from(update.pubkey.as_slice()),
+                            Pubkey::try_from(update.owner.as_slice()),
+                        ) {
+                            (Ok(pubkey), Ok(owner)) => (pubkey, owner),
+                            _ => continue,
                         };
-                        assert!(update.pubkey.len() == 32);
-                        assert!(update.owner.len() == 32);
 
                         metric_account_writes.increment();
-                        metric_account_queue.set(account_write_queue_sender.len() as u64);
+                        metric_account_queue.set(account_write_queue.len() as u64);
 
                         // Skip writes that a different server has already sent
                         let pubkey_writes = latest_write.entry(info.slot).or_default();
-                        let pubkey_bytes =
-                            Pubkey::try_from(update.pubkey.clone()).unwrap().to_bytes();
-                        let writes = pubkey_writes.entry(pubkey_bytes).or_insert(0);
+                        let writes = pubkey_writes.entry(pubkey.to_bytes()).or_insert(0);
                         if update.write_version <= *writes {
                             continue;
                         }
                         *writes = update.write_version;
-                        latest_write.retain(|&k, _| k >= info.slot - latest_write_retention);
-                        // let mut uncompressed: Vec<u8> = Vec::new();
-                        // zstd_decompress(&update.data, &mut uncompressed).unwrap();
-                        account_write_queue_sender
-                            .send(AccountWrite {
-                                pubkey: Pubkey::try_from(update.pubkey.clone()).unwrap(),
-                                slot: info.slot,
-                                write_version: update.write_version,
-                                lamports: update.lamports,
-                                owner: Pubkey::try_from(update.owner.clone()).unwrap(),
-                                executable: update.executable,
-                                rent_epoch: update.rent_epoch,
-                                data: update.data,
-                                // TODO: what should this be? related to account deletes?
-                                is_selected: true,
-                            })
-                            .await
-                            .expect("send success");
+                        latest_write
+                            .retain(|&k, _| k >= info.slot.saturating_sub(latest_write_retention));
+                        // closed accounts arrive with 0 lamports, see AccountWrite::is_deleted()
+                        let account_write = AccountWrite {
+                            pubkey,
+                            slot: info.slot,
+                            write_version: update.write_version,
+                            lamports: update.lamports,
+                            owner,
+                            executable: update.executable,
+                            rent_epoch: update.rent_epoch,
+                            data: update.data,
+                            data_slice: None,
+                            is_selected: true,
+                        }
+                        .slice_data(data_slice);
+                        known_accounts.observe(&account_write);
+                        account_write_queue.send(account_write).await?;
                     }
                     UpdateOneof::Slot(update) => {
                         metric_slot_updates.increment();
@@ -502,39 +1249,141 @@ pub async fn process_events(
                             status: status.expect("qed"),
                         };
 
+                        if let Some(parent) = update.parent {
+                            slot_parents.insert(update.slot, parent);
+                            slot_parents.retain(|&k, _| {
+                                k >= update.slot.saturating_sub(latest_write_retention)
+                            });
+                        }
+
                         slot_queue_sender
                             .send(slot_update)
                             .await
-                            .expect("send success");
+                            .map_err(|_| ConnectorError::ChannelClosed("slot update"))?;
+                        metric_slot_queue_high_water.set_max(slot_queue_sender.len() as u64);
+                    }
+                    UpdateOneof::Transaction(update) => {
+                        let transaction_queue_sender = match &transaction_queue_sender {
+                            Some(sender) => sender,
+                            None => continue,
+                        };
+                        let transaction_update = match make_transaction_update(update) {
+                            Some(x) => x,
+                            None => {
+                                warn!("skipping malformed transaction update");
+                                continue;
+                            }
+                        };
+
+                        metric_transaction_updates.increment();
+                        metric_transaction_queue.set(transaction_queue_sender.len() as u64);
+
+                        // Skip transactions that a different server has already sent
+                        let slot = transaction_update.slot;
+                        if !latest_transactions
+                            .entry(slot)
+                            .or_default()
+                            .insert(transaction_update.signature)
+                        {
+                            continue;
+                        }
+                        latest_transactions
+                            .retain(|&k, _| k >= slot.saturating_sub(latest_write_retention));
+
+                        transaction_queue_sender
+                            .send(transaction_update)
+                            .await
+                            .map_err(|_| ConnectorError::ChannelClosed("transaction update"))?;
+                    }
+                    UpdateOneof::BlockMeta(update) => {
+                        let block_meta_queue_sender = match &block_meta_queue_sender {
+                            Some(sender) => sender,
+                            None => continue,
+                        };
+
+                        // Skip block meta that a different server has already sent
+                        let slot = update.slot;
+                        if !latest_block_meta.insert(slot) {
+                            continue;
+                        }
+                        latest_block_meta
+                            .retain(|&k| k >= slot.saturating_sub(latest_write_retention));
+
+                        let block_meta_update = match make_block_meta_update(
+                            update,
+                            slot_parents.get(&slot).copied(),
+                        ) {
+                            Some(x) => x,
+                            None => {
+                                warn!("skipping malformed block meta update");
+                                continue;
+                            }
+                        };
+
+                        metric_block_meta_updates.increment();
+                        metric_block_meta_queue.set(block_meta_queue_sender.len() as u64);
+
+                        block_meta_queue_sender
+                            .send(block_meta_update)
+                            .await
+                            .map_err(|_| ConnectorError::ChannelClosed("block meta update"))?;
                     }
                     UpdateOneof::Block(_) => {}
-                    UpdateOneof::Transaction(_) => {}
-                    UpdateOneof::BlockMeta(_) => {}
                     UpdateOneof::Ping(_) => {}
                 }
             }
             Message::Snapshot(update) => {
                 metric_snapshots.increment();
                 info!("processing snapshot...");
-                for account in update.accounts.iter() {
+                let mut snapshot_pubkeys = HashSet::new();
+                for (pubkey, account) in update.accounts {
                     metric_snapshot_account_writes.increment();
-                    metric_account_queue.set(account_write_queue_sender.len() as u64);
-
-                    match account {
-                        (key, Some(ui_account)) => {
-                            // TODO: Resnapshot on invalid data?
-                            let pubkey = Pubkey::from_str(key).unwrap();
-                            let account: Account = ui_account.decode().unwrap();
-                            account_write_queue_sender
-                                .send(AccountWrite::from(pubkey, update.slot, 0, account))
-                                .await
-                                .expect("send success");
+                    metric_account_queue.set(account_write_queue.len() as u64);
+
+                    let account_write = match account {
+                        Some(account) => {
+                            snapshot_pubkeys.insert(pubkey);
+                            AccountWrite {
+                                data_slice: update.data_slice,
+                                ..AccountWrite::from(pubkey, update.slot, 0, account)
+                            }
+                        }
+                        // gMA reports closed accounts as missing
+                        None => {
+                            debug!("account not found {}", pubkey);
+                            AccountWrite::tombstone(pubkey, update.slot, 0)
                         }
-                        (key, None) => warn!("account not found {}", key),
+                    };
+                    if known_accounts.is_correction(&account_write) {
+                        metric_snapshot_accounts_corrected.increment();
                     }
+                    known_accounts.observe(&account_write);
+                    account_write_queue
+                        .send_snapshot_write(account_write)
+                        .await?;
+                }
+                for tombstone in known_accounts.closed_since_snapshot(
+                    &update.complete_program_ids,
+                    &snapshot_pubkeys,
+                    update.slot,
+                ) {
+                    metric_snapshot_accounts_corrected.increment();
+                    account_write_queue.send_snapshot_write(tombstone).await?;
                 }
                 info!("processing snapshot done");
             }
+            Message::SourceStopped { source, err } => {
+                running_sources -= 1;
+                if running_sources == 0 {
+                    return Err(err);
+                }
+                warn!(
+                    "grpc source {} stopped, {} sources left",
+                    config.grpc_sources[source].name, running_sources
+                );
+            }
         }
     }
+
+    Ok(())
 }
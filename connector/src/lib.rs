@@ -1,13 +1,31 @@
 pub mod account_write_filter;
 pub mod chain_data;
+pub mod error;
+pub mod filter_handle;
 pub mod grpc_plugin_source;
 pub mod metrics;
+pub mod queue;
+pub mod recording_sink;
+pub mod replay_source;
+pub mod rpc_connection;
+pub mod secrets;
+pub mod shutdown;
 pub mod snapshot;
+mod source_consistency;
+mod source_latency;
+#[cfg(feature = "test-support")]
+pub mod test_support;
 pub mod websocket_source;
 
 use {
-    serde_derive::Deserialize,
-    solana_sdk::{account::Account, pubkey::Pubkey},
+    secrets::{redact, redact_url},
+    serde_derive::{Deserialize, Serialize},
+    solana_account_decoder::UiDataSliceConfig,
+    solana_sdk::{
+        account::Account, hash::Hash, instruction::CompiledInstruction, pubkey::Pubkey,
+        signature::Signature,
+    },
+    std::{collections::HashMap, fmt},
 };
 
 #[cfg(all(feature = "solana-1-14", feature = "solana-1-15"))]
@@ -44,6 +62,8 @@ pub struct AccountWrite {
     pub executable: bool,
     pub rent_epoch: u64,
     pub data: Vec<u8>,
+    /// The range of the account data that `data` holds, None if it is the full data
+    pub data_slice: Option<DataSlice>,
     pub is_selected: bool,
 }
 
@@ -58,9 +78,40 @@ impl AccountWrite {
             executable: account.executable,
             rent_epoch: account.rent_epoch,
             data: account.data,
+            data_slice: None,
             is_selected: true,
         }
     }
+
+    /// Write that records the account as closed
+    fn tombstone(pubkey: Pubkey, slot: u64, write_version: u64) -> AccountWrite {
+        AccountWrite {
+            pubkey,
+            slot,
+            write_version,
+            lamports: 0,
+            owner: Pubkey::default(),
+            executable: false,
+            rent_epoch: 0,
+            data: vec![],
+            data_slice: None,
+            is_selected: true,
+        }
+    }
+
+    /// Whether this write closed the account: closed accounts have no lamports left
+    pub fn is_deleted(&self) -> bool {
+        self.lamports == 0
+    }
+
+    /// Cut the full account data down to `data_slice`
+    fn slice_data(mut self, data_slice: Option<DataSlice>) -> AccountWrite {
+        if let (Some(data_slice), None) = (data_slice, self.data_slice) {
+            self.data = data_slice.apply(&self.data);
+            self.data_slice = Some(data_slice);
+        }
+        self
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -70,6 +121,32 @@ pub struct SlotUpdate {
     pub status: chain_data::SlotStatus,
 }
 
+#[derive(Clone, Debug)]
+pub struct TransactionUpdate {
+    pub slot: u64,
+    pub signature: Signature,
+    /// Position of the transaction in its block
+    pub index: u64,
+    pub is_vote: bool,
+    pub is_failed: bool,
+    /// Static account keys, followed by the writable and then the readonly
+    /// keys loaded from address lookup tables
+    pub account_keys: Vec<Pubkey>,
+    pub instructions: Vec<CompiledInstruction>,
+    pub log_messages: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockMetaUpdate {
+    pub slot: u64,
+    pub blockhash: Hash,
+    /// Estimated production time of the block, as unix timestamp in seconds
+    pub block_time: Option<i64>,
+    pub block_height: Option<u64>,
+    /// Parent slot as seen on the slot stream, if it was known when the block arrived
+    pub parent: Option<u64>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct TlsConfig {
     pub ca_cert_path: String,
@@ -78,32 +155,322 @@ pub struct TlsConfig {
     pub domain_name: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// Compression of the grpc messages on the wire
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrpcCompression {
+    Gzip,
+}
+
+/// Compression of the data of each account write, done by the geyser plugin
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountDataCompression {
+    Zstd,
+    Lz4,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct GrpcSourceConfig {
     pub name: String,
     pub connection_string: String,
     pub token: Option<String>,
     pub retry_connection_sleep_secs: u64,
     pub tls: Option<TlsConfig>,
+    /// Ask the server to compress the grpc stream
+    pub compression: Option<GrpcCompression>,
+    /// Set if the plugin sends compressed account data, which is decompressed on arrival
+    pub account_data_compression: Option<AccountDataCompression>,
+}
+
+impl fmt::Debug for GrpcSourceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcSourceConfig")
+            .field("name", &self.name)
+            .field("connection_string", &redact_url(&self.connection_string))
+            .field("token", &self.token.as_deref().map(redact))
+            .field(
+                "retry_connection_sleep_secs",
+                &self.retry_connection_sleep_secs,
+            )
+            .field("tls", &self.tls)
+            .field("compression", &self.compression)
+            .field("account_data_compression", &self.account_data_compression)
+            .finish()
+    }
+}
+
+/// Headers and TLS settings for connecting to a JSON-RPC node, for paid providers that
+/// want an auth header instead of a token in the url
+///
+/// Header values and paths starting with '$' are read from the named env var.
+#[derive(Clone, Default, Deserialize)]
+pub struct RpcConnectionConfig {
+    /// Sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// Sent with every request and with the websocket handshake
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub tls: Option<RpcTlsConfig>,
 }
 
+impl fmt::Debug for RpcConnectionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: HashMap<_, _> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name, redact(value)))
+            .collect();
+        f.debug_struct("RpcConnectionConfig")
+            .field("token", &self.token.as_deref().map(redact))
+            .field("headers", &headers)
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+/// TLS settings of a JSON-RPC connection, the webpki roots are trusted either way
 #[derive(Clone, Debug, Deserialize)]
+pub struct RpcTlsConfig {
+    /// Additional CA certificate to trust, for nodes with a private CA
+    pub ca_cert_path: Option<String>,
+    /// Client certificate for mutual TLS, needs `client_key_path` too
+    pub client_cert_path: Option<String>,
+    pub client_key_path: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct SourceConfig {
     pub dedup_queue_size: usize,
     pub grpc_sources: Vec<GrpcSourceConfig>,
     pub snapshot: SnapshotSourceConfig,
     pub rpc_ws_url: String,
+    /// Headers and TLS settings of the websocket source
+    #[serde(default)]
+    pub rpc_ws_connection: RpcConnectionConfig,
+    /// Record the account writes and slot updates received from the source
+    pub recording: Option<RecordingConfig>,
+    /// Play back a recording instead of connecting to a live source
+    pub replay: Option<ReplaySourceConfig>,
+    /// Compare the data of the grpc sources against each other
+    pub consistency_check: Option<ConsistencyCheckConfig>,
+    /// What to do when the consumer of the account writes falls behind
+    #[serde(default)]
+    pub queue_policy: QueuePolicy,
+    /// Rooted account history that the consumers keep for `ChainData::account_at_slot()`,
+    /// only the newest rooted write of each account if unset
+    pub history_retention: Option<chain_data::HistoryRetention>,
+}
+
+impl fmt::Debug for SourceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SourceConfig")
+            .field("dedup_queue_size", &self.dedup_queue_size)
+            .field("grpc_sources", &self.grpc_sources)
+            .field("snapshot", &self.snapshot)
+            .field("rpc_ws_url", &redact_url(&self.rpc_ws_url))
+            .field("rpc_ws_connection", &self.rpc_ws_connection)
+            .field("recording", &self.recording)
+            .field("replay", &self.replay)
+            .field("consistency_check", &self.consistency_check)
+            .field("queue_policy", &self.queue_policy)
+            .field("history_retention", &self.history_retention)
+            .finish()
+    }
+}
+
+/// Bounds the queues between a source and its consumer
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    #[default]
+    Unbounded,
+    /// The source waits while the queue is full
+    Blocking { capacity: usize },
+    /// While the queue is full the source keeps only the newest write of each account,
+    /// older writes of an account are superseded anyway
+    CoalescePerPubkey { capacity: usize },
+    /// When the queue is full the source drops writes and starts over with a new snapshot
+    FailFast { capacity: usize },
+}
+
+impl QueuePolicy {
+    pub fn capacity(&self) -> Option<usize> {
+        match self {
+            QueuePolicy::Unbounded => None,
+            QueuePolicy::Blocking { capacity }
+            | QueuePolicy::CoalescePerPubkey { capacity }
+            | QueuePolicy::FailFast { capacity } => Some(*capacity),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConsistencyCheckConfig {
+    /// Stop using a source that keeps disagreeing with the majority of the sources
+    pub quarantine: Option<QuarantineConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct QuarantineConfig {
+    /// Number of disagreements in a row that put a source into quarantine
+    pub after_divergences: u64,
+    /// How long updates from a quarantined source are ignored
+    pub duration_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RecordingConfig {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplaySourceConfig {
+    pub path: String,
+    /// Playback speed relative to the recording: 1.0 is original speed, 10.0 is ten
+    /// times faster. Plays back as fast as possible if unset.
+    pub speed: Option<f64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
+pub struct ChainDataPersistenceConfig {
+    /// File that the rooted account state is saved to and loaded from on startup
+    pub path: String,
+    pub interval_secs: u64,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct SnapshotSourceConfig {
     pub rpc_http_url: String,
+    /// Headers and TLS settings of the snapshot requests
+    #[serde(default)]
+    pub rpc_http_connection: RpcConnectionConfig,
+    /// Take a new snapshot this often while connected, to correct writes that were
+    /// missed. The websocket source defaults to 300s, the grpc source to never.
+    pub resnapshot_interval_secs: Option<u64>,
+}
+
+impl fmt::Debug for SnapshotSourceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SnapshotSourceConfig")
+            .field("rpc_http_url", &redact_url(&self.rpc_http_url))
+            .field("rpc_http_connection", &self.rpc_http_connection)
+            .field("resnapshot_interval_secs", &self.resnapshot_interval_secs)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountFilter {
+    /// Account data at `offset` must equal the base58 encoded `bytes`
+    Memcmp { offset: u64, bytes: String },
+    /// Account data must have exactly this length
+    DataSize(u64),
+}
+
+impl AccountFilter {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            AccountFilter::Memcmp { offset, bytes } => {
+                let bytes = match bs58::decode(bytes).into_vec() {
+                    Ok(bytes) => bytes,
+                    Err(_) => return false,
+                };
+                let offset = *offset as usize;
+                data.len() >= offset + bytes.len() && data[offset..offset + bytes.len()] == bytes
+            }
+            AccountFilter::DataSize(size) => data.len() as u64 == *size,
+        }
+    }
+
+    /// Like `matches`, for `data` that only holds `data_slice` of the account data.
+    ///
+    /// The filter is checked against the full data it refers to, so a memcmp on bytes
+    /// outside of the slice and a data size can't pass on sliced data.
+    pub fn matches_slice(&self, data: &[u8], data_slice: Option<DataSlice>) -> bool {
+        let data_slice = match data_slice {
+            Some(data_slice) => data_slice,
+            None => return self.matches(data),
+        };
+        match self {
+            AccountFilter::Memcmp { offset, bytes } => {
+                match offset.checked_sub(data_slice.offset as u64) {
+                    Some(offset) => AccountFilter::Memcmp {
+                        offset,
+                        bytes: bytes.clone(),
+                    }
+                    .matches(data),
+                    None => false,
+                }
+            }
+            AccountFilter::DataSize(_) => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct TransactionFilterConfig {
+    /// Transactions must mention at least one of these accounts
+    pub account_include: Vec<String>,
+    /// Transactions must not mention any of these accounts
+    #[serde(default)]
+    pub account_exclude: Vec<String>,
+    /// Include (true) or exclude (false) vote transactions, both if unset
+    pub vote: Option<bool>,
+    /// Include (true) or exclude (false) failed transactions, both if unset
+    pub failed: Option<bool>,
+}
+
+/// A byte range of the account data
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct DataSlice {
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl DataSlice {
+    /// The part of `data` in the range, shorter if the data ends before it
+    pub fn apply(&self, data: &[u8]) -> Vec<u8> {
+        let start = self.offset.min(data.len());
+        let end = self.offset.saturating_add(self.length).min(data.len());
+        data[start..end].to_vec()
+    }
+}
+
+impl From<DataSlice> for UiDataSliceConfig {
+    fn from(data_slice: DataSlice) -> Self {
+        UiDataSliceConfig {
+            offset: data_slice.offset,
+            length: data_slice.length,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct FilterConfig {
     pub program_ids: Vec<String>,
     pub account_ids: Vec<String>,
+    /// Filters that every account must pass, applied to the geyser subscription
+    /// and to the snapshots alike
+    #[serde(default)]
+    pub account_filters: Vec<AccountFilter>,
+    /// Subscribe to transactions matching this filter, only supported by the grpc source
+    pub transaction_filter: Option<TransactionFilterConfig>,
+    /// Only deliver this range of the account data, see `AccountWrite::data_slice`.
+    /// The account filters still apply to the full data.
+    #[serde(default)]
+    pub data_slice: Option<DataSlice>,
+}
+
+impl FilterConfig {
+    /// Whether the account passes all account filters, `data_slice` being the range of
+    /// the account data that `data` holds, see `AccountFilter::matches_slice`
+    pub fn matches_account_filters(&self, data: &[u8], data_slice: Option<DataSlice>) -> bool {
+        self.account_filters
+            .iter()
+            .all(|f| f.matches_slice(data, data_slice))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -0,0 +1,190 @@
+//! Queues between the sources and the services consuming their updates.
+//!
+//! The consumer creates its queues with `channel()` for the configured `QueuePolicy`,
+//! the sources apply the same policy when the queue is full.
+
+use log::*;
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    error::ConnectorError,
+    metrics::{MetricType, MetricU64, Metrics},
+    AccountWrite, QueuePolicy,
+};
+
+/// Create a queue for `policy`: bounded to its capacity, if it has one
+pub fn channel<T>(policy: &QueuePolicy) -> (async_channel::Sender<T>, async_channel::Receiver<T>) {
+    match policy.capacity() {
+        Some(capacity) => async_channel::bounded(capacity),
+        None => async_channel::unbounded(),
+    }
+}
+
+/// Lets the account write queue ask the source connections to start over with a new snapshot
+#[derive(Clone, Default)]
+pub(crate) struct ResnapshotSignal {
+    generation: Arc<AtomicU64>,
+}
+
+impl ResnapshotSignal {
+    pub fn request(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Changes every time a resnapshot is requested
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+/// Source side of the account write queue, applying the queue policy when it is full
+pub(crate) struct AccountWriteQueue {
+    policy: QueuePolicy,
+    sender: async_channel::Sender<AccountWrite>,
+    /// Pubkeys with a coalesced write waiting for room in the queue, oldest first
+    pending_pubkeys: VecDeque<Pubkey>,
+    pending_writes: HashMap<Pubkey, AccountWrite>,
+    resnapshot: ResnapshotSignal,
+    /// Writes were dropped and no snapshot was sent since
+    awaiting_snapshot: bool,
+    metric_high_water: MetricU64,
+    metric_coalesced: MetricU64,
+    metric_dropped: MetricU64,
+}
+
+impl AccountWriteQueue {
+    pub fn new(
+        policy: QueuePolicy,
+        sender: async_channel::Sender<AccountWrite>,
+        resnapshot: ResnapshotSignal,
+        metrics_sender: &Metrics,
+        metrics_prefix: &str,
+    ) -> Self {
+        Self {
+            policy,
+            sender,
+            pending_pubkeys: VecDeque::new(),
+            pending_writes: HashMap::new(),
+            resnapshot,
+            awaiting_snapshot: false,
+            metric_high_water: metrics_sender.register_u64(
+                format!("{}_account_write_queue_high_water", metrics_prefix),
+                MetricType::Gauge,
+            ),
+            metric_coalesced: metrics_sender.register_u64(
+                format!("{}_account_write_queue_coalesced", metrics_prefix),
+                MetricType::Counter,
+            ),
+            metric_dropped: metrics_sender.register_u64(
+                format!("{}_account_write_queue_dropped", metrics_prefix),
+                MetricType::Counter,
+            ),
+        }
+    }
+
+    /// Number of writes that are queued, including coalesced ones waiting for room
+    pub fn len(&self) -> usize {
+        self.sender.len() + self.pending_writes.len()
+    }
+
+    /// Whether coalesced writes are waiting for room in the queue
+    pub fn has_pending(&self) -> bool {
+        !self.pending_writes.is_empty()
+    }
+
+    /// Queue a live account write according to the policy
+    pub async fn send(&mut self, write: AccountWrite) -> Result<(), ConnectorError> {
+        self.flush()?;
+        match self.policy {
+            QueuePolicy::Unbounded | QueuePolicy::Blocking { .. } => {
+                self.sender.send(write).await.map_err(|_| Self::closed())?;
+            }
+            QueuePolicy::CoalescePerPubkey { .. } => {
+                if self.has_pending() {
+                    self.coalesce(write);
+                } else if let Err(err) = self.sender.try_send(write) {
+                    match err {
+                        async_channel::TrySendError::Full(write) => self.coalesce(write),
+                        async_channel::TrySendError::Closed(_) => return Err(Self::closed()),
+                    }
+                }
+            }
+            QueuePolicy::FailFast { .. } => {
+                if let Err(err) = self.sender.try_send(write) {
+                    match err {
+                        async_channel::TrySendError::Full(_) => {
+                            self.metric_dropped.increment();
+                            if !self.awaiting_snapshot {
+                                warn!("account write queue is full, dropping writes until a new snapshot");
+                                self.awaiting_snapshot = true;
+                                self.resnapshot.request();
+                            }
+                        }
+                        async_channel::TrySendError::Closed(_) => return Err(Self::closed()),
+                    }
+                }
+            }
+        }
+        self.metric_high_water.set_max(self.len() as u64);
+        Ok(())
+    }
+
+    /// Queue an account write from a snapshot, waiting for room
+    ///
+    /// Snapshots are what brings the consumer back to a consistent state, so their
+    /// writes are never dropped or coalesced.
+    pub async fn send_snapshot_write(&mut self, write: AccountWrite) -> Result<(), ConnectorError> {
+        self.awaiting_snapshot = false;
+        self.sender.send(write).await.map_err(|_| Self::closed())?;
+        self.metric_high_water.set_max(self.len() as u64);
+        Ok(())
+    }
+
+    /// Move coalesced writes into the queue while it has room
+    pub fn flush(&mut self) -> Result<(), ConnectorError> {
+        while let Some(pubkey) = self.pending_pubkeys.pop_front() {
+            let write = self
+                .pending_writes
+                .remove(&pubkey)
+                .expect("pending pubkeys have a write");
+            match self.sender.try_send(write) {
+                Ok(()) => {}
+                Err(async_channel::TrySendError::Full(write)) => {
+                    self.pending_writes.insert(pubkey, write);
+                    self.pending_pubkeys.push_front(pubkey);
+                    break;
+                }
+                Err(async_channel::TrySendError::Closed(_)) => return Err(Self::closed()),
+            }
+        }
+        Ok(())
+    }
+
+    fn closed() -> ConnectorError {
+        ConnectorError::ChannelClosed("account write")
+    }
+
+    fn coalesce(&mut self, write: AccountWrite) {
+        match self.pending_writes.get_mut(&write.pubkey) {
+            Some(pending) => {
+                self.metric_coalesced.increment();
+                let is_newer =
+                    (write.slot, write.write_version) > (pending.slot, pending.write_version);
+                if is_newer {
+                    *pending = write;
+                }
+            }
+            None => {
+                self.pending_pubkeys.push_back(write.pubkey);
+                self.pending_writes.insert(write.pubkey, write);
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    chain_data::SlotStatus,
+    error::ConnectorError,
+    metrics::{MetricType, MetricU64, Metrics},
+    queue, AccountWrite, DataSlice, QueuePolicy, RecordingConfig, SlotUpdate,
+};
+
+/// Entries waiting to be written to the file, the recording waits once this many are queued
+const WRITE_QUEUE_SIZE: usize = 10_000;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One entry of a recording file. Files are a plain sequence of bincode encoded entries.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RecordEntry {
+    /// Arrival time, relative to the start of the recording
+    pub micros: u64,
+    pub event: RecordEvent,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum RecordEvent {
+    AccountWrite {
+        pubkey: Pubkey,
+        slot: u64,
+        write_version: u64,
+        lamports: u64,
+        owner: Pubkey,
+        executable: bool,
+        rent_epoch: u64,
+        data: Vec<u8>,
+    },
+    SlotUpdate {
+        slot: u64,
+        parent: Option<u64>,
+        // 0 = processed, 1 = confirmed, 2 = rooted
+        status: u8,
+    },
+    /// An account write that only holds `data_slice` of the account data. A separate
+    /// variant, so recordings made before data slices existed can still be read.
+    SlicedAccountWrite {
+        pubkey: Pubkey,
+        slot: u64,
+        write_version: u64,
+        lamports: u64,
+        owner: Pubkey,
+        executable: bool,
+        rent_epoch: u64,
+        data: Vec<u8>,
+        data_slice: DataSlice,
+    },
+}
+
+impl RecordEvent {
+    fn from_account_write(write: &AccountWrite) -> Self {
+        match write.data_slice {
+            None => RecordEvent::AccountWrite {
+                pubkey: write.pubkey,
+                slot: write.slot,
+                write_version: write.write_version,
+                lamports: write.lamports,
+                owner: write.owner,
+                executable: write.executable,
+                rent_epoch: write.rent_epoch,
+                data: write.data.clone(),
+            },
+            Some(data_slice) => RecordEvent::SlicedAccountWrite {
+                pubkey: write.pubkey,
+                slot: write.slot,
+                write_version: write.write_version,
+                lamports: write.lamports,
+                owner: write.owner,
+                executable: write.executable,
+                rent_epoch: write.rent_epoch,
+                data: write.data.clone(),
+                data_slice,
+            },
+        }
+    }
+
+    fn from_slot_update(update: &SlotUpdate) -> Self {
+        RecordEvent::SlotUpdate {
+            slot: update.slot,
+            parent: update.parent,
+            status: match update.status {
+                SlotStatus::Processed => 0,
+                SlotStatus::Confirmed => 1,
+                SlotStatus::Rooted => 2,
+            },
+        }
+    }
+}
+
+pub(crate) fn slot_status_from_u8(status: u8) -> Result<SlotStatus, ConnectorError> {
+    match status {
+        0 => Ok(SlotStatus::Processed),
+        1 => Ok(SlotStatus::Confirmed),
+        2 => Ok(SlotStatus::Rooted),
+        _ => Err(ConnectorError::InvalidUpdate(format!(
+            "unknown slot status {} in recording",
+            status
+        ))),
+    }
+}
+
+/// Record the account write and slot update streams to a file while passing them on.
+///
+/// The returned senders are meant to be handed to a source; everything sent to them is
+/// written to `config.path` and forwarded to the given senders. The file can be played
+/// back with `replay_source::process_events`.
+///
+/// The returned queues are bounded by the same `queue_policy` as the consumer's, and
+/// writes are only taken from them once the consumer accepted the previous one, so a
+/// slow consumer fills them up and the source applies the policy.
+pub fn init(
+    config: &RecordingConfig,
+    account_write_queue_sender: async_channel::Sender<AccountWrite>,
+    slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    queue_policy: &QueuePolicy,
+    metrics_sender: Metrics,
+) -> anyhow::Result<(
+    async_channel::Sender<AccountWrite>,
+    async_channel::Sender<SlotUpdate>,
+)> {
+    let (recorded_account_write_sender, recorded_account_write_receiver) =
+        queue::channel::<AccountWrite>(queue_policy);
+    let (recorded_slot_sender, recorded_slot_receiver) = queue::channel::<SlotUpdate>(queue_policy);
+
+    let writer = BufWriter::new(File::create(&config.path)?);
+    info!(
+        "recording account writes and slot updates to {}",
+        config.path
+    );
+
+    let metric_recorded =
+        metrics_sender.register_u64("recording_entries".into(), MetricType::Counter);
+
+    // the file is written on the blocking pool, so slow disks don't stall the runtime
+    let (entry_sender, entry_receiver) = async_channel::bounded::<RecordEntry>(WRITE_QUEUE_SIZE);
+    let file_writer =
+        tokio::task::spawn_blocking(move || write_entries(writer, entry_receiver, metric_recorded));
+
+    tokio::spawn(async move {
+        let start = Instant::now();
+        loop {
+            let event = tokio::select! {
+                Ok(account_write) = recorded_account_write_receiver.recv() => {
+                    let event = RecordEvent::from_account_write(&account_write);
+                    if account_write_queue_sender.send(account_write).await.is_err() {
+                        // the consumers are gone, e.g. on shutdown
+                        info!("account write receiver closed, recording stopped");
+                        break;
+                    }
+                    event
+                }
+                Ok(slot_update) = recorded_slot_receiver.recv() => {
+                    let event = RecordEvent::from_slot_update(&slot_update);
+                    if slot_queue_sender.send(slot_update).await.is_err() {
+                        // the consumers are gone, e.g. on shutdown
+                        info!("slot update receiver closed, recording stopped");
+                        break;
+                    }
+                    event
+                }
+                // both channels are closed and empty
+                else => break,
+            };
+
+            let entry = RecordEntry {
+                micros: start.elapsed().as_micros() as u64,
+                event,
+            };
+            if entry_sender.send(entry).await.is_err() {
+                error!("recording file writer stopped, recording stopped");
+                break;
+            }
+        }
+
+        // the consumers only see the channels close once the file is complete
+        drop(entry_sender);
+        if let Err(err) = file_writer.await {
+            error!("recording file writer failed: {:?}", err);
+        }
+        drop(account_write_queue_sender);
+        drop(slot_queue_sender);
+    });
+
+    Ok((recorded_account_write_sender, recorded_slot_sender))
+}
+
+/// Write entries to the file until the sender is dropped, flushing at least every
+/// `FLUSH_INTERVAL` while entries arrive
+fn write_entries(
+    mut writer: BufWriter<File>,
+    entries: async_channel::Receiver<RecordEntry>,
+    mut metric_recorded: MetricU64,
+) {
+    let mut flushed_at = Instant::now();
+    while let Ok(entry) = futures::executor::block_on(entries.recv()) {
+        if let Err(err) = bincode::serialize_into(&mut writer, &entry) {
+            error!("could not write recording entry: {:?}", err);
+            continue;
+        }
+        metric_recorded.increment();
+        if flushed_at.elapsed() >= FLUSH_INTERVAL {
+            if let Err(err) = writer.flush() {
+                error!("could not flush recording: {:?}", err);
+            }
+            flushed_at = Instant::now();
+        }
+    }
+    if let Err(err) = writer.flush() {
+        error!("could not flush recording: {:?}", err);
+    }
+}
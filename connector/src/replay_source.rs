@@ -0,0 +1,189 @@
+use futures::{Future, FutureExt};
+use log::*;
+use std::{
+    fs::File,
+    io::BufReader,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+use crate::{
+    error::ConnectorError,
+    filter_handle::{FilterHandle, FilterSelection},
+    metrics::{MetricType, Metrics},
+    recording_sink::{slot_status_from_u8, RecordEntry, RecordEvent},
+    shutdown::CancellationToken,
+    AccountWrite, BlockMetaUpdate, FilterConfig, SlotUpdate, SourceConfig, TransactionUpdate,
+};
+
+/// Play back a file written by `recording_sink`.
+///
+/// Takes the same arguments and returns the same as `grpc_plugin_source::process_events`,
+/// so that a service can swap sources. Account writes are filtered by `filter_config`,
+/// changes made through the handle apply to the writes replayed after them. Recordings
+/// contain no transactions or block meta, so those senders never receive anything.
+///
+/// The future completes once the whole recording was played back or `shutdown` was
+/// cancelled, with a `Config` error if the recording can't be opened, or with an
+/// `InvalidUpdate` error if it holds an unknown slot status.
+#[allow(clippy::too_many_arguments)]
+pub fn process_events<'a>(
+    config: &'a SourceConfig,
+    filter_config: &FilterConfig,
+    account_write_queue_sender: async_channel::Sender<AccountWrite>,
+    slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    _transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+    _block_meta_queue_sender: Option<async_channel::Sender<BlockMetaUpdate>>,
+    metrics_sender: Metrics,
+    shutdown: CancellationToken,
+) -> (
+    FilterHandle,
+    impl Future<Output = Result<(), ConnectorError>> + 'a,
+) {
+    let (filter_handle, filter_updates) = FilterHandle::new(filter_config.clone());
+    let events = replay(
+        config,
+        filter_updates,
+        account_write_queue_sender,
+        slot_queue_sender,
+        metrics_sender,
+        shutdown,
+    );
+    (filter_handle, events)
+}
+
+async fn replay(
+    config: &SourceConfig,
+    mut filter_updates: watch::Receiver<FilterConfig>,
+    account_write_queue_sender: async_channel::Sender<AccountWrite>,
+    slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    metrics_sender: Metrics,
+    shutdown: CancellationToken,
+) -> Result<(), ConnectorError> {
+    let replay_config = config
+        .replay
+        .as_ref()
+        .ok_or_else(|| ConnectorError::Config("replay source needs a replay config".into()))?;
+    let file = File::open(&replay_config.path).map_err(|err| {
+        ConnectorError::Config(format!("opening recording {}: {}", replay_config.path, err))
+    })?;
+    let mut reader = BufReader::new(file);
+
+    // speed factor relative to the recording, None means as fast as possible
+    let speed = replay_config.speed.filter(|s| *s > 0.0);
+    info!(
+        "replaying {} at speed {:?}",
+        replay_config.path, replay_config.speed
+    );
+
+    let mut metric_account_writes =
+        metrics_sender.register_u64("replay_account_writes".into(), MetricType::Counter);
+    let mut metric_slot_updates =
+        metrics_sender.register_u64("replay_slot_updates".into(), MetricType::Counter);
+
+    let mut selection = FilterSelection::new(filter_updates.borrow().clone());
+    let start = Instant::now();
+    loop {
+        if shutdown.is_cancelled() {
+            warn!("shutting down replay_source...");
+            break;
+        }
+
+        let entry: RecordEntry = match bincode::deserialize_from(&mut reader) {
+            Ok(entry) => entry,
+            Err(err) => {
+                match *err {
+                    bincode::ErrorKind::Io(ref io_err)
+                        if io_err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                    {
+                        info!("replay finished");
+                    }
+                    _ => error!("could not read recording entry: {:?}", err),
+                }
+                break;
+            }
+        };
+
+        if let Some(speed) = speed {
+            let due = start + Duration::from_micros((entry.micros as f64 / speed) as u64);
+            tokio::select! {
+                _ = tokio::time::sleep_until(due.into()) => {}
+                _ = shutdown.cancelled() => continue,
+            }
+        }
+
+        let write = match entry.event {
+            RecordEvent::AccountWrite {
+                pubkey,
+                slot,
+                write_version,
+                lamports,
+                owner,
+                executable,
+                rent_epoch,
+                data,
+            } => AccountWrite {
+                pubkey,
+                slot,
+                write_version,
+                lamports,
+                owner,
+                executable,
+                rent_epoch,
+                data,
+                data_slice: None,
+                is_selected: true,
+            },
+            RecordEvent::SlicedAccountWrite {
+                pubkey,
+                slot,
+                write_version,
+                lamports,
+                owner,
+                executable,
+                rent_epoch,
+                data,
+                data_slice,
+            } => AccountWrite {
+                pubkey,
+                slot,
+                write_version,
+                lamports,
+                owner,
+                executable,
+                rent_epoch,
+                data,
+                data_slice: Some(data_slice),
+                is_selected: true,
+            },
+            RecordEvent::SlotUpdate {
+                slot,
+                parent,
+                status,
+            } => {
+                metric_slot_updates.increment();
+                slot_queue_sender
+                    .send(SlotUpdate {
+                        slot,
+                        parent,
+                        status: slot_status_from_u8(status)?,
+                    })
+                    .await
+                    .map_err(|_| ConnectorError::ChannelClosed("slot update"))?;
+                continue;
+            }
+        };
+        if let Some(Ok(())) = filter_updates.changed().now_or_never() {
+            selection = FilterSelection::new(filter_updates.borrow().clone());
+        }
+        if !selection.selects_write(&write) {
+            continue;
+        }
+        metric_account_writes.increment();
+        account_write_queue_sender
+            .send(write.slice_data(selection.filter_config().data_slice))
+            .await
+            .map_err(|_| ConnectorError::ChannelClosed("account write"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! Connections to a JSON-RPC node with auth headers and TLS settings.
+//!
+//! The HTTP and websocket transports of jsonrpc-core-client can't send headers or use a
+//! custom TLS config. The jsonrpc clients talk to the node through the transports here
+//! instead, which hand the JSON messages to them through a duplex channel.
+
+use futures::{channel::mpsc, future, sink, SinkExt, StreamExt, TryFutureExt};
+use jsonrpc_core_client::{transports::duplex, RpcChannel, RpcError};
+use log::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use serde_json::json;
+use std::{fmt, sync::Arc};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, Message},
+    Connector,
+};
+
+use crate::{
+    error::ConnectorError,
+    secrets::{config_file, config_value, redact_url},
+    RpcConnectionConfig, RpcTlsConfig,
+};
+
+/// Url, headers and TLS config of a JSON-RPC node, resolved once at startup so that a
+/// bad config fails immediately instead of on every request
+#[derive(Clone)]
+pub struct RpcConnection {
+    url: String,
+    headers: HeaderMap,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    http_client: reqwest::Client,
+}
+
+impl RpcConnection {
+    pub fn new(url: &str, config: &RpcConnectionConfig) -> Result<Self, ConnectorError> {
+        let url = config_value(url, "rpc url")?;
+        let invalid = |what: &str, err: &dyn fmt::Display| {
+            ConnectorError::Config(format!(
+                "invalid {} for rpc {}: {}",
+                what,
+                redact_url(&url),
+                err
+            ))
+        };
+
+        reqwest::Url::parse(&url).map_err(|err| invalid("url", &err))?;
+
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &config.token {
+            let value = format!("Bearer {}", config_value(token, "rpc token")?);
+            let mut value = HeaderValue::from_str(&value).map_err(|err| invalid("token", &err))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|err| invalid("header name", &err))?;
+            let mut value = HeaderValue::from_str(&config_value(value, "rpc header")?)
+                .map_err(|err| invalid("header value", &err))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+
+        let tls = match &config.tls {
+            Some(tls) => Some(Arc::new(make_tls_config(tls)?)),
+            None => None,
+        };
+        let http_client = reqwest::Client::builder().default_headers(headers.clone());
+        let http_client = match &tls {
+            Some(tls) => http_client.use_preconfigured_tls(rustls::ClientConfig::clone(tls)),
+            None => http_client,
+        };
+        let http_client = http_client
+            .build()
+            .map_err(|err| invalid("http client", &err))?;
+
+        Ok(Self {
+            url,
+            headers,
+            tls,
+            http_client,
+        })
+    }
+
+    /// A jsonrpc client that sends every call as its own HTTP request
+    pub fn connect_http<TClient: From<RpcChannel>>(&self) -> TClient {
+        let (response_sender, responses) = mpsc::unbounded::<String>();
+        let http_client = self.http_client.clone();
+        let url = self.url.clone();
+        let requests = sink::unfold((), move |(), request: String| {
+            let http_client = http_client.clone();
+            let url = url.clone();
+            let response_sender = response_sender.clone();
+            // the requests of a client, like gMA chunks, run concurrently
+            tokio::spawn(async move {
+                let response = post(&http_client, &url, request).await;
+                let _ = response_sender.unbounded_send(response);
+            });
+            future::ready(Ok::<_, RpcError>(()))
+        });
+        let (client, channel) = duplex(Box::pin(requests), Box::pin(responses));
+        tokio::spawn(client.map_err(|err| warn!("rpc http client failed: {:?}", err)));
+        channel.into()
+    }
+
+    /// A jsonrpc client on a websocket, the headers are sent with the handshake
+    pub async fn connect_ws<TClient: From<RpcChannel>>(&self) -> Result<TClient, ConnectorError> {
+        let mut request = self.url.as_str().into_client_request().map_err(|err| {
+            ConnectorError::Config(format!(
+                "invalid websocket url {}: {}",
+                redact_url(&self.url),
+                err
+            ))
+        })?;
+        request.headers_mut().extend(self.headers.clone());
+        let connector = self.tls.clone().map(Connector::Rustls);
+        let (socket, _) =
+            tokio_tungstenite::connect_async_tls_with_config(request, None, connector)
+                .await
+                .map_err(|err| {
+                    ConnectorError::Transport(format!(
+                        "connecting to {}: {}",
+                        redact_url(&self.url),
+                        err
+                    ))
+                })?;
+
+        let (sink, stream) = socket.split();
+        let sink = sink
+            .sink_map_err(|err| RpcError::Other(Box::new(err)))
+            .with(|request: String| future::ready(Ok::<_, RpcError>(Message::Text(request))));
+        let stream = stream
+            .take_while(|message| future::ready(message.is_ok()))
+            .filter_map(|message| {
+                future::ready(match message {
+                    Ok(Message::Text(text)) => Some(text),
+                    Ok(Message::Binary(data)) => String::from_utf8(data).ok(),
+                    _ => None,
+                })
+            });
+        let (client, channel) = duplex(Box::pin(sink), Box::pin(stream));
+        tokio::spawn(client.map_err(|err| warn!("rpc websocket client failed: {:?}", err)));
+        Ok(channel.into())
+    }
+}
+
+impl fmt::Display for RpcConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", redact_url(&self.url))
+    }
+}
+
+/// Post a JSON-RPC request, a failed request is answered with a JSON-RPC error so that
+/// the call that sent it returns
+async fn post(http_client: &reqwest::Client, url: &str, request: String) -> String {
+    let response = async {
+        http_client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(request.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    }
+    .await;
+    match response {
+        Ok(response) => response,
+        Err(err) => {
+            // the url can contain a token
+            let err = err.without_url();
+            let id = serde_json::from_str::<serde_json::Value>(&request)
+                .map(|request| request["id"].clone())
+                .unwrap_or_default();
+            json!({
+                "jsonrpc": "2.0",
+                "error": { "code": -32603, "message": format!("http request failed: {}", err) },
+                "id": id,
+            })
+            .to_string()
+        }
+    }
+}
+
+fn make_tls_config(config: &RpcTlsConfig) -> Result<rustls::ClientConfig, ConnectorError> {
+    let invalid = |what: &str, err: &dyn fmt::Display| {
+        ConnectorError::Config(format!("invalid rpc {}: {}", what, err))
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    if let Some(path) = &config.ca_cert_path {
+        let pem = config_file(path, "rpc ca cert")?;
+        let certs = rustls_pemfile::certs(&mut &pem[..]).map_err(|err| invalid("ca cert", &err))?;
+        for cert in certs {
+            roots
+                .add(&rustls::Certificate(cert))
+                .map_err(|err| invalid("ca cert", &err))?;
+        }
+    }
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    match (&config.client_cert_path, &config.client_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let pem = config_file(cert_path, "rpc client cert")?;
+            let certs = rustls_pemfile::certs(&mut &pem[..])
+                .map_err(|err| invalid("client cert", &err))?
+                .into_iter()
+                .map(rustls::Certificate)
+                .collect();
+            let pem = config_file(key_path, "rpc client key")?;
+            let key = rustls_pemfile::read_all(&mut &pem[..])
+                .map_err(|err| invalid("client key", &err))?
+                .into_iter()
+                .find_map(|item| match item {
+                    rustls_pemfile::Item::RSAKey(key)
+                    | rustls_pemfile::Item::PKCS8Key(key)
+                    | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    ConnectorError::Config("no private key in the rpc client key".into())
+                })?;
+            builder
+                .with_single_cert(certs, key)
+                .map_err(|err| invalid("client cert", &err))
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(ConnectorError::Config(
+            "rpc client_cert_path and client_key_path must be set together".into(),
+        )),
+    }
+}
@@ -0,0 +1,68 @@
+//! Config values that can hold secrets, like tokens, auth headers and urls of paid RPC
+//! providers.
+//!
+//! Values starting with '$' are read from the named env var. Logs and the `Debug`
+//! output of the configs only show redacted values.
+
+use std::env;
+
+use crate::error::ConnectorError;
+
+const REDACTED: &str = "<redacted>";
+
+/// Resolve a config value, reading it from the named env var if it starts with '$'
+pub(crate) fn config_value(value: &str, what: &str) -> Result<String, ConnectorError> {
+    match value.strip_prefix('$') {
+        Some(var) => env::var(var).map_err(|err| {
+            ConnectorError::Config(format!("reading {} from env {}: {}", what, var, err))
+        }),
+        None => Ok(value.to_owned()),
+    }
+}
+
+/// Resolve a path config value, reading the contents from the named env var if it starts with '$'
+pub(crate) fn config_file(path: &str, what: &str) -> Result<Vec<u8>, ConnectorError> {
+    match path.strip_prefix('$') {
+        Some(_) => Ok(config_value(path, what)?.into_bytes()),
+        None => std::fs::read(path).map_err(|err| {
+            ConnectorError::Config(format!("reading {} from {}: {}", what, path, err))
+        }),
+    }
+}
+
+/// A secret config value for logs: the name of the env var it's read from, or nothing
+pub fn redact(value: &str) -> &str {
+    if value.starts_with('$') {
+        value
+    } else {
+        REDACTED
+    }
+}
+
+/// A url for logs, without user info, path and query since providers put tokens there
+pub fn redact_url(url: &str) -> String {
+    if url.starts_with('$') {
+        return url.to_owned();
+    }
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, url),
+    };
+    let (authority, path) = match rest.find(|c| c == '/' || c == '?' || c == '#') {
+        Some(end) => rest.split_at(end),
+        None => (rest, ""),
+    };
+    let host = match authority.rsplit_once('@') {
+        Some((_, host)) => host,
+        None => authority,
+    };
+    let mut redacted = match scheme {
+        Some(scheme) => format!("{}://{}", scheme, host),
+        None => host.to_owned(),
+    };
+    if authority.contains('@') || !matches!(path, "" | "/") {
+        redacted.push('/');
+        redacted.push_str(REDACTED);
+    }
+    redacted
+}
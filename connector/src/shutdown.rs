@@ -0,0 +1,48 @@
+//! Graceful shutdown on SIGINT and SIGTERM.
+//!
+//! Sources, filters and sinks take a `CancellationToken`. Once it is cancelled the
+//! sources stop and return, the filters save their chain data and stop, and sinks like
+//! the fills postgres target write out what was already queued before they stop.
+
+use log::*;
+use std::time::Duration;
+
+pub use tokio_util::sync::CancellationToken;
+
+/// Time to shut down before the process exits anyway, within the 30s kill timeout
+/// of the deployments
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(25);
+
+/// Wait for SIGINT or SIGTERM
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+            _ = sigterm.recv() => info!("received SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("install SIGINT handler");
+        info!("received SIGINT");
+    }
+}
+
+/// Cancel `shutdown` on SIGINT or SIGTERM, and exit the process if shutting down
+/// takes longer than `deadline`
+pub fn cancel_on_signal(shutdown: &CancellationToken, deadline: Duration) {
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        signal().await;
+        info!("shutting down...");
+        shutdown.cancel();
+        tokio::time::sleep(deadline).await;
+        error!("shutdown took longer than {:?}, exiting", deadline);
+        std::process::exit(1);
+    });
+}
@@ -1,32 +1,128 @@
 use anyhow::anyhow;
-use jsonrpc_core_client::transports::http;
+use futures::{future::join_all, stream, StreamExt, TryStreamExt};
 use log::*;
 use solana_account_decoder::{UiAccount, UiAccountEncoding};
 use solana_client::{
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
     rpc_response::{OptionalContext, RpcKeyedAccount},
 };
 use solana_rpc::rpc::rpc_accounts::AccountsDataClient;
-use solana_sdk::{commitment_config::CommitmentConfig, slot_history::Slot};
+use solana_sdk::{
+    account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey, slot_history::Slot,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use crate::{
+    error::ConnectorError, rpc_connection::RpcConnection, source_consistency::account_hash,
+    AccountFilter, AccountWrite, AnyhowWrap, DataSlice, FilterConfig,
+};
+
+/// Translate the account filters into their getProgramAccounts representation
+pub fn rpc_filters(account_filters: &[AccountFilter]) -> Option<Vec<RpcFilterType>> {
+    if account_filters.is_empty() {
+        return None;
+    }
+    Some(
+        account_filters
+            .iter()
+            .map(|f| match f {
+                AccountFilter::Memcmp { offset, bytes } => RpcFilterType::Memcmp(Memcmp {
+                    offset: *offset as usize,
+                    bytes: MemcmpEncodedBytes::Base58(bytes.clone()),
+                    encoding: None,
+                }),
+                AccountFilter::DataSize(size) => RpcFilterType::DataSize(*size),
+            })
+            .collect(),
+    )
+}
+
+/// The data slice to request from gMA: the account filters need the full data
+pub(crate) fn gma_data_slice(filter_config: &FilterConfig) -> Option<DataSlice> {
+    if filter_config.account_filters.is_empty() {
+        filter_config.data_slice
+    } else {
+        None
+    }
+}
+
+/// gMA can't filter on the server, so apply the account filters to its result.
+///
+/// Accounts that don't match are dropped, the same way the geyser subscription
+/// never reports them. The data of the others is sliced afterwards, see `gma_data_slice`.
+pub fn filter_gma_accounts(
+    filter_config: &FilterConfig,
+    accounts: Vec<(String, Option<UiAccount>)>,
+) -> Vec<(String, Option<UiAccount>)> {
+    if filter_config.account_filters.is_empty() {
+        return accounts;
+    }
+    accounts
+        .into_iter()
+        .filter_map(|(key, ui_account)| match ui_account {
+            Some(ui_account) => {
+                let account = ui_account.decode::<Account>()?;
+                if !filter_config.matches_account_filters(&account.data, None) {
+                    return None;
+                }
+                let ui_account = match (filter_config.data_slice, Pubkey::from_str(&key)) {
+                    (Some(data_slice), Ok(pubkey)) => UiAccount::encode(
+                        &pubkey,
+                        &account,
+                        UiAccountEncoding::Base64,
+                        None,
+                        Some(data_slice.into()),
+                    ),
+                    _ => ui_account,
+                };
+                Some((key, Some(ui_account)))
+            }
+            None => Some((key, None)),
+        })
+        .collect()
+}
 
-use crate::{AnyhowWrap, FilterConfig};
+/// Decode the accounts of a snapshot, accounts that are missing stay None
+pub fn decode_snapshot_accounts(
+    accounts: Vec<(String, Option<UiAccount>)>,
+) -> Result<Vec<(Pubkey, Option<Account>)>, ConnectorError> {
+    accounts
+        .into_iter()
+        .map(|(key, ui_account)| {
+            let pubkey = Pubkey::from_str(&key).map_err(|err| {
+                ConnectorError::Snapshot(format!("invalid pubkey {}: {}", key, err))
+            })?;
+            let account = match ui_account {
+                Some(ui_account) => Some(ui_account.decode::<Account>().ok_or_else(|| {
+                    ConnectorError::Snapshot(format!("undecodable account {}", pubkey))
+                })?),
+                None => None,
+            };
+            Ok((pubkey, account))
+        })
+        .collect()
+}
 
 pub async fn get_snapshot_gpa(
-    rpc_http_url: String,
+    rpc_http: RpcConnection,
     program_id: String,
+    account_filters: Vec<AccountFilter>,
+    data_slice: Option<DataSlice>,
 ) -> anyhow::Result<OptionalContext<Vec<RpcKeyedAccount>>> {
-    let rpc_client = http::connect::<crate::GetProgramAccountsClient>(&rpc_http_url)
-        .await
-        .map_err_anyhow()?;
+    let rpc_client = rpc_http.connect_http::<crate::GetProgramAccountsClient>();
 
     let account_info_config = RpcAccountInfoConfig {
         encoding: Some(UiAccountEncoding::Base64),
         commitment: Some(CommitmentConfig::finalized()),
-        data_slice: None,
+        data_slice: data_slice.map(Into::into),
         min_context_slot: None,
     };
     let program_accounts_config = RpcProgramAccountsConfig {
-        filters: None,
+        filters: rpc_filters(&account_filters),
         with_context: Some(true),
         account_config: account_info_config.clone(),
     };
@@ -40,37 +136,88 @@ pub async fn get_snapshot_gpa(
     Ok(account_snapshot)
 }
 
-pub async fn get_snapshot_gma(
-    rpc_http_url: String,
-    ids: Vec<String>,
-) -> anyhow::Result<solana_client::rpc_response::Response<Vec<Option<UiAccount>>>> {
-    let rpc_client = http::connect::<AccountsDataClient>(&rpc_http_url)
-        .await
-        .map_err_anyhow()?;
+/// RPC nodes refuse getMultipleAccounts requests for more accounts than this
+const GMA_CHUNK_SIZE: usize = 100;
 
+/// How many getMultipleAccounts chunks are requested at the same time
+const GMA_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+async fn get_multiple_accounts(
+    rpc_client: &AccountsDataClient,
+    ids: &[String],
+    data_slice: Option<DataSlice>,
+    min_context_slot: Option<Slot>,
+) -> anyhow::Result<solana_client::rpc_response::Response<Vec<Option<UiAccount>>>> {
     let account_info_config = RpcAccountInfoConfig {
         encoding: Some(UiAccountEncoding::Base64),
         commitment: Some(CommitmentConfig::finalized()),
-        data_slice: None,
-        min_context_slot: None,
+        data_slice: data_slice.map(Into::into),
+        min_context_slot,
     };
-
-    info!("requesting snapshot {:?}", ids);
-    let account_snapshot = rpc_client
-        .get_multiple_accounts(ids.clone(), Some(account_info_config))
+    let response = rpc_client
+        .get_multiple_accounts(ids.to_vec(), Some(account_info_config))
         .await
         .map_err_anyhow()?;
-    info!("snapshot received {:?}", ids);
+    if response.value.len() != ids.len() {
+        return Err(anyhow!(
+            "gma returned {} accounts for {} ids",
+            response.value.len(),
+            ids.len()
+        ));
+    }
+    Ok(response)
+}
+
+/// getMultipleAccounts for any number of accounts
+///
+/// The ids are requested in chunks. The first chunk decides the slot, the other chunks
+/// are pinned to at least that slot with `min_context_slot` and fetched concurrently.
+/// The result is reported at the oldest slot of the chunks, like merged gPA snapshots.
+pub async fn get_snapshot_gma(
+    rpc_http: RpcConnection,
+    ids: Vec<String>,
+    data_slice: Option<DataSlice>,
+) -> anyhow::Result<solana_client::rpc_response::Response<Vec<Option<UiAccount>>>> {
+    let rpc_client = rpc_http.connect_http::<AccountsDataClient>();
+
+    info!("requesting snapshot of {} accounts", ids.len());
+    let mut chunks = ids.chunks(GMA_CHUNK_SIZE);
+    let mut account_snapshot = get_multiple_accounts(
+        &rpc_client,
+        chunks.next().unwrap_or_default(),
+        data_slice,
+        None,
+    )
+    .await?;
+    let first_slot = account_snapshot.context.slot;
+    let responses: Vec<_> = stream::iter(chunks)
+        .map(|chunk| get_multiple_accounts(&rpc_client, chunk, data_slice, Some(first_slot)))
+        .buffered(GMA_MAX_CONCURRENT_REQUESTS)
+        .try_collect()
+        .await?;
+    for response in responses {
+        account_snapshot.context.slot = account_snapshot.context.slot.min(response.context.slot);
+        account_snapshot.value.extend(response.value);
+    }
+    info!(
+        "snapshot received of {} accounts for slot {}",
+        ids.len(),
+        account_snapshot.context.slot
+    );
     Ok(account_snapshot)
 }
 
 pub async fn get_snapshot(
-    rpc_http_url: String,
+    rpc_http: RpcConnection,
     filter_config: &FilterConfig,
 ) -> anyhow::Result<(Slot, Vec<(String, Option<UiAccount>)>)> {
     if !filter_config.account_ids.is_empty() {
-        let response =
-            get_snapshot_gma(rpc_http_url.clone(), filter_config.account_ids.clone()).await;
+        let response = get_snapshot_gma(
+            rpc_http.clone(),
+            filter_config.account_ids.clone(),
+            gma_data_slice(filter_config),
+        )
+        .await;
         if let Ok(snapshot) = response {
             let accounts: Vec<(String, Option<UiAccount>)> = filter_config
                 .account_ids
@@ -78,27 +225,137 @@ pub async fn get_snapshot(
                 .zip(snapshot.value)
                 .map(|x| (x.0.clone(), x.1))
                 .collect();
-            Ok((snapshot.context.slot, accounts))
+            Ok((
+                snapshot.context.slot,
+                filter_gma_accounts(filter_config, accounts),
+            ))
         } else {
             Err(anyhow!("invalid gma response {:?}", response))
         }
     } else if !filter_config.program_ids.is_empty() {
-        let response =
-            get_snapshot_gpa(rpc_http_url.clone(), filter_config.program_ids[0].clone()).await;
-        if let Ok(OptionalContext::Context(snapshot)) = response {
-            let accounts: Vec<(String, Option<UiAccount>)> = snapshot
-                .value
-                .iter()
-                .map(|x| {
-                    let deref = x.clone();
-                    (deref.pubkey, Some(deref.account))
-                })
-                .collect();
-            Ok((snapshot.context.slot, accounts))
-        } else {
-            Err(anyhow!("invalid gpa response {:?}", response))
+        let program_ids: HashSet<&String> = filter_config.program_ids.iter().collect();
+        let responses = join_all(program_ids.into_iter().map(|program_id| {
+            get_snapshot_gpa(
+                rpc_http.clone(),
+                program_id.clone(),
+                filter_config.account_filters.clone(),
+                filter_config.data_slice,
+            )
+        }))
+        .await;
+
+        // merge the per-program snapshots, using the oldest slot among them
+        let mut slot = Slot::MAX;
+        let mut accounts: Vec<(String, Option<UiAccount>)> = Vec::new();
+        for response in responses {
+            if let Ok(OptionalContext::Context(snapshot)) = response {
+                slot = slot.min(snapshot.context.slot);
+                accounts.extend(
+                    snapshot
+                        .value
+                        .into_iter()
+                        .map(|x| (x.pubkey, Some(x.account))),
+                );
+            } else {
+                return Err(anyhow!("invalid gpa response {:?}", response));
+            }
         }
+        Ok((slot, accounts))
     } else {
         Err(anyhow!("invalid filter_config"))
     }
 }
+
+/// Parse program ids, skipping invalid ones
+pub(crate) fn program_pubkeys(program_ids: &[String]) -> HashSet<Pubkey> {
+    program_ids
+        .iter()
+        .filter_map(|id| Pubkey::from_str(id).ok())
+        .collect()
+}
+
+struct KnownAccount {
+    owner: Pubkey,
+    slot: Slot,
+    hash: u64,
+}
+
+/// Accounts that a source has reported as existing, with their owner and latest state
+///
+/// gPA snapshots only list existing accounts, an account of the snapshotted program that
+/// was reported before but is missing from a snapshot was closed in the meantime.
+#[derive(Default)]
+pub(crate) struct KnownAccounts {
+    accounts: HashMap<Pubkey, KnownAccount>,
+}
+
+impl KnownAccounts {
+    pub fn observe(&mut self, write: &AccountWrite) {
+        if write.is_deleted() {
+            self.accounts.remove(&write.pubkey);
+            return;
+        }
+        if let Some(known) = self.accounts.get(&write.pubkey) {
+            if known.slot > write.slot {
+                return;
+            }
+        }
+        self.accounts.insert(
+            write.pubkey,
+            KnownAccount {
+                owner: write.owner,
+                slot: write.slot,
+                hash: account_hash(
+                    write.lamports,
+                    write.owner.as_ref(),
+                    write.executable,
+                    &write.data,
+                ),
+            },
+        );
+    }
+
+    /// Whether a snapshot write disagrees with what was known about the account up to
+    /// the snapshot slot, meaning an earlier write was missed
+    pub fn is_correction(&self, write: &AccountWrite) -> bool {
+        let known = match self.accounts.get(&write.pubkey) {
+            Some(known) if known.slot <= write.slot => known,
+            _ => return false,
+        };
+        write.is_deleted()
+            || known.hash
+                != account_hash(
+                    write.lamports,
+                    write.owner.as_ref(),
+                    write.executable,
+                    &write.data,
+                )
+    }
+
+    /// Tombstones for known accounts of `program_ids` that are missing from a gPA
+    /// snapshot of these programs at `slot`
+    pub fn closed_since_snapshot(
+        &mut self,
+        program_ids: &HashSet<Pubkey>,
+        snapshot_pubkeys: &HashSet<Pubkey>,
+        slot: Slot,
+    ) -> Vec<AccountWrite> {
+        let closed: Vec<Pubkey> = self
+            .accounts
+            .iter()
+            .filter(|(pubkey, known)| {
+                program_ids.contains(&known.owner)
+                    && known.slot <= slot
+                    && !snapshot_pubkeys.contains(pubkey)
+            })
+            .map(|(pubkey, _)| *pubkey)
+            .collect();
+        closed
+            .into_iter()
+            .map(|pubkey| {
+                self.accounts.remove(&pubkey);
+                AccountWrite::tombstone(pubkey, slot, 0)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,453 @@
+//! Compare what several grpc sources report, to notice a source that fell behind or
+//! serves different data than the others.
+//!
+//! Account writes are compared by a hash of their contents per (slot, pubkey, write_version),
+//! which works because write versions are remapped to a per-slot numbering that agrees
+//! between nodes. Rooted slots are compared by which sources rooted them. Both are only
+//! checked once a slot is well beyond the newest rooted slot, to give slower sources a
+//! chance to catch up.
+//!
+//! A source that disagrees with the majority of the other sources counts a divergence.
+//! Quarantined sources are still watched, but left out of the comparisons.
+//! Without a majority, for example with only two sources, divergences are still counted
+//! but no source gets quarantined. The last source that isn't quarantined never is.
+
+use log::*;
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+use yellowstone_grpc_proto::prelude::{
+    subscribe_update::UpdateOneof, SubscribeUpdate, SubscribeUpdateSlotStatus,
+};
+
+use crate::{
+    metrics::{MetricBool, MetricType, MetricU64, Metrics},
+    ConsistencyCheckConfig, GrpcSourceConfig,
+};
+
+/// Number of slots beyond the newest rooted slot before a slot is checked
+const CHECK_DELAY_SLOTS: u64 = 40;
+
+struct SourceState {
+    name: String,
+    /// First rooted slot seen, the source can't be expected to report anything before
+    first_rooted_slot: Option<u64>,
+    newest_processed_slot: u64,
+    newest_rooted_slot: u64,
+    /// Disagreements with the majority since the last agreement
+    divergence_streak: u64,
+    quarantined_until: Option<Instant>,
+    metric_divergences: MetricU64,
+    metric_slot_lag: MetricU64,
+    metric_rooted_slot_lag: MetricU64,
+    metric_quarantined: MetricBool,
+}
+
+impl SourceState {
+    /// Whether the source is quarantined, without ending an expired quarantine
+    fn in_quarantine(&self, now: Instant) -> bool {
+        self.quarantined_until.map_or(false, |until| until > now)
+    }
+}
+
+pub(crate) struct ConsistencyChecker {
+    config: ConsistencyCheckConfig,
+    sources: Vec<SourceState>,
+    /// slot -> (pubkey, write_version) -> (source, data hash)
+    account_writes: BTreeMap<u64, HashMap<(Pubkey, u64), Vec<(usize, u64)>>>,
+    /// slot -> sources that rooted it
+    rooted_slots: BTreeMap<u64, Vec<usize>>,
+    newest_rooted_slot: u64,
+}
+
+pub(crate) fn account_hash(lamports: u64, owner: &[u8], executable: bool, data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    lamports.hash(&mut hasher);
+    owner.hash(&mut hasher);
+    executable.hash(&mut hasher);
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Value reported by more than half of the reports, if any
+fn majority<T: Copy + Eq + Hash>(reports: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts = HashMap::<T, usize>::new();
+    let mut total = 0;
+    for value in reports {
+        *counts.entry(value).or_default() += 1;
+        total += 1;
+    }
+    counts
+        .into_iter()
+        .find(|(_, count)| *count * 2 > total)
+        .map(|(value, _)| value)
+}
+
+impl ConsistencyChecker {
+    pub fn new(
+        config: ConsistencyCheckConfig,
+        grpc_sources: &[GrpcSourceConfig],
+        metrics_sender: &Metrics,
+    ) -> Self {
+        let sources = grpc_sources
+            .iter()
+            .map(|source| SourceState {
+                name: source.name.clone(),
+                first_rooted_slot: None,
+                newest_processed_slot: 0,
+                newest_rooted_slot: 0,
+                divergence_streak: 0,
+                quarantined_until: None,
+                metric_divergences: metrics_sender.register_u64(
+                    format!("grpc_source_{}_divergences", source.name),
+                    MetricType::Counter,
+                ),
+                metric_slot_lag: metrics_sender.register_u64(
+                    format!("grpc_source_{}_slot_lag", source.name),
+                    MetricType::Gauge,
+                ),
+                metric_rooted_slot_lag: metrics_sender.register_u64(
+                    format!("grpc_source_{}_rooted_slot_lag", source.name),
+                    MetricType::Gauge,
+                ),
+                metric_quarantined: metrics_sender
+                    .register_bool(format!("grpc_source_{}_quarantined", source.name)),
+            })
+            .collect();
+        Self {
+            config,
+            sources,
+            account_writes: BTreeMap::new(),
+            rooted_slots: BTreeMap::new(),
+            newest_rooted_slot: 0,
+        }
+    }
+
+    /// Whether updates from the source should currently be ignored
+    pub fn is_quarantined(&mut self, source: usize) -> bool {
+        let state = &mut self.sources[source];
+        match state.quarantined_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                info!("grpc source {} leaves quarantine", state.name);
+                state.quarantined_until = None;
+                state.divergence_streak = 0;
+                state.metric_quarantined.set(false);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record an update that was received from a source
+    pub fn observe(&mut self, source: usize, update: &SubscribeUpdate) {
+        match &update.update_oneof {
+            Some(UpdateOneof::Account(info)) => {
+                let write = match &info.account {
+                    Some(write) => write,
+                    None => return,
+                };
+                let pubkey = match Pubkey::try_from(write.pubkey.as_slice()) {
+                    Ok(pubkey) => pubkey,
+                    Err(_) => return,
+                };
+                if info.slot + CHECK_DELAY_SLOTS <= self.newest_rooted_slot {
+                    // too late, the slot was already checked
+                    return;
+                }
+                let hash =
+                    account_hash(write.lamports, &write.owner, write.executable, &write.data);
+                self.account_writes
+                    .entry(info.slot)
+                    .or_default()
+                    .entry((pubkey, write.write_version))
+                    .or_default()
+                    .push((source, hash));
+            }
+            Some(UpdateOneof::Slot(update)) => {
+                let state = &mut self.sources[source];
+                if update.status == SubscribeUpdateSlotStatus::Finalized as i32 {
+                    state.first_rooted_slot.get_or_insert(update.slot);
+                    state.newest_rooted_slot = state.newest_rooted_slot.max(update.slot);
+                    if update.slot + CHECK_DELAY_SLOTS > self.newest_rooted_slot {
+                        self.rooted_slots
+                            .entry(update.slot)
+                            .or_default()
+                            .push(source);
+                    }
+                    if update.slot > self.newest_rooted_slot {
+                        self.newest_rooted_slot = update.slot;
+                        self.check(update.slot.saturating_sub(CHECK_DELAY_SLOTS));
+                    }
+                } else {
+                    state.newest_processed_slot = state.newest_processed_slot.max(update.slot);
+                }
+                self.report_lag();
+            }
+            _ => {}
+        }
+    }
+
+    fn report_lag(&mut self) {
+        let newest_processed_slot = self
+            .sources
+            .iter()
+            .map(|s| s.newest_processed_slot)
+            .max()
+            .unwrap_or(0);
+        for state in self.sources.iter_mut() {
+            state
+                .metric_slot_lag
+                .set(newest_processed_slot - state.newest_processed_slot);
+            state
+                .metric_rooted_slot_lag
+                .set(self.newest_rooted_slot - state.newest_rooted_slot);
+        }
+    }
+
+    /// Compare the reports for all slots up to and including `up_to_slot`
+    fn check(&mut self, up_to_slot: u64) {
+        let now = Instant::now();
+        // (source, agrees with the majority, there is a majority)
+        let mut outcomes = Vec::<(usize, bool, bool)>::new();
+
+        let checked_slots = self.account_writes.split_off(&(up_to_slot + 1));
+        let checked_writes = std::mem::replace(&mut self.account_writes, checked_slots);
+        for (slot, writes) in checked_writes {
+            for ((pubkey, write_version), mut reports) in writes {
+                // quarantined sources keep reporting, but don't get a vote
+                reports.retain(|(source, _)| !self.sources[*source].in_quarantine(now));
+                if reports.len() < 2 {
+                    continue;
+                }
+                let expected = majority(reports.iter().map(|(_, hash)| *hash));
+                for (source, hash) in reports.iter() {
+                    let agrees = expected == Some(*hash);
+                    if !agrees {
+                        debug!(
+                            "grpc source {} disagrees on {} in slot {} write {}",
+                            self.sources[*source].name, pubkey, slot, write_version
+                        );
+                    }
+                    outcomes.push((*source, agrees, expected.is_some()));
+                }
+            }
+        }
+
+        let checked_slots = self.rooted_slots.split_off(&(up_to_slot + 1));
+        let checked_rooted = std::mem::replace(&mut self.rooted_slots, checked_slots);
+        for (slot, rooted_by) in checked_rooted {
+            // sources that were connected while the slot was rooted and have since moved beyond it
+            let expected_from: Vec<usize> = (0..self.sources.len())
+                .filter(|source| {
+                    let state = &self.sources[*source];
+                    !state.in_quarantine(now)
+                        && state.first_rooted_slot.map(|s| s < slot).unwrap_or(false)
+                        && state.newest_rooted_slot > slot
+                })
+                .collect();
+            if expected_from.len() < 2 {
+                continue;
+            }
+            let expected = majority(
+                expected_from
+                    .iter()
+                    .map(|source| rooted_by.contains(source)),
+            );
+            for source in expected_from {
+                let has_rooted = rooted_by.contains(&source);
+                let agrees = expected == Some(has_rooted);
+                if !agrees {
+                    debug!(
+                        "grpc source {} disagrees on slot {} being rooted",
+                        self.sources[source].name, slot
+                    );
+                }
+                outcomes.push((source, agrees, expected.is_some()));
+            }
+        }
+
+        for (source, agrees, has_majority) in outcomes {
+            let state = &mut self.sources[source];
+            if agrees {
+                state.divergence_streak = 0;
+                continue;
+            }
+            state.metric_divergences.increment();
+            if !has_majority {
+                continue;
+            }
+            state.divergence_streak += 1;
+
+            let quarantine = match &self.config.quarantine {
+                Some(quarantine) => quarantine,
+                None => continue,
+            };
+            if state.quarantined_until.is_some()
+                || state.divergence_streak < quarantine.after_divergences
+            {
+                continue;
+            }
+
+            let others_in_use = self
+                .sources
+                .iter()
+                .enumerate()
+                .any(|(other, state)| other != source && !state.in_quarantine(now));
+            let state = &mut self.sources[source];
+            if !others_in_use {
+                warn!(
+                    "grpc source {} disagreed with the other sources {} times in a row, not quarantining the last source in use",
+                    state.name, state.divergence_streak
+                );
+                continue;
+            }
+            warn!(
+                "grpc source {} disagreed with the other sources {} times in a row, quarantining it for {}s",
+                state.name, state.divergence_streak, quarantine.duration_secs
+            );
+            state.quarantined_until = Some(now + Duration::from_secs(quarantine.duration_secs));
+            state.metric_quarantined.set(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metrics, MetricsConfig, QuarantineConfig};
+    use yellowstone_grpc_proto::prelude::{
+        SubscribeUpdateAccount, SubscribeUpdateAccountInfo, SubscribeUpdateSlot,
+    };
+
+    fn checker(sources: usize, quarantine_secs: u64) -> ConsistencyChecker {
+        let grpc_sources: Vec<GrpcSourceConfig> = (0..sources)
+            .map(|i| GrpcSourceConfig {
+                name: format!("source{}", i),
+                connection_string: String::new(),
+                token: None,
+                retry_connection_sleep_secs: 0,
+                tls: None,
+                compression: None,
+                account_data_compression: None,
+            })
+            .collect();
+        let config = ConsistencyCheckConfig {
+            quarantine: Some(QuarantineConfig {
+                after_divergences: 1,
+                duration_secs: quarantine_secs,
+            }),
+        };
+        let metrics_sender = metrics::start(
+            MetricsConfig {
+                output_stdout: false,
+                output_http: false,
+            },
+            "test".into(),
+        );
+        ConsistencyChecker::new(config, &grpc_sources, &metrics_sender)
+    }
+
+    fn update(update: UpdateOneof) -> SubscribeUpdate {
+        SubscribeUpdate {
+            filters: vec![],
+            update_oneof: Some(update),
+        }
+    }
+
+    /// Every source reports a write in `slot` with its data, then the slot is checked
+    fn check_round(checker: &mut ConsistencyChecker, slot: u64, data: &[u8]) {
+        let pubkey = Pubkey::new_unique();
+        for (source, data) in data.iter().enumerate() {
+            let write = SubscribeUpdateAccount {
+                account: Some(SubscribeUpdateAccountInfo {
+                    pubkey: pubkey.to_bytes().to_vec(),
+                    lamports: 1,
+                    owner: Pubkey::default().to_bytes().to_vec(),
+                    data: vec![*data],
+                    write_version: 1,
+                    ..Default::default()
+                }),
+                slot,
+                is_startup: false,
+            };
+            checker.observe(source, &update(UpdateOneof::Account(write)));
+        }
+        let rooted = SubscribeUpdateSlot {
+            slot: slot + CHECK_DELAY_SLOTS,
+            parent: None,
+            status: SubscribeUpdateSlotStatus::Finalized as i32,
+        };
+        checker.observe(0, &update(UpdateOneof::Slot(rooted)));
+    }
+
+    fn quarantined(checker: &ConsistencyChecker) -> Vec<bool> {
+        checker
+            .sources
+            .iter()
+            .map(|s| s.quarantined_until.is_some())
+            .collect()
+    }
+
+    #[test]
+    fn majority_needs_more_than_half() {
+        assert_eq!(majority([1, 1, 2].into_iter()), Some(1));
+        assert_eq!(majority([1, 2].into_iter()), None);
+        assert_eq!(majority([1, 1, 2, 2].into_iter()), None);
+        assert_eq!(majority([3].into_iter()), Some(3));
+        assert_eq!(majority(std::iter::empty::<u64>()), None);
+    }
+
+    #[tokio::test]
+    async fn the_source_that_disagrees_with_the_majority_is_quarantined() {
+        let mut checker = checker(3, 3600);
+        check_round(&mut checker, 100, &[1, 1, 2]);
+        assert_eq!(quarantined(&checker), vec![false, false, true]);
+        assert_eq!(checker.sources[2].metric_divergences.value(), 1);
+        assert!(checker.is_quarantined(2));
+        assert!(!checker.is_quarantined(0));
+    }
+
+    #[tokio::test]
+    async fn ties_count_divergences_but_quarantine_nobody() {
+        let mut two = checker(2, 3600);
+        check_round(&mut two, 100, &[1, 2]);
+        assert_eq!(quarantined(&two), vec![false, false]);
+        assert_eq!(two.sources[0].metric_divergences.value(), 1);
+        assert_eq!(two.sources[1].metric_divergences.value(), 1);
+
+        let mut four = checker(4, 3600);
+        check_round(&mut four, 100, &[1, 1, 2, 2]);
+        assert_eq!(quarantined(&four), vec![false; 4]);
+    }
+
+    #[tokio::test]
+    async fn quarantined_sources_dont_vote() {
+        let mut checker = checker(3, 3600);
+        check_round(&mut checker, 100, &[1, 1, 2]);
+        assert_eq!(quarantined(&checker), vec![false, false, true]);
+
+        // the quarantined source agrees with source 0, but that is no majority against
+        // source 1
+        check_round(&mut checker, 200, &[2, 1, 2]);
+        assert_eq!(quarantined(&checker), vec![false, false, true]);
+        assert_eq!(checker.sources[0].metric_divergences.value(), 1);
+        assert_eq!(checker.sources[1].metric_divergences.value(), 1);
+        // and its own reports aren't compared
+        assert_eq!(checker.sources[2].metric_divergences.value(), 1);
+    }
+
+    #[tokio::test]
+    async fn quarantine_ends_after_its_duration() {
+        let mut checker = checker(3, 0);
+        check_round(&mut checker, 100, &[1, 1, 2]);
+        assert_eq!(quarantined(&checker), vec![false, false, true]);
+
+        assert!(!checker.is_quarantined(2));
+        assert_eq!(quarantined(&checker), vec![false, false, false]);
+        assert_eq!(checker.sources[2].divergence_streak, 0);
+    }
+}
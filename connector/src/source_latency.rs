@@ -0,0 +1,304 @@
+//! Track which grpc source delivers an update first and how far behind the others are.
+//!
+//! Every account write and slot update is timed on arrival. The first source to deliver
+//! it counts a first delivery, every later source counts its delay against the first one.
+//! A source that delivers the same update again doesn't count it a second time.
+//! Delays are exported as a sum and as cumulative buckets, per source and for account
+//! writes and each slot commitment level separately.
+
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use yellowstone_grpc_proto::prelude::SubscribeUpdateSlotStatus;
+
+use crate::{
+    metrics::{MetricType, MetricU64, Metrics},
+    GrpcSourceConfig,
+};
+
+/// Upper bounds of the delay buckets, in milliseconds
+const DELAY_BUCKETS_MS: [u64; 7] = [1, 5, 10, 50, 100, 500, 1000];
+
+/// Number of slots before the newest one to keep arrival times for
+const RETENTION_SLOTS: u64 = 50;
+
+struct DelayMetrics {
+    first_deliveries: MetricU64,
+    late_deliveries: MetricU64,
+    delay_micros_total: MetricU64,
+    buckets: Vec<(Duration, MetricU64)>,
+}
+
+impl DelayMetrics {
+    fn new(metrics_sender: &Metrics, prefix: &str) -> Self {
+        Self {
+            first_deliveries: metrics_sender
+                .register_u64(format!("{}_first_deliveries", prefix), MetricType::Counter),
+            late_deliveries: metrics_sender
+                .register_u64(format!("{}_late_deliveries", prefix), MetricType::Counter),
+            delay_micros_total: metrics_sender.register_u64(
+                format!("{}_delay_micros_total", prefix),
+                MetricType::Counter,
+            ),
+            buckets: DELAY_BUCKETS_MS
+                .iter()
+                .map(|ms| {
+                    (
+                        Duration::from_millis(*ms),
+                        metrics_sender.register_u64(
+                            format!("{}_delay_le_{}ms", prefix, ms),
+                            MetricType::Counter,
+                        ),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn record(&mut self, delivery: Delivery) {
+        let delay = match delivery {
+            Delivery::First => {
+                self.first_deliveries.increment();
+                return;
+            }
+            Delivery::Late(delay) => delay,
+            Delivery::Repeated => return,
+        };
+        self.late_deliveries.increment();
+        self.delay_micros_total.add(delay.as_micros() as u64);
+        for (bound, metric) in self.buckets.iter_mut() {
+            if delay <= *bound {
+                metric.increment();
+            }
+        }
+    }
+}
+
+enum Delivery {
+    First,
+    /// Delay against the first delivery
+    Late(Duration),
+    /// The source delivered the update before
+    Repeated,
+}
+
+/// When an update first arrived, and the sources that delivered it so far
+struct Arrival {
+    first_received_at: Instant,
+    sources: HashSet<usize>,
+}
+
+impl Arrival {
+    fn new(received_at: Instant) -> Self {
+        Self {
+            first_received_at: received_at,
+            sources: HashSet::new(),
+        }
+    }
+
+    fn deliver(&mut self, source: usize, received_at: Instant) -> Delivery {
+        let first = self.sources.is_empty();
+        if !self.sources.insert(source) {
+            Delivery::Repeated
+        } else if first {
+            Delivery::First
+        } else {
+            Delivery::Late(received_at.saturating_duration_since(self.first_received_at))
+        }
+    }
+}
+
+struct SourceMetrics {
+    account_writes: DelayMetrics,
+    processed_slots: DelayMetrics,
+    confirmed_slots: DelayMetrics,
+    rooted_slots: DelayMetrics,
+}
+
+pub(crate) struct DeliveryTracker {
+    sources: Vec<SourceMetrics>,
+    /// slot -> (pubkey, write_version) -> arrival of the write
+    write_arrivals: HashMap<u64, HashMap<(Pubkey, u64), Arrival>>,
+    /// (slot, grpc slot status) -> arrival of the slot update
+    slot_arrivals: HashMap<(u64, i32), Arrival>,
+    newest_slot: u64,
+}
+
+impl DeliveryTracker {
+    pub fn new(grpc_sources: &[GrpcSourceConfig], metrics_sender: &Metrics) -> Self {
+        let sources = grpc_sources
+            .iter()
+            .map(|source| {
+                let prefix = format!("grpc_source_{}", source.name);
+                SourceMetrics {
+                    account_writes: DelayMetrics::new(
+                        metrics_sender,
+                        &format!("{}_account_write", prefix),
+                    ),
+                    processed_slots: DelayMetrics::new(
+                        metrics_sender,
+                        &format!("{}_processed_slot", prefix),
+                    ),
+                    confirmed_slots: DelayMetrics::new(
+                        metrics_sender,
+                        &format!("{}_confirmed_slot", prefix),
+                    ),
+                    rooted_slots: DelayMetrics::new(
+                        metrics_sender,
+                        &format!("{}_rooted_slot", prefix),
+                    ),
+                }
+            })
+            .collect();
+        Self {
+            sources,
+            write_arrivals: HashMap::new(),
+            slot_arrivals: HashMap::new(),
+            newest_slot: 0,
+        }
+    }
+
+    /// Record an account write, `write_version` being the remapped one that agrees
+    /// between sources
+    pub fn record_account_write(
+        &mut self,
+        source: usize,
+        received_at: Instant,
+        slot: u64,
+        pubkey: Pubkey,
+        write_version: u64,
+    ) {
+        if slot + RETENTION_SLOTS < self.newest_slot {
+            return;
+        }
+        let delivery = self
+            .write_arrivals
+            .entry(slot)
+            .or_default()
+            .entry((pubkey, write_version))
+            .or_insert_with(|| Arrival::new(received_at))
+            .deliver(source, received_at);
+        self.sources[source].account_writes.record(delivery);
+    }
+
+    pub fn record_slot(&mut self, source: usize, received_at: Instant, slot: u64, status: i32) {
+        if slot + RETENTION_SLOTS < self.newest_slot {
+            return;
+        }
+        let delivery = self
+            .slot_arrivals
+            .entry((slot, status))
+            .or_insert_with(|| Arrival::new(received_at))
+            .deliver(source, received_at);
+        let source_metrics = &mut self.sources[source];
+        match SubscribeUpdateSlotStatus::from_i32(status) {
+            Some(SubscribeUpdateSlotStatus::Processed) => {
+                source_metrics.processed_slots.record(delivery)
+            }
+            Some(SubscribeUpdateSlotStatus::Confirmed) => {
+                source_metrics.confirmed_slots.record(delivery)
+            }
+            Some(SubscribeUpdateSlotStatus::Finalized) => {
+                source_metrics.rooted_slots.record(delivery)
+            }
+            None => {}
+        }
+
+        if slot > self.newest_slot {
+            self.newest_slot = slot;
+            let oldest_slot = slot.saturating_sub(RETENTION_SLOTS);
+            self.write_arrivals.retain(|&s, _| s >= oldest_slot);
+            self.slot_arrivals.retain(|&(s, _), _| s >= oldest_slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metrics, MetricsConfig};
+
+    fn tracker(sources: usize) -> DeliveryTracker {
+        let grpc_sources: Vec<GrpcSourceConfig> = (0..sources)
+            .map(|i| GrpcSourceConfig {
+                name: format!("source{}", i),
+                connection_string: String::new(),
+                token: None,
+                retry_connection_sleep_secs: 0,
+                tls: None,
+                compression: None,
+                account_data_compression: None,
+            })
+            .collect();
+        let metrics_sender = metrics::start(
+            MetricsConfig {
+                output_stdout: false,
+                output_http: false,
+            },
+            "test".into(),
+        );
+        DeliveryTracker::new(&grpc_sources, &metrics_sender)
+    }
+
+    fn counts(metrics: &DelayMetrics) -> (u64, u64, u64) {
+        (
+            metrics.first_deliveries.value(),
+            metrics.late_deliveries.value(),
+            metrics.delay_micros_total.value(),
+        )
+    }
+
+    #[tokio::test]
+    async fn later_sources_count_their_delay() {
+        let mut tracker = tracker(2);
+        let pubkey = Pubkey::new_unique();
+        let start = Instant::now();
+        tracker.record_account_write(0, start, 100, pubkey, 1);
+        tracker.record_account_write(1, start + Duration::from_millis(7), 100, pubkey, 1);
+
+        assert_eq!(counts(&tracker.sources[0].account_writes), (1, 0, 0));
+        assert_eq!(counts(&tracker.sources[1].account_writes), (0, 1, 7000));
+        let buckets: Vec<u64> = tracker.sources[1]
+            .account_writes
+            .buckets
+            .iter()
+            .map(|(_, metric)| metric.value())
+            .collect();
+        assert_eq!(buckets, vec![0, 0, 1, 1, 1, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn repeated_deliveries_are_counted_once() {
+        let mut tracker = tracker(2);
+        let pubkey = Pubkey::new_unique();
+        let start = Instant::now();
+        tracker.record_account_write(0, start, 100, pubkey, 1);
+        tracker.record_account_write(0, start + Duration::from_millis(1), 100, pubkey, 1);
+        tracker.record_account_write(1, start + Duration::from_millis(2), 100, pubkey, 1);
+        tracker.record_account_write(1, start + Duration::from_millis(3), 100, pubkey, 1);
+        assert_eq!(counts(&tracker.sources[0].account_writes), (1, 0, 0));
+        assert_eq!(counts(&tracker.sources[1].account_writes), (0, 1, 2000));
+
+        let processed = SubscribeUpdateSlotStatus::Processed as i32;
+        tracker.record_slot(1, start, 100, processed);
+        tracker.record_slot(1, start + Duration::from_millis(1), 100, processed);
+        tracker.record_slot(0, start + Duration::from_millis(4), 100, processed);
+        assert_eq!(counts(&tracker.sources[1].processed_slots), (1, 0, 0));
+        assert_eq!(counts(&tracker.sources[0].processed_slots), (0, 1, 4000));
+    }
+
+    #[tokio::test]
+    async fn slot_statuses_are_tracked_separately() {
+        let mut tracker = tracker(1);
+        let start = Instant::now();
+        tracker.record_slot(0, start, 100, SubscribeUpdateSlotStatus::Processed as i32);
+        tracker.record_slot(0, start, 100, SubscribeUpdateSlotStatus::Confirmed as i32);
+        tracker.record_slot(0, start, 100, SubscribeUpdateSlotStatus::Finalized as i32);
+        let source = &tracker.sources[0];
+        assert_eq!(counts(&source.processed_slots), (1, 0, 0));
+        assert_eq!(counts(&source.confirmed_slots), (1, 0, 0));
+        assert_eq!(counts(&source.rooted_slots), (1, 0, 0));
+    }
+}
@@ -0,0 +1,541 @@
+//! Local stand-ins for a geyser plugin and a JSON-RPC node, to drive the sources
+//! in tests without network access.
+//!
+//! `FakeGeyser` serves the Yellowstone `Geyser` service. Every subscription that a
+//! source opens shows up as a `FakeSubscription`, which the test uses to push slot,
+//! account and ping updates, and to await requests that the source sends later on the
+//! same stream. Dropping it closes the stream.
+//!
+//! `RpcStub` answers `getProgramAccounts` and `getMultipleAccounts` from accounts the
+//! test registered, at a slot the test controls. Data slices and the memcmp and dataSize
+//! filters of gPA are honored. Like a real node, the stub rejects requests with a
+//! `minContextSlot` above its slot, and requests without the header set by
+//! `require_header`.
+//!
+//! `PubsubStub` accepts websocket connections and answers every subscription request,
+//! without sending notifications. Every connection shows up as a `PubsubConnection`,
+//! dropping it closes the connection.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{SinkExt, Stream, StreamExt};
+use serde_json::{json, Value};
+use solana_account_decoder::{UiAccount, UiAccountEncoding, UiDataSliceConfig};
+use solana_client::rpc_filter::RpcFilterType;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+use tokio_stream::wrappers::TcpListenerStream;
+use warp::{
+    http::{HeaderMap, StatusCode},
+    ws::{Message, WebSocket, Ws},
+    Filter,
+};
+use yellowstone_grpc_proto::{
+    prelude::{
+        geyser_server::{Geyser, GeyserServer},
+        subscribe_update::UpdateOneof,
+        SubscribeRequest, SubscribeUpdate, SubscribeUpdateAccount, SubscribeUpdateAccountInfo,
+        SubscribeUpdatePing, SubscribeUpdateSlot, SubscribeUpdateSlotStatus,
+    },
+    tonic::{transport::Server, Request, Response, Status, Streaming},
+};
+
+use crate::{
+    metrics, rpc_connection::RpcConnection, GrpcSourceConfig, MetricsConfig, QueuePolicy,
+    RpcConnectionConfig, SnapshotSourceConfig, SourceConfig,
+};
+
+/// A subscription opened against the `FakeGeyser`
+pub struct FakeSubscription {
+    /// The request the client subscribed with
+    pub request: SubscribeRequest,
+    sender: async_channel::Sender<Result<SubscribeUpdate, Status>>,
+    /// Requests the client sent later on the same stream
+    later_requests: async_channel::Receiver<SubscribeRequest>,
+}
+
+impl FakeSubscription {
+    pub async fn send(&self, update: UpdateOneof) {
+        self.sender
+            .send(Ok(SubscribeUpdate {
+                filters: vec!["client".to_owned()],
+                update_oneof: Some(update),
+            }))
+            .await
+            .expect("subscription is open");
+    }
+
+    pub async fn send_slot(
+        &self,
+        slot: u64,
+        parent: Option<u64>,
+        status: SubscribeUpdateSlotStatus,
+    ) {
+        self.send(slot_update(slot, parent, status)).await;
+    }
+
+    pub async fn send_account(&self, slot: u64, write: SubscribeUpdateAccountInfo) {
+        self.send(account_update(slot, write)).await;
+    }
+
+    pub async fn send_ping(&self) {
+        self.send(UpdateOneof::Ping(SubscribeUpdatePing {})).await;
+    }
+
+    /// Wait for the client to send another request on the stream
+    pub async fn next_request(&self) -> SubscribeRequest {
+        tokio::time::timeout(Duration::from_secs(10), self.later_requests.recv())
+            .await
+            .expect("client sent a request in time")
+            .expect("request stream is open")
+    }
+
+    /// Whether the client went away
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+struct FakeGeyserService {
+    subscription_sender: async_channel::Sender<FakeSubscription>,
+}
+
+#[async_trait]
+impl Geyser for FakeGeyserService {
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<SubscribeUpdate, Status>> + Send + 'static>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<Streaming<SubscribeRequest>>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let mut requests = request.into_inner();
+        let request = requests
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("missing subscribe request"))?;
+        let (later_request_sender, later_requests) = async_channel::unbounded();
+        tokio::spawn(async move {
+            while let Ok(Some(request)) = requests.message().await {
+                if later_request_sender.send(request).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let (sender, receiver) = async_channel::unbounded();
+        self.subscription_sender
+            .send(FakeSubscription {
+                request,
+                sender,
+                later_requests,
+            })
+            .await
+            .map_err(|_| Status::unavailable("fake geyser was dropped"))?;
+        Ok(Response::new(Box::pin(receiver)))
+    }
+}
+
+/// A geyser grpc server on a local port
+pub struct FakeGeyser {
+    pub addr: SocketAddr,
+    subscription_receiver: async_channel::Receiver<FakeSubscription>,
+}
+
+impl FakeGeyser {
+    pub async fn start() -> FakeGeyser {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind fake geyser");
+        let addr = listener.local_addr().unwrap();
+        let (subscription_sender, subscription_receiver) = async_channel::unbounded();
+        let service = FakeGeyserService {
+            subscription_sender,
+        };
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(GeyserServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .expect("fake geyser server");
+        });
+        FakeGeyser {
+            addr,
+            subscription_receiver,
+        }
+    }
+
+    pub fn connection_string(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Wait for the next client subscription
+    pub async fn next_subscription(&self) -> FakeSubscription {
+        tokio::time::timeout(Duration::from_secs(10), self.subscription_receiver.recv())
+            .await
+            .expect("client subscribed in time")
+            .expect("fake geyser is running")
+    }
+}
+
+#[derive(Default)]
+struct RpcStubState {
+    slot: u64,
+    /// Slots for the next requests, one each, before `slot` applies again
+    queued_slots: VecDeque<u64>,
+    accounts: HashMap<Pubkey, Account>,
+    requests: HashMap<String, usize>,
+    required_header: Option<(String, String)>,
+}
+
+impl RpcStubState {
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        match &self.required_header {
+            Some((name, value)) => headers
+                .get(name.as_str())
+                .map_or(false, |header| header == value.as_str()),
+            None => true,
+        }
+    }
+
+    fn handle(&mut self, request: &Value) -> Value {
+        let method = request["method"].as_str().unwrap_or_default().to_owned();
+        *self.requests.entry(method.clone()).or_default() += 1;
+        let params = &request["params"];
+        let slot = self.queued_slots.pop_front().unwrap_or(self.slot);
+        if let Some(min_context_slot) = params[1]["minContextSlot"].as_u64() {
+            if min_context_slot > slot {
+                return json!({
+                    "jsonrpc": "2.0",
+                    "error": {
+                        "code": -32016,
+                        "message": "Minimum context slot has not been reached",
+                        "data": { "contextSlot": slot },
+                    },
+                    "id": request["id"],
+                });
+            }
+        }
+        let context = json!({ "slot": slot });
+        let data_slice: Option<UiDataSliceConfig> =
+            serde_json::from_value(params[1]["dataSlice"].clone()).unwrap_or_default();
+        let result = match method.as_str() {
+            "getProgramAccounts" => {
+                let program_id: Pubkey = params[0].as_str().unwrap_or_default().parse().unwrap();
+                let filters: Vec<RpcFilterType> =
+                    serde_json::from_value(params[1]["filters"].clone()).unwrap_or_default();
+                let value: Vec<Value> = self
+                    .accounts
+                    .iter()
+                    .filter(|(_, account)| account.owner == program_id)
+                    .filter(|(_, account)| {
+                        filters.iter().all(|filter| match filter {
+                            RpcFilterType::DataSize(size) => account.data.len() as u64 == *size,
+                            RpcFilterType::Memcmp(memcmp) => memcmp.bytes_match(&account.data),
+                            _ => true,
+                        })
+                    })
+                    .map(|(pubkey, account)| {
+                        json!({
+                            "pubkey": pubkey.to_string(),
+                            "account": encode_account(pubkey, account, data_slice),
+                        })
+                    })
+                    .collect();
+                json!({ "context": context, "value": value })
+            }
+            "getMultipleAccounts" => {
+                let value: Vec<Value> = params[0]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|id| {
+                        let pubkey: Pubkey = id.as_str().unwrap_or_default().parse().unwrap();
+                        match self.accounts.get(&pubkey) {
+                            Some(account) => {
+                                json!(encode_account(&pubkey, account, data_slice))
+                            }
+                            None => Value::Null,
+                        }
+                    })
+                    .collect();
+                json!({ "context": context, "value": value })
+            }
+            _ => {
+                return json!({
+                    "jsonrpc": "2.0",
+                    "error": { "code": -32601, "message": "Method not found" },
+                    "id": request["id"],
+                })
+            }
+        };
+        json!({ "jsonrpc": "2.0", "result": result, "id": request["id"] })
+    }
+}
+
+fn encode_account(
+    pubkey: &Pubkey,
+    account: &Account,
+    data_slice: Option<UiDataSliceConfig>,
+) -> UiAccount {
+    UiAccount::encode(pubkey, account, UiAccountEncoding::Base64, None, data_slice)
+}
+
+/// A JSON-RPC server on a local port that serves account snapshots
+#[derive(Clone)]
+pub struct RpcStub {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<RpcStubState>>,
+}
+
+impl RpcStub {
+    pub fn start() -> RpcStub {
+        let state = Arc::new(Mutex::new(RpcStubState::default()));
+        let route_state = state.clone();
+        let route = warp::post()
+            .and(warp::header::headers_cloned())
+            .and(warp::body::json())
+            .map(move |headers: HeaderMap, request: Value| {
+                let mut state = route_state.lock().unwrap();
+                if !state.is_authorized(&headers) {
+                    return warp::reply::with_status(
+                        warp::reply::json(&json!({})),
+                        StatusCode::UNAUTHORIZED,
+                    );
+                }
+                let response = state.handle(&request);
+                warp::reply::with_status(warp::reply::json(&response), StatusCode::OK)
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        RpcStub { addr, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// A connection to the stub without headers
+    pub fn connection(&self) -> RpcConnection {
+        RpcConnection::new(&self.url(), &RpcConnectionConfig::default()).expect("valid rpc url")
+    }
+
+    /// Reject requests that don't send this header
+    pub fn require_header(&self, name: &str, value: &str) {
+        self.state.lock().unwrap().required_header = Some((name.to_owned(), value.to_owned()));
+    }
+
+    /// The context slot that snapshots are reported for
+    pub fn set_slot(&self, slot: u64) {
+        self.state.lock().unwrap().slot = slot;
+    }
+
+    /// Answer the next requests at these slots, one each, like a load balancer that
+    /// hands requests to nodes at different slots
+    pub fn queue_slots(&self, slots: &[u64]) {
+        self.state
+            .lock()
+            .unwrap()
+            .queued_slots
+            .extend(slots.iter().copied());
+    }
+
+    pub fn set_account(&self, pubkey: Pubkey, account: Account) {
+        self.state.lock().unwrap().accounts.insert(pubkey, account);
+    }
+
+    /// Number of requests received for a JSON-RPC method
+    pub fn request_count(&self, method: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .get(method)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// A websocket connection opened against the `PubsubStub`
+pub struct PubsubConnection {
+    /// Methods of the subscriptions the client opened
+    subscriptions: async_channel::Receiver<String>,
+    _close: tokio::sync::oneshot::Sender<()>,
+}
+
+impl PubsubConnection {
+    /// Wait for the client to subscribe, returns the subscription method
+    pub async fn next_subscription(&self) -> String {
+        tokio::time::timeout(Duration::from_secs(10), self.subscriptions.recv())
+            .await
+            .expect("client subscribed in time")
+            .expect("connection is open")
+    }
+}
+
+async fn serve_pubsub(
+    mut socket: WebSocket,
+    connection_sender: async_channel::Sender<PubsubConnection>,
+) {
+    let (subscription_sender, subscriptions) = async_channel::unbounded();
+    let (close, mut closed) = tokio::sync::oneshot::channel();
+    let connection = PubsubConnection {
+        subscriptions,
+        _close: close,
+    };
+    if connection_sender.send(connection).await.is_err() {
+        return;
+    }
+    let mut next_subscription_id = 0;
+    loop {
+        let message = tokio::select! {
+            message = socket.next() => message,
+            _ = &mut closed => break,
+        };
+        let request: Value = match message {
+            Some(Ok(message)) => match message.to_str().map(serde_json::from_str) {
+                Ok(Ok(request)) => request,
+                _ => continue,
+            },
+            _ => break,
+        };
+        let method = request["method"].as_str().unwrap_or_default().to_owned();
+        let result = if method.ends_with("Unsubscribe") {
+            json!(true)
+        } else {
+            next_subscription_id += 1;
+            let _ = subscription_sender.send(method).await;
+            json!(next_subscription_id)
+        };
+        let response = json!({ "jsonrpc": "2.0", "result": result, "id": request["id"] });
+        if socket
+            .send(Message::text(response.to_string()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    let _ = socket.close().await;
+}
+
+/// A websocket JSON-RPC server on a local port that accepts subscriptions
+pub struct PubsubStub {
+    pub addr: SocketAddr,
+    connection_receiver: async_channel::Receiver<PubsubConnection>,
+}
+
+impl PubsubStub {
+    pub fn start() -> PubsubStub {
+        let (connection_sender, connection_receiver) = async_channel::unbounded();
+        let route = warp::ws().map(move |ws: Ws| {
+            let connection_sender = connection_sender.clone();
+            ws.on_upgrade(move |socket| serve_pubsub(socket, connection_sender))
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        PubsubStub {
+            addr,
+            connection_receiver,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// Wait for the next client connection
+    pub async fn next_connection(&self) -> PubsubConnection {
+        tokio::time::timeout(Duration::from_secs(10), self.connection_receiver.recv())
+            .await
+            .expect("client connected in time")
+            .expect("pubsub stub is running")
+    }
+}
+
+pub fn slot_update(
+    slot: u64,
+    parent: Option<u64>,
+    status: SubscribeUpdateSlotStatus,
+) -> UpdateOneof {
+    UpdateOneof::Slot(SubscribeUpdateSlot {
+        slot,
+        parent,
+        status: status as i32,
+    })
+}
+
+pub fn account_update(slot: u64, write: SubscribeUpdateAccountInfo) -> UpdateOneof {
+    UpdateOneof::Account(SubscribeUpdateAccount {
+        account: Some(write),
+        slot,
+        is_startup: false,
+    })
+}
+
+/// An account write as the geyser plugin would send it, `write_version` being the
+/// node's global write version
+pub fn account_info(
+    pubkey: &Pubkey,
+    owner: &Pubkey,
+    write_version: u64,
+    data: Vec<u8>,
+) -> SubscribeUpdateAccountInfo {
+    SubscribeUpdateAccountInfo {
+        pubkey: pubkey.to_bytes().to_vec(),
+        lamports: 1,
+        owner: owner.to_bytes().to_vec(),
+        data,
+        write_version,
+        ..Default::default()
+    }
+}
+
+/// A source config that connects to the given fake geysers and takes snapshots from `rpc`
+pub fn source_config(geysers: &[&FakeGeyser], rpc: &RpcStub) -> SourceConfig {
+    SourceConfig {
+        dedup_queue_size: 1000,
+        grpc_sources: geysers
+            .iter()
+            .enumerate()
+            .map(|(i, geyser)| GrpcSourceConfig {
+                name: format!("fake{}", i),
+                connection_string: geyser.connection_string(),
+                token: None,
+                retry_connection_sleep_secs: 0,
+                tls: None,
+                compression: None,
+                account_data_compression: None,
+            })
+            .collect(),
+        snapshot: SnapshotSourceConfig {
+            rpc_http_url: rpc.url(),
+            rpc_http_connection: RpcConnectionConfig::default(),
+            resnapshot_interval_secs: None,
+        },
+        rpc_ws_url: String::new(),
+        rpc_ws_connection: RpcConnectionConfig::default(),
+        recording: None,
+        replay: None,
+        consistency_check: None,
+        queue_policy: QueuePolicy::Unbounded,
+        history_retention: None,
+    }
+}
+
+/// Metrics that aren't exported anywhere
+pub fn metrics() -> metrics::Metrics {
+    metrics::start(
+        MetricsConfig {
+            output_stdout: false,
+            output_http: false,
+        },
+        "test".into(),
+    )
+}
@@ -1,5 +1,4 @@
 use futures::stream::{SelectAll, StreamExt};
-use jsonrpc_core_client::transports::ws;
 
 use solana_account_decoder::{UiAccount, UiAccountEncoding};
 use solana_client::{
@@ -13,43 +12,92 @@ use solana_sdk::{
 
 use log::*;
 use std::{
+    collections::HashSet,
     str::FromStr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use crate::{
-    chain_data::SlotStatus, snapshot::get_snapshot, AccountWrite, AnyhowWrap, FilterConfig,
-    SlotUpdate, SourceConfig,
+    chain_data::SlotStatus,
+    error::ConnectorError,
+    metrics::{MetricType, Metrics},
+    queue::{AccountWriteQueue, ResnapshotSignal},
+    rpc_connection::RpcConnection,
+    shutdown::CancellationToken,
+    snapshot::{
+        decode_snapshot_accounts, get_snapshot, program_pubkeys, rpc_filters, KnownAccounts,
+    },
+    AccountWrite, FilterConfig, SlotUpdate, SourceConfig,
 };
 
+// Backoff between reconnection attempts, doubled after every failed connection
+const RETRY_CONNECTION_SLEEP_MIN: Duration = Duration::from_secs(1);
+const RETRY_CONNECTION_SLEEP_MAX: Duration = Duration::from_secs(60);
+
+// A connection that stayed up for this long resets the backoff
+const HEALTHY_CONNECTION_DURATION: Duration = Duration::from_secs(60);
+
 enum WebsocketMessage {
-    SingleUpdate(Response<RpcKeyedAccount>),
-    SnapshotUpdate((Slot, Vec<(String, Option<UiAccount>)>)),
+    SingleUpdate {
+        slot: Slot,
+        pubkey: Pubkey,
+        account: Account,
+    },
+    SnapshotUpdate((Slot, Vec<(Pubkey, Option<Account>)>)),
     SlotUpdate(Arc<solana_client::rpc_response::SlotUpdate>),
 }
 
-// TODO: the reconnecting should be part of this
+fn transport_error(err: impl std::fmt::Debug) -> ConnectorError {
+    ConnectorError::Transport(format!("{:?}", err))
+}
+
+/// Decode an account update, bad data fails the connection and the next one starts with
+/// a new snapshot
+fn single_update(update: Response<RpcKeyedAccount>) -> Result<WebsocketMessage, ConnectorError> {
+    let pubkey = Pubkey::from_str(&update.value.pubkey).map_err(|err| {
+        ConnectorError::InvalidUpdate(format!("invalid pubkey {}: {}", update.value.pubkey, err))
+    })?;
+    let account =
+        update.value.account.decode::<Account>().ok_or_else(|| {
+            ConnectorError::InvalidUpdate(format!("undecodable account {}", pubkey))
+        })?;
+    Ok(WebsocketMessage::SingleUpdate {
+        slot: update.context.slot,
+        pubkey,
+        account,
+    })
+}
+
+fn channel_closed<T>(_: async_channel::SendError<T>) -> ConnectorError {
+    ConnectorError::ChannelClosed("websocket update")
+}
+
 async fn feed_data(
     config: &SourceConfig,
+    rpc_ws: &RpcConnection,
+    rpc_http: &RpcConnection,
     filter_config: &FilterConfig,
     sender: async_channel::Sender<WebsocketMessage>,
-) -> anyhow::Result<()> {
+    resnapshot: &ResnapshotSignal,
+) -> Result<(), ConnectorError> {
     debug!("feed_data {config:?}");
+    info!("connecting {}", rpc_ws);
 
-    let snapshot_duration = Duration::from_secs(300);
+    let snapshot_duration =
+        Duration::from_secs(config.snapshot.resnapshot_interval_secs.unwrap_or(300));
+    let idle_timeout = Duration::from_secs(60);
 
-    let connect = ws::try_connect::<RpcSolPubSubClient>(&config.rpc_ws_url).map_err_anyhow()?;
-    let client = connect.await.map_err_anyhow()?;
+    let client = rpc_ws.connect_ws::<RpcSolPubSubClient>().await?;
 
     let account_info_config = RpcAccountInfoConfig {
         encoding: Some(UiAccountEncoding::Base64),
         commitment: Some(CommitmentConfig::processed()),
-        data_slice: None,
+        data_slice: filter_config.data_slice.map(Into::into),
         min_context_slot: None,
     };
     let program_accounts_config = RpcProgramAccountsConfig {
-        filters: None,
+        filters: rpc_filters(&filter_config.account_filters),
         with_context: Some(true),
         account_config: account_info_config.clone(),
     };
@@ -66,7 +114,7 @@ async fn feed_data(
                         let account_id = account_id.clone();
                         s.map(move |r| (account_id.clone(), r))
                     })
-                    .map_err_anyhow()?,
+                    .map_err(transport_error)?,
             );
         }
     } else {
@@ -75,21 +123,40 @@ async fn feed_data(
             program_subs.push(
                 client
                     .program_subscribe(program_id, Some(program_accounts_config.clone()))
-                    .map_err_anyhow()?,
+                    .map_err(transport_error)?,
             );
         }
     }
 
-    let mut slot_sub = client.slots_updates_subscribe().map_err_anyhow()?;
+    let mut slot_sub = client.slots_updates_subscribe().map_err(transport_error)?;
 
-    let mut last_snapshot = Instant::now().checked_sub(snapshot_duration).unwrap();
+    // Always start a connection with a snapshot: account writes that happened while
+    // disconnected were missed and must not be kept around as stale data.
+    let (slot, accounts) = get_snapshot(rpc_http.clone(), filter_config)
+        .await
+        .map_err(|err| ConnectorError::Snapshot(format!("{:#}", err)))?;
+    let accounts = decode_snapshot_accounts(accounts)?;
+    debug!(
+        "fetched initial snapshot slot={slot} len={:?}",
+        accounts.len()
+    );
+    sender
+        .send(WebsocketMessage::SnapshotUpdate((slot, accounts)))
+        .await
+        .map_err(channel_closed)?;
+    let mut last_snapshot = Instant::now();
+    let mut resnapshot_generation = resnapshot.generation();
 
     loop {
-        // occasionally cause a new snapshot to be produced
-        // including the first time
-        if last_snapshot + snapshot_duration <= Instant::now() {
-            let snapshot = get_snapshot(config.snapshot.rpc_http_url.clone(), filter_config).await;
+        // occasionally cause a new snapshot to be produced, or when the account write
+        // queue dropped writes
+        if last_snapshot + snapshot_duration <= Instant::now()
+            || resnapshot.generation() != resnapshot_generation
+        {
+            resnapshot_generation = resnapshot.generation();
+            let snapshot = get_snapshot(rpc_http.clone(), filter_config).await;
             if let Ok((slot, accounts)) = snapshot {
+                let accounts = decode_snapshot_accounts(accounts)?;
                 debug!(
                     "fetched new snapshot slot={slot} len={:?} time={:?}",
                     accounts.len(),
@@ -98,7 +165,7 @@ async fn feed_data(
                 sender
                     .send(WebsocketMessage::SnapshotUpdate((slot, accounts)))
                     .await
-                    .expect("sending must succeed");
+                    .map_err(channel_closed)?;
             } else {
                 error!("failed to parse snapshot")
             }
@@ -111,7 +178,7 @@ async fn feed_data(
                     match account {
                         Some((account_id, response)) => {
                             sender.send(
-                                WebsocketMessage::SingleUpdate(
+                                single_update(
                                     response
                                         .map( |r: Response<UiAccount>|
                                             Response {
@@ -119,30 +186,25 @@ async fn feed_data(
                                                 value: RpcKeyedAccount {
                                                     pubkey: account_id.clone(),
                                                     account: r.value }})
-                                        .map_err_anyhow()?)).await.expect("sending must succeed");
+                                        .map_err(transport_error)?)?).await.map_err(channel_closed)?;
                         },
                         None => {
-                            warn!("account stream closed");
-                            if filter_config.program_ids.is_empty() {
-                                return Ok(());
-                            }
+                            return Err(ConnectorError::Transport("account stream closed".into()));
                         },
                     }
                 },
                 slot_update = slot_sub.next() => {
                     match slot_update {
                         Some(slot_update) => {
-                            sender.send(WebsocketMessage::SlotUpdate(slot_update.map_err_anyhow()?)).await.expect("sending must succeed");
+                            sender.send(WebsocketMessage::SlotUpdate(slot_update.map_err(transport_error)?)).await.map_err(channel_closed)?;
                         },
                         None => {
-                            warn!("slot update stream closed");
-                            return Ok(());
+                            return Err(ConnectorError::Transport("slot update stream closed".into()));
                         },
                     }
                 },
-                _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                    warn!("websocket timeout");
-                    return Ok(())
+                _ = tokio::time::sleep(idle_timeout) => {
+                    return Err(ConnectorError::IdleTimeout(idle_timeout));
                 }
             }
         } else {
@@ -150,28 +212,25 @@ async fn feed_data(
                 program_account = program_subs.next() => {
                     match program_account {
                         Some(account) => {
-                            sender.send(WebsocketMessage::SingleUpdate(account.map_err_anyhow()?)).await.expect("sending must succeed");
+                            sender.send(single_update(account.map_err(transport_error)?)?).await.map_err(channel_closed)?;
                         },
                         None => {
-                            warn!("program account stream closed");
-                            return Ok(());
+                            return Err(ConnectorError::Transport("program account stream closed".into()));
                         },
                     }
                 },
                 slot_update = slot_sub.next() => {
                     match slot_update {
                         Some(slot_update) => {
-                            sender.send(WebsocketMessage::SlotUpdate(slot_update.map_err_anyhow()?)).await.expect("sending must succeed");
+                            sender.send(WebsocketMessage::SlotUpdate(slot_update.map_err(transport_error)?)).await.map_err(channel_closed)?;
                         },
                         None => {
-                            warn!("slot update stream closed");
-                            return Ok(());
+                            return Err(ConnectorError::Transport("slot update stream closed".into()));
                         },
                     }
                 },
-                _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                    warn!("websocket timeout");
-                    return Ok(())
+                _ = tokio::time::sleep(idle_timeout) => {
+                    return Err(ConnectorError::IdleTimeout(idle_timeout));
                 }
             }
         }
@@ -184,53 +243,174 @@ pub async fn process_events(
     filter_config: &FilterConfig,
     account_write_queue_sender: async_channel::Sender<AccountWrite>,
     slot_queue_sender: async_channel::Sender<SlotUpdate>,
-) {
+    metrics_sender: Metrics,
+    shutdown: CancellationToken,
+) -> Result<(), ConnectorError> {
+    // Check the config before connecting anywhere
+    let rpc_ws = RpcConnection::new(&config.rpc_ws_url, &config.rpc_ws_connection)?;
+    let rpc_http = RpcConnection::new(
+        &config.snapshot.rpc_http_url,
+        &config.snapshot.rpc_http_connection,
+    )?;
+
     // Subscribe to program account updates websocket
     let (update_sender, update_receiver) = async_channel::unbounded::<WebsocketMessage>();
+    // gPA snapshots only contain existing accounts, gMA snapshots report closed ones
+    let gpa_program_ids = if filter_config.account_ids.is_empty() {
+        program_pubkeys(&filter_config.program_ids)
+    } else {
+        HashSet::new()
+    };
+    let resnapshot = ResnapshotSignal::default();
+    let mut account_write_queue = AccountWriteQueue::new(
+        config.queue_policy.clone(),
+        account_write_queue_sender,
+        resnapshot.clone(),
+        &metrics_sender,
+        "websocket",
+    );
+    let mut metric_slot_queue_high_water = metrics_sender.register_u64(
+        "websocket_slot_update_queue_high_water".into(),
+        MetricType::Gauge,
+    );
     let config = config.clone();
     let filter_config = filter_config.clone();
-    tokio::spawn(async move {
+    let feed_shutdown = shutdown.clone();
+    // ends on shutdown or with the error that stopped the source, closing the channel
+    let feed = tokio::spawn(async move {
+        let mut metric_retries = metrics_sender.register_u64(
+            "websocket_source_connection_retries".into(),
+            MetricType::Counter,
+        );
+        let metric_connected = metrics_sender.register_bool("websocket_source_status".into());
+
         // if the websocket disconnects, we get no data in a while etc, reconnect and try again
+        let mut retry_sleep = RETRY_CONNECTION_SLEEP_MIN;
         loop {
-            let out = feed_data(&config, &filter_config, update_sender.clone());
-            let _ = out.await;
+            metric_connected.set(true);
+            let connected_at = Instant::now();
+            let out = feed_data(
+                &config,
+                &rpc_ws,
+                &rpc_http,
+                &filter_config,
+                update_sender.clone(),
+                &resnapshot,
+            );
+            let err = tokio::select! {
+                out = out => match out {
+                    Ok(()) => unreachable!("feed_data only returns on errors"),
+                    Err(err) => err,
+                },
+                _ = feed_shutdown.cancelled() => break,
+            };
+            metric_connected.set(false);
+            metrics_sender
+                .register_u64(
+                    format!("websocket_source_errors_{}", err.kind()),
+                    MetricType::Counter,
+                )
+                .increment();
+            if err.is_fatal() {
+                warn!("websocket source stopped: {}", err);
+                return Err(err);
+            }
+            warn!(
+                "error during communication with the websocket source. retrying. {}",
+                err
+            );
+            metric_retries.increment();
+
+            if connected_at.elapsed() >= HEALTHY_CONNECTION_DURATION {
+                retry_sleep = RETRY_CONNECTION_SLEEP_MIN;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(retry_sleep) => {}
+                _ = feed_shutdown.cancelled() => break,
+            }
+            retry_sleep = (retry_sleep * 2).min(RETRY_CONNECTION_SLEEP_MAX);
         }
+        Ok(())
     });
 
     //
     // The thread that pulls updates and forwards them to postgres
     //
 
+    // Used to notice accounts that were closed while no subscription reported it
+    let mut known_accounts = KnownAccounts::default();
+
     // copy websocket updates into the postgres account write queue
     loop {
-        let update = update_receiver.recv().await.unwrap();
+        if shutdown.is_cancelled() {
+            warn!("shutting down websocket_source...");
+            break;
+        }
+
+        let update = if account_write_queue.has_pending() {
+            // coalesced writes need to move on once the consumer catches up
+            match tokio::time::timeout(Duration::from_millis(10), update_receiver.recv()).await {
+                Ok(update) => update,
+                Err(_) => {
+                    account_write_queue.flush()?;
+                    continue;
+                }
+            }
+        } else {
+            tokio::select! {
+                update = update_receiver.recv() => update,
+                _ = shutdown.cancelled() => continue,
+            }
+        };
+        let update = match update {
+            Ok(update) => update,
+            Err(_) => {
+                return feed
+                    .await
+                    .map_err(|_| ConnectorError::ChannelClosed("websocket update"))?;
+            }
+        };
         trace!("got update message");
 
         match update {
-            WebsocketMessage::SingleUpdate(update) => {
+            WebsocketMessage::SingleUpdate {
+                slot,
+                pubkey,
+                account,
+            } => {
                 trace!("single update");
-                let account: Account = update.value.account.decode().unwrap();
-                let pubkey = Pubkey::from_str(&update.value.pubkey).unwrap();
-                account_write_queue_sender
-                    .send(AccountWrite::from(pubkey, update.context.slot, 0, account))
-                    .await
-                    .expect("send success");
+                // closed accounts arrive with 0 lamports, see AccountWrite::is_deleted()
+                let account_write = AccountWrite {
+                    data_slice: filter_config.data_slice,
+                    ..AccountWrite::from(pubkey, slot, 0, account)
+                };
+                known_accounts.observe(&account_write);
+                account_write_queue.send(account_write).await?;
             }
             WebsocketMessage::SnapshotUpdate((slot, accounts)) => {
                 trace!("snapshot update {slot}");
+                let mut snapshot_pubkeys = HashSet::new();
                 for (pubkey, account) in accounts {
-                    if let Some(account) = account {
-                        let pubkey = Pubkey::from_str(&pubkey).unwrap();
-                        account_write_queue_sender
-                            .send(AccountWrite::from(
-                                pubkey,
-                                slot,
-                                0,
-                                account.decode().unwrap(),
-                            ))
-                            .await
-                            .expect("send success");
-                    }
+                    let account_write = match account {
+                        Some(account) => {
+                            snapshot_pubkeys.insert(pubkey);
+                            AccountWrite {
+                                data_slice: filter_config.data_slice,
+                                ..AccountWrite::from(pubkey, slot, 0, account)
+                            }
+                        }
+                        // gMA reports closed accounts as missing
+                        None => AccountWrite::tombstone(pubkey, slot, 0),
+                    };
+                    known_accounts.observe(&account_write);
+                    account_write_queue
+                        .send_snapshot_write(account_write)
+                        .await?;
+                }
+                for tombstone in
+                    known_accounts.closed_since_snapshot(&gpa_program_ids, &snapshot_pubkeys, slot)
+                {
+                    account_write_queue.send_snapshot_write(tombstone).await?;
                 }
             }
             WebsocketMessage::SlotUpdate(update) => {
@@ -261,9 +441,15 @@ pub async fn process_events(
                     _ => None,
                 };
                 if let Some(message) = message {
-                    slot_queue_sender.send(message).await.expect("send success");
+                    slot_queue_sender
+                        .send(message)
+                        .await
+                        .map_err(|_| ConnectorError::ChannelClosed("slot update"))?;
+                    metric_slot_queue_high_water.set_max(slot_queue_sender.len() as u64);
                 }
             }
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,169 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use mango_feeds_connector::{
+    account_write_filter::{self, AccountMatcher, AccountWriteRoute, AccountWriteSink},
+    chain_data::AccountState,
+    shutdown::CancellationToken,
+    solana_sdk::pubkey::Pubkey,
+    test_support::metrics,
+    AccountFilter, AccountWrite, DataSlice, QueuePolicy,
+};
+
+/// Remembers the accounts it was called with, whether they were closed and the data
+/// slice of their writes
+#[derive(Default)]
+struct RecordingSink {
+    processed: Mutex<Vec<(Pubkey, bool)>>,
+    data_slices: Mutex<Vec<Option<DataSlice>>>,
+}
+
+#[async_trait]
+impl AccountWriteSink for RecordingSink {
+    async fn process(&self, pubkey: &Pubkey, account: AccountState<'_>) -> Result<(), String> {
+        let is_deleted = matches!(account, AccountState::Deleted(_));
+        self.processed.lock().unwrap().push((*pubkey, is_deleted));
+        self.data_slices
+            .lock()
+            .unwrap()
+            .push(account.write().data_slice);
+        Ok(())
+    }
+}
+
+fn account_write(
+    pubkey: Pubkey,
+    owner: Pubkey,
+    slot: u64,
+    lamports: u64,
+    data: Vec<u8>,
+) -> AccountWrite {
+    AccountWrite {
+        pubkey,
+        slot,
+        write_version: 1,
+        lamports,
+        owner,
+        executable: false,
+        rent_epoch: 0,
+        data,
+        data_slice: None,
+        is_selected: true,
+    }
+}
+
+#[tokio::test]
+async fn accounts_are_routed_by_owner_and_discriminator() {
+    let program_id = Pubkey::new_unique();
+    let discriminator = vec![1, 2, 3, 4];
+    let sink = Arc::new(RecordingSink::default());
+    let routes = vec![AccountWriteRoute {
+        matched_pubkeys: vec![],
+        matchers: vec![AccountMatcher::Owner {
+            program_id,
+            account_filters: vec![AccountFilter::Memcmp {
+                offset: 0,
+                bytes: bs58::encode(&discriminator).into_string(),
+            }],
+        }],
+        sink: sink.clone(),
+        timeout_interval: Duration::default(),
+    }];
+    let shutdown = CancellationToken::new();
+    let (account_write_sender, _slot_sender) = account_write_filter::init(
+        routes,
+        &QueuePolicy::Unbounded,
+        None,
+        metrics(),
+        shutdown.clone(),
+    )
+    .unwrap();
+
+    let matching = Pubkey::new_unique();
+    let other_discriminator = Pubkey::new_unique();
+    let other_owner = Pubkey::new_unique();
+    let mut data = discriminator.clone();
+    data.extend([9, 9]);
+    for write in [
+        account_write(matching, program_id, 10, 1, data.clone()),
+        account_write(other_discriminator, program_id, 10, 1, vec![4, 3, 2, 1]),
+        account_write(other_owner, Pubkey::new_unique(), 10, 1, data),
+        // closing changes the owner and clears the data, the account stays routed
+        account_write(matching, Pubkey::default(), 11, 0, vec![]),
+    ] {
+        account_write_sender.send(write).await.unwrap();
+    }
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while sink.processed.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("sink was called in time");
+    shutdown.cancel();
+    assert_eq!(
+        *sink.processed.lock().unwrap(),
+        vec![(matching, false), (matching, true)]
+    );
+}
+
+#[tokio::test]
+async fn sliced_writes_only_pass_filters_within_the_slice() {
+    let program_id = Pubkey::new_unique();
+    let discriminator = vec![1, 2, 3, 4];
+    let sink = Arc::new(RecordingSink::default());
+    let routes = vec![AccountWriteRoute {
+        matched_pubkeys: vec![],
+        matchers: vec![AccountMatcher::Owner {
+            program_id,
+            account_filters: vec![AccountFilter::Memcmp {
+                offset: 8,
+                bytes: bs58::encode(&discriminator).into_string(),
+            }],
+        }],
+        sink: sink.clone(),
+        timeout_interval: Duration::default(),
+    }];
+    let shutdown = CancellationToken::new();
+    let (account_write_sender, _slot_sender) = account_write_filter::init(
+        routes,
+        &QueuePolicy::Unbounded,
+        None,
+        metrics(),
+        shutdown.clone(),
+    )
+    .unwrap();
+
+    let within = Pubkey::new_unique();
+    let outside = Pubkey::new_unique();
+    let within_slice = DataSlice {
+        offset: 8,
+        length: 4,
+    };
+    // the same bytes, but at offset 0 of the account data. Sent first, the writes are
+    // handled in order.
+    let mut write = account_write(outside, program_id, 10, 1, discriminator.clone());
+    write.data_slice = Some(DataSlice {
+        offset: 0,
+        length: 4,
+    });
+    account_write_sender.send(write).await.unwrap();
+    let mut write = account_write(within, program_id, 10, 1, discriminator);
+    write.data_slice = Some(within_slice);
+    account_write_sender.send(write).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while sink.processed.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("sink was called in time");
+    shutdown.cancel();
+    assert_eq!(*sink.processed.lock().unwrap(), vec![(within, false)]);
+    assert_eq!(*sink.data_slices.lock().unwrap(), vec![Some(within_slice)]);
+}
@@ -0,0 +1,378 @@
+use mango_feeds_connector::{
+    chain_data::{
+        AccountData, AccountState, ChainData, ChainDataPersistence, HistoryRetention, SlotData,
+        SlotStatus,
+    },
+    solana_sdk::{account::WritableAccount, pubkey::Pubkey},
+    test_support::metrics,
+    ChainDataPersistenceConfig, DataSlice,
+};
+
+fn update_slot(chain: &mut ChainData, slot: u64, parent: u64, status: SlotStatus) -> Vec<Pubkey> {
+    chain.update_slot(SlotData {
+        slot,
+        parent: Some(parent),
+        status,
+        chain: 0,
+    })
+}
+
+/// A write with `lamports: 0` closes the account
+fn account_data(slot: u64, lamports: u64, data: Vec<u8>) -> AccountData {
+    AccountData {
+        slot,
+        write_version: 1,
+        data_slice: None,
+        account: WritableAccount::create(lamports, data, Pubkey::default(), false, 0),
+    }
+}
+
+fn is_deleted(chain: &ChainData, pubkey: &Pubkey) -> bool {
+    matches!(chain.account_state(pubkey), Ok(AccountState::Deleted(_)))
+}
+
+#[test]
+fn closed_accounts_come_back_on_a_fork_switch_and_go_away_once_rooted() {
+    let mut chain = ChainData::new();
+    let pubkey = Pubkey::new_unique();
+    update_slot(&mut chain, 10, 9, SlotStatus::Rooted);
+    chain.update_account(pubkey, account_data(10, 1, vec![1]));
+
+    // closed on the fork of slot 11
+    update_slot(&mut chain, 11, 10, SlotStatus::Processed);
+    assert!(chain.update_account(pubkey, account_data(11, 0, vec![])));
+    assert!(is_deleted(&chain, &pubkey));
+    assert!(chain.account(&pubkey).is_err());
+    assert_eq!(chain.iter_accounts().count(), 0);
+
+    // the fork of slot 12 doesn't close it
+    assert_eq!(
+        update_slot(&mut chain, 12, 10, SlotStatus::Processed),
+        vec![pubkey]
+    );
+    assert_eq!(chain.account(&pubkey).unwrap().slot, 10);
+
+    // back on the closing fork, and rooting the close drops the account
+    update_slot(&mut chain, 13, 11, SlotStatus::Processed);
+    assert!(is_deleted(&chain, &pubkey));
+    update_slot(&mut chain, 13, 11, SlotStatus::Rooted);
+    assert!(chain.account_state(&pubkey).is_err());
+    assert_eq!(chain.accounts_count(), 0);
+}
+
+#[test]
+fn reorg_events_report_the_fork_point_and_the_switched_accounts() {
+    let mut chain = ChainData::new();
+    chain.enable_reorg_events();
+    let on_fork = Pubkey::new_unique();
+    let on_ancestor = Pubkey::new_unique();
+    update_slot(&mut chain, 10, 9, SlotStatus::Rooted);
+    update_slot(&mut chain, 11, 10, SlotStatus::Processed);
+    update_slot(&mut chain, 12, 11, SlotStatus::Processed);
+    chain.update_account(on_ancestor, account_data(11, 1, vec![1]));
+    chain.update_account(on_fork, account_data(12, 1, vec![2]));
+
+    // extending the best chain is no reorg
+    update_slot(&mut chain, 13, 12, SlotStatus::Processed);
+    assert!(chain.take_reorg_events().is_empty());
+
+    // switching to a fork off slot 11 drops the write in slot 12
+    assert_eq!(
+        update_slot(&mut chain, 14, 11, SlotStatus::Processed),
+        vec![on_fork]
+    );
+    let events = chain.take_reorg_events();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(
+        (event.old_tip, event.new_tip, event.common_ancestor),
+        (13, 14, 11)
+    );
+    assert_eq!(event.accounts.len(), 1);
+    assert_eq!(
+        (
+            event.accounts[0].pubkey,
+            event.accounts[0].old,
+            event.accounts[0].new
+        ),
+        (on_fork, Some((12, 1)), None)
+    );
+    assert!(chain.take_reorg_events().is_empty());
+
+    // a fork that meets the best chain at the rooted slot
+    update_slot(&mut chain, 15, 10, SlotStatus::Processed);
+    let events = chain.take_reorg_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].common_ancestor, 10);
+    assert_eq!(chain.reorg_count(), 2);
+}
+
+#[test]
+fn reorgs_are_only_counted_unless_events_are_enabled() {
+    let mut chain = ChainData::new();
+    update_slot(&mut chain, 10, 9, SlotStatus::Rooted);
+    update_slot(&mut chain, 11, 10, SlotStatus::Processed);
+    update_slot(&mut chain, 12, 10, SlotStatus::Processed);
+    assert_eq!(chain.reorg_count(), 1);
+    assert!(chain.take_reorg_events().is_empty());
+
+    // a new tip whose chain isn't known down to the old tip can't be told apart from
+    // a descendant yet
+    update_slot(&mut chain, 20, 19, SlotStatus::Processed);
+    assert_eq!(chain.reorg_count(), 1);
+}
+
+fn root(chain: &mut ChainData, slot: u64) {
+    update_slot(chain, slot, slot - 1, SlotStatus::Rooted);
+}
+
+fn slot_at(chain: &ChainData, pubkey: &Pubkey, slot: u64) -> Option<u64> {
+    chain
+        .account_at_slot(pubkey, slot)
+        .ok()
+        .map(|write| write.slot)
+}
+
+#[test]
+fn history_is_kept_for_the_retained_slots() {
+    let pubkey = Pubkey::new_unique();
+    let mut chains = [ChainData::new(), ChainData::new()];
+    chains[1].set_history_retention(Some(HistoryRetention::Slots(5)));
+    for chain in chains.iter_mut() {
+        root(chain, 10);
+        for slot in [10, 12, 14] {
+            chain.update_account(pubkey, account_data(slot, 1, vec![0; 4]));
+        }
+        for slot in 11..=15 {
+            root(chain, slot);
+        }
+    }
+    let [without_history, mut with_history] = chains;
+
+    // without retention only the newest rooted write is left
+    assert_eq!(slot_at(&without_history, &pubkey, 13), None);
+    assert_eq!(slot_at(&without_history, &pubkey, 14), Some(14));
+    assert_eq!(without_history.history_writes_count(), 0);
+
+    assert_eq!(slot_at(&with_history, &pubkey, 9), None);
+    assert_eq!(slot_at(&with_history, &pubkey, 11), Some(10));
+    assert_eq!(slot_at(&with_history, &pubkey, 13), Some(12));
+    assert_eq!(slot_at(&with_history, &pubkey, 20), Some(14));
+    assert_eq!(with_history.history_writes_count(), 2);
+
+    // slot 10 falls out of the five slots before the rooted slot
+    root(&mut with_history, 16);
+    assert_eq!(slot_at(&with_history, &pubkey, 11), None);
+    assert_eq!(slot_at(&with_history, &pubkey, 13), Some(12));
+    assert_eq!(with_history.history_writes_count(), 1);
+    assert_eq!(with_history.history_bytes(), 4);
+}
+
+#[test]
+fn history_is_trimmed_to_the_byte_budget_oldest_first() {
+    let pubkey = Pubkey::new_unique();
+    let mut chain = ChainData::new();
+    chain.set_history_retention(Some(HistoryRetention::Bytes(8)));
+    for slot in 10..=13 {
+        chain.update_account(pubkey, account_data(slot, 1, vec![0; 4]));
+    }
+    for slot in 10..=12 {
+        root(&mut chain, slot);
+    }
+    assert_eq!(slot_at(&chain, &pubkey, 10), Some(10));
+    assert_eq!(chain.history_bytes(), 8);
+
+    // the history of slots 10 to 12 doesn't fit anymore
+    root(&mut chain, 13);
+    assert_eq!(slot_at(&chain, &pubkey, 10), None);
+    assert_eq!(slot_at(&chain, &pubkey, 11), Some(11));
+    assert_eq!(slot_at(&chain, &pubkey, 12), Some(12));
+    assert_eq!(chain.history_writes_count(), 2);
+    assert_eq!(chain.history_bytes(), 8);
+    assert_eq!(chain.account_writes_count(), 3);
+    assert_eq!(chain.account_bytes(), 12);
+}
+
+#[test]
+fn live_write_changes_are_reported_once() {
+    let mut chain = ChainData::new();
+    let pubkey = Pubkey::new_unique();
+    let other = Pubkey::new_unique();
+    root(&mut chain, 10);
+    chain.update_account(pubkey, account_data(10, 1, vec![1]));
+    chain.update_account(other, account_data(10, 1, vec![1]));
+    update_slot(&mut chain, 11, 10, SlotStatus::Processed);
+    assert!(chain.update_account(pubkey, account_data(11, 1, vec![2])));
+
+    // the same write again, or an older one, changes nothing
+    assert!(!chain.update_account(pubkey, account_data(11, 1, vec![2])));
+    let mut older = account_data(11, 1, vec![3]);
+    older.write_version = 0;
+    assert!(!chain.update_account(pubkey, older));
+
+    // a fork switch goes back to the write in slot 10, once
+    assert_eq!(
+        update_slot(&mut chain, 12, 10, SlotStatus::Processed),
+        vec![pubkey]
+    );
+    assert_eq!(chain.account(&pubkey).unwrap().slot, 10);
+    assert!(update_slot(&mut chain, 13, 12, SlotStatus::Processed).is_empty());
+    assert!(update_slot(&mut chain, 12, 10, SlotStatus::Confirmed).is_empty());
+
+    // and switching back brings the write in slot 11 back, once
+    assert_eq!(
+        update_slot(&mut chain, 14, 11, SlotStatus::Processed),
+        vec![pubkey]
+    );
+    assert!(update_slot(&mut chain, 14, 11, SlotStatus::Confirmed).is_empty());
+
+    // rooting slot 11 drops the older write without changing the live one
+    assert!(update_slot(&mut chain, 11, 10, SlotStatus::Rooted).is_empty());
+    assert_eq!(chain.account(&pubkey).unwrap().slot, 11);
+}
+
+fn slot_at_commitment(chain: &ChainData, pubkey: &Pubkey, status: SlotStatus) -> Option<u64> {
+    chain
+        .account_at_commitment(pubkey, status)
+        .ok()
+        .map(|write| write.slot)
+}
+
+#[test]
+fn confirmed_writes_are_seen_under_a_processed_tip() {
+    let mut chain = ChainData::new();
+    let pubkey = Pubkey::new_unique();
+    root(&mut chain, 10);
+    chain.update_account(pubkey, account_data(10, 1, vec![1]));
+    update_slot(&mut chain, 11, 10, SlotStatus::Confirmed);
+    chain.update_account(pubkey, account_data(11, 1, vec![2]));
+    update_slot(&mut chain, 12, 11, SlotStatus::Processed);
+    chain.update_account(pubkey, account_data(12, 1, vec![3]));
+
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Processed),
+        Some(12)
+    );
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Confirmed),
+        Some(11)
+    );
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Rooted),
+        Some(10)
+    );
+    assert_eq!(
+        chain
+            .accounts_snapshot_at_commitment(SlotStatus::Confirmed)
+            .get(&pubkey)
+            .map(|write| write.slot),
+        Some(11)
+    );
+}
+
+#[test]
+fn writes_before_the_rooted_slot_are_rooted_without_their_own_notification() {
+    let mut chain = ChainData::new();
+    let pubkey = Pubkey::new_unique();
+    root(&mut chain, 10);
+    chain.update_account(pubkey, account_data(10, 1, vec![1]));
+    update_slot(&mut chain, 11, 10, SlotStatus::Processed);
+    chain.update_account(pubkey, account_data(11, 1, vec![2]));
+    update_slot(&mut chain, 12, 11, SlotStatus::Processed);
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Rooted),
+        Some(10)
+    );
+
+    // slot 11 is never reported as rooted, only its child
+    update_slot(&mut chain, 12, 11, SlotStatus::Rooted);
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Rooted),
+        Some(11)
+    );
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Confirmed),
+        Some(11)
+    );
+    assert_eq!(chain.account_writes_count(), 1);
+}
+
+#[test]
+fn fork_switch_moves_the_confirmed_write() {
+    let mut chain = ChainData::new();
+    let pubkey = Pubkey::new_unique();
+    root(&mut chain, 10);
+    chain.update_account(pubkey, account_data(10, 1, vec![1]));
+    update_slot(&mut chain, 11, 10, SlotStatus::Confirmed);
+    chain.update_account(pubkey, account_data(11, 1, vec![2]));
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Confirmed),
+        Some(11)
+    );
+
+    // the fork of slot 12 doesn't contain slot 11
+    update_slot(&mut chain, 12, 10, SlotStatus::Processed);
+    chain.update_account(pubkey, account_data(12, 1, vec![3]));
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Confirmed),
+        Some(10)
+    );
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Processed),
+        Some(12)
+    );
+
+    update_slot(&mut chain, 12, 10, SlotStatus::Confirmed);
+    assert_eq!(
+        slot_at_commitment(&chain, &pubkey, SlotStatus::Confirmed),
+        Some(12)
+    );
+}
+
+#[test]
+fn slot_statuses_are_ordered_by_commitment() {
+    assert!(SlotStatus::Processed < SlotStatus::Confirmed);
+    assert!(SlotStatus::Confirmed < SlotStatus::Rooted);
+    assert!(SlotStatus::Rooted.reaches(SlotStatus::Confirmed));
+    assert!(!SlotStatus::Processed.reaches(SlotStatus::Confirmed));
+}
+
+#[tokio::test]
+async fn persistence_saves_rooted_writes_in_the_background() {
+    let path = std::env::temp_dir().join(format!("connector-chain-data-{}", std::process::id()));
+    let config = ChainDataPersistenceConfig {
+        path: path.to_str().unwrap().to_owned(),
+        interval_secs: 0,
+    };
+    let mut persistence = ChainDataPersistence::new(&config, &metrics());
+
+    let mut chain = ChainData::new();
+    let rooted = Pubkey::new_unique();
+    let unrooted = Pubkey::new_unique();
+    root(&mut chain, 10);
+    let mut write = account_data(10, 1, vec![1, 2]);
+    write.data_slice = Some(DataSlice {
+        offset: 4,
+        length: 2,
+    });
+    chain.update_account(rooted, write);
+    chain.update_account(unrooted, account_data(11, 1, vec![3]));
+
+    persistence.save_if_due(&chain);
+    // waits for the background save, then saves again
+    persistence.save(&chain).await;
+
+    let loaded = persistence.load();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.newest_rooted_slot(), 10);
+    assert_eq!(loaded.accounts_count(), 1);
+    let write = loaded.account(&rooted).unwrap();
+    assert_eq!(write.slot, 10);
+    assert_eq!(
+        write.data_slice,
+        Some(DataSlice {
+            offset: 4,
+            length: 2
+        })
+    );
+}
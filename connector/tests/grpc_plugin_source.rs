@@ -0,0 +1,724 @@
+use std::{future::Future, io::Write, time::Duration};
+
+use mango_feeds_connector::{
+    error::ConnectorError,
+    filter_handle::FilterHandle,
+    grpc_plugin_source,
+    metrics::{MetricType, Metrics},
+    shutdown::CancellationToken,
+    solana_sdk::{account::Account, hash::Hash, pubkey::Pubkey, signature::Signature},
+    test_support::{account_info, metrics, source_config, FakeGeyser, RpcStub},
+    AccountDataCompression, AccountWrite, BlockMetaUpdate, DataSlice, FilterConfig, SlotUpdate,
+    SourceConfig, TransactionFilterConfig, TransactionUpdate,
+};
+use yellowstone_grpc_proto::prelude::{
+    subscribe_update::UpdateOneof,
+    BlockHeight, CompiledInstruction, Message, SubscribeUpdateBlockMeta,
+    SubscribeUpdateSlotStatus::{Finalized, Processed},
+    SubscribeUpdateTransaction, SubscribeUpdateTransactionInfo, Transaction, TransactionStatusMeta,
+    UnixTimestamp,
+};
+
+fn program_filter(program_id: &Pubkey) -> FilterConfig {
+    FilterConfig {
+        program_ids: vec![program_id.to_string()],
+        account_ids: vec![],
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
+    }
+}
+
+fn program_account(owner: Pubkey, data: Vec<u8>) -> Account {
+    Account {
+        lamports: 1,
+        data,
+        owner,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+/// A transaction as the geyser plugin would send it, `signature` repeated for all bytes
+fn transaction(slot: u64, signature: u8, account_keys: &[Pubkey]) -> UpdateOneof {
+    UpdateOneof::Transaction(SubscribeUpdateTransaction {
+        transaction: Some(SubscribeUpdateTransactionInfo {
+            signature: vec![signature; 64],
+            is_vote: false,
+            transaction: Some(Transaction {
+                signatures: vec![vec![signature; 64]],
+                message: Some(Message {
+                    account_keys: account_keys
+                        .iter()
+                        .map(|key| key.to_bytes().to_vec())
+                        .collect(),
+                    instructions: vec![CompiledInstruction {
+                        program_id_index: 0,
+                        accounts: vec![],
+                        data: vec![signature],
+                    }],
+                    ..Default::default()
+                }),
+            }),
+            meta: Some(TransactionStatusMeta {
+                log_messages: vec![format!("transaction {}", signature)],
+                ..Default::default()
+            }),
+            index: signature as u64,
+        }),
+        slot,
+    })
+}
+
+fn block_meta(slot: u64, blockhash: &Hash) -> UpdateOneof {
+    UpdateOneof::BlockMeta(SubscribeUpdateBlockMeta {
+        slot,
+        blockhash: blockhash.to_string(),
+        block_time: Some(UnixTimestamp {
+            timestamp: 1_700_000_000 + slot as i64,
+        }),
+        block_height: Some(BlockHeight {
+            block_height: slot - 100,
+        }),
+        ..Default::default()
+    })
+}
+
+fn lz4_compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = lz4::EncoderBuilder::new().build(Vec::new()).unwrap();
+    encoder.write_all(data).unwrap();
+    let (compressed, result) = encoder.finish();
+    result.unwrap();
+    compressed
+}
+
+async fn recv<T>(receiver: &async_channel::Receiver<T>) -> T {
+    tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+        .await
+        .expect("received in time")
+        .expect("channel open")
+}
+
+/// Fake geysers and rpc node for a grpc source
+struct Harness {
+    geysers: Vec<FakeGeyser>,
+    rpc: RpcStub,
+    config: SourceConfig,
+    metrics: Metrics,
+    shutdown: CancellationToken,
+}
+
+/// Receiving ends of the queues of a running source
+struct Queues {
+    account_writes: async_channel::Receiver<AccountWrite>,
+    slots: async_channel::Receiver<SlotUpdate>,
+    transactions: async_channel::Receiver<TransactionUpdate>,
+    block_metas: async_channel::Receiver<BlockMetaUpdate>,
+}
+
+impl Harness {
+    async fn new(geyser_count: usize) -> Self {
+        let mut geysers = vec![];
+        for _ in 0..geyser_count {
+            geysers.push(FakeGeyser::start().await);
+        }
+        let rpc = RpcStub::start();
+        let config = source_config(&geysers.iter().collect::<Vec<_>>(), &rpc);
+        Self {
+            geysers,
+            rpc,
+            config,
+            metrics: metrics(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    fn start(
+        &self,
+        filter_config: &FilterConfig,
+    ) -> (
+        FilterHandle,
+        Queues,
+        impl Future<Output = Result<(), ConnectorError>> + '_,
+    ) {
+        let (account_write_sender, account_writes) = async_channel::unbounded();
+        let (slot_sender, slots) = async_channel::unbounded();
+        let (transaction_sender, transactions) = async_channel::unbounded();
+        let (block_meta_sender, block_metas) = async_channel::unbounded();
+        let (filter_handle, source) = grpc_plugin_source::process_events(
+            &self.config,
+            filter_config,
+            account_write_sender,
+            slot_sender,
+            Some(transaction_sender),
+            Some(block_meta_sender),
+            self.metrics.clone(),
+            self.shutdown.clone(),
+        );
+        let queues = Queues {
+            account_writes,
+            slots,
+            transactions,
+            block_metas,
+        };
+        (filter_handle, queues, source)
+    }
+}
+
+/// Run `script` against a running source, which must not stop before the script is done
+async fn run(
+    source: impl Future<Output = Result<(), ConnectorError>>,
+    script: impl Future<Output = ()>,
+) {
+    tokio::select! {
+        result = source => panic!("source stopped: {:?}", result),
+        _ = script => {}
+    }
+}
+
+#[tokio::test]
+async fn write_versions_are_remapped_from_first_full_slot() {
+    let harness = Harness::new(1).await;
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&program_id));
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        assert_eq!(
+            subscription.request.accounts["client"].owner,
+            vec![program_id.to_string()]
+        );
+
+        // writes before the first finalized slot and for that slot itself are dropped
+        subscription
+            .send_account(999, account_info(&account, &program_id, 100, vec![1]))
+            .await;
+        subscription.send_slot(1000, Some(999), Finalized).await;
+        subscription
+            .send_account(1000, account_info(&account, &program_id, 101, vec![2]))
+            .await;
+        subscription.send_slot(1001, Some(1000), Processed).await;
+        subscription
+            .send_account(1001, account_info(&account, &program_id, 102, vec![3]))
+            .await;
+        subscription
+            .send_account(1001, account_info(&account, &program_id, 107, vec![4]))
+            .await;
+        subscription
+            .send_account(1002, account_info(&account, &program_id, 110, vec![5]))
+            .await;
+
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.slot, write.write_version, write.data),
+            (1001, 1, vec![3])
+        );
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.slot, write.write_version, write.data),
+            (1001, 2, vec![4])
+        );
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.slot, write.write_version, write.data),
+            (1002, 1, vec![5])
+        );
+
+        assert_eq!(recv(&queues.slots).await.slot, 1000);
+        assert_eq!(recv(&queues.slots).await.slot, 1001);
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn too_old_snapshot_is_retried() {
+    let harness = Harness::new(1).await;
+    let rpc = &harness.rpc;
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    rpc.set_account(account, program_account(program_id, vec![9]));
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&program_id));
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        subscription.send_slot(1000, None, Finalized).await;
+
+        // first full slot is 1001, the snapshot is requested once the rooted slot is more
+        // than 30 slots beyond it
+        rpc.set_slot(1000);
+        subscription.send_slot(1031, None, Finalized).await;
+        subscription.send_ping().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(rpc.request_count("getProgramAccounts"), 0);
+
+        subscription.send_slot(1032, None, Finalized).await;
+        while rpc.request_count("getProgramAccounts") < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the snapshot was too old, the retry waits 10 more slots
+        rpc.set_slot(1040);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        subscription.send_slot(1041, None, Finalized).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(rpc.request_count("getProgramAccounts"), 1);
+
+        subscription.send_slot(1042, None, Finalized).await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(rpc.request_count("getProgramAccounts"), 2);
+        assert_eq!(write.pubkey, account);
+        assert_eq!(
+            (write.slot, write.write_version, write.data),
+            (1040, 0, vec![9])
+        );
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn duplicate_program_ids_are_snapshotted_once() {
+    let harness = Harness::new(1).await;
+    let rpc = &harness.rpc;
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    rpc.set_account(account, program_account(program_id, vec![9]));
+    rpc.set_slot(1032);
+    let filter_config = FilterConfig {
+        program_ids: vec![program_id.to_string(), program_id.to_string()],
+        ..program_filter(&program_id)
+    };
+    let (_filter_handle, queues, source) = harness.start(&filter_config);
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        subscription.send_slot(1000, None, Finalized).await;
+        subscription.send_slot(1032, None, Finalized).await;
+
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(rpc.request_count("getProgramAccounts"), 1);
+        assert_eq!(write.pubkey, account);
+        assert_eq!((write.slot, write.data), (1032, vec![9]));
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn writes_from_several_sources_are_deduplicated() {
+    let harness = Harness::new(2).await;
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&program_id));
+
+    let script = async {
+        let subscription_a = harness.geysers[0].next_subscription().await;
+        let subscription_b = harness.geysers[1].next_subscription().await;
+        subscription_a.send_slot(1000, None, Finalized).await;
+        subscription_b.send_slot(1000, None, Finalized).await;
+
+        // the nodes use different global write versions, the remapped ones agree
+        subscription_a
+            .send_account(1001, account_info(&account, &program_id, 10, vec![1]))
+            .await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!((write.write_version, write.data), (1, vec![1]));
+
+        subscription_b
+            .send_account(1001, account_info(&account, &program_id, 77, vec![1]))
+            .await;
+        subscription_b
+            .send_account(1001, account_info(&account, &program_id, 78, vec![2]))
+            .await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!((write.write_version, write.data), (2, vec![2]));
+
+        subscription_a
+            .send_account(1001, account_info(&account, &program_id, 11, vec![2]))
+            .await;
+        subscription_a
+            .send_account(1002, account_info(&account, &program_id, 12, vec![3]))
+            .await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.slot, write.write_version, write.data),
+            (1002, 1, vec![3])
+        );
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn transactions_from_several_sources_are_deduplicated() {
+    let harness = Harness::new(2).await;
+    let program_id = Pubkey::new_unique();
+    let payer = Pubkey::new_unique();
+    let filter_config = FilterConfig {
+        transaction_filter: Some(TransactionFilterConfig {
+            account_include: vec![program_id.to_string()],
+            account_exclude: vec![],
+            vote: Some(false),
+            failed: None,
+        }),
+        ..program_filter(&program_id)
+    };
+    let (_filter_handle, queues, source) = harness.start(&filter_config);
+
+    let script = async {
+        let subscription_a = harness.geysers[0].next_subscription().await;
+        let subscription_b = harness.geysers[1].next_subscription().await;
+        let request = &subscription_a.request.transactions["client"];
+        assert_eq!(request.account_include, vec![program_id.to_string()]);
+        assert_eq!(request.vote, Some(false));
+
+        subscription_a
+            .send(transaction(1001, 1, &[payer, program_id]))
+            .await;
+        let update = recv(&queues.transactions).await;
+        assert_eq!(
+            (update.slot, update.signature, update.index),
+            (1001, Signature::new(&[1; 64]), 1)
+        );
+        assert_eq!(update.account_keys, vec![payer, program_id]);
+        assert_eq!(update.instructions[0].data, vec![1]);
+        assert_eq!(update.log_messages, vec!["transaction 1".to_owned()]);
+        assert!(!update.is_failed);
+
+        // the other source sends the same transaction, and one that is new
+        subscription_b
+            .send(transaction(1001, 1, &[payer, program_id]))
+            .await;
+        subscription_b
+            .send(transaction(1001, 2, &[payer, program_id]))
+            .await;
+        let update = recv(&queues.transactions).await;
+        assert_eq!(update.signature, Signature::new(&[2; 64]));
+
+        // also once it arrives late on the first source
+        subscription_a
+            .send(transaction(1001, 2, &[payer, program_id]))
+            .await;
+        subscription_a
+            .send(transaction(1002, 3, &[payer, program_id]))
+            .await;
+        let update = recv(&queues.transactions).await;
+        assert_eq!(
+            (update.slot, update.signature),
+            (1002, Signature::new(&[3; 64]))
+        );
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn block_meta_takes_the_parent_from_the_slot_stream() {
+    let harness = Harness::new(2).await;
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&Pubkey::new_unique()));
+
+    let script = async {
+        let subscription_a = harness.geysers[0].next_subscription().await;
+        let subscription_b = harness.geysers[1].next_subscription().await;
+        assert!(subscription_a.request.blocks_meta.contains_key("client"));
+
+        let blockhash = Hash::new_unique();
+        subscription_a.send_slot(1001, Some(999), Processed).await;
+        subscription_a.send(block_meta(1001, &blockhash)).await;
+        let update = recv(&queues.block_metas).await;
+        assert_eq!(
+            (
+                update.slot,
+                update.blockhash,
+                update.block_time,
+                update.block_height,
+                update.parent
+            ),
+            (1001, blockhash, Some(1_700_001_001), Some(901), Some(999))
+        );
+
+        // the other source sends the same block, then one whose slot wasn't seen yet
+        let next_blockhash = Hash::new_unique();
+        subscription_b.send(block_meta(1001, &blockhash)).await;
+        subscription_b.send(block_meta(1002, &next_blockhash)).await;
+        let update = recv(&queues.block_metas).await;
+        assert_eq!(
+            (update.slot, update.blockhash, update.parent),
+            (1002, next_blockhash, None)
+        );
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn compressed_account_data_is_decoded() {
+    let mut harness = Harness::new(2).await;
+    harness.config.grpc_sources[0].account_data_compression = Some(AccountDataCompression::Zstd);
+    harness.config.grpc_sources[1].account_data_compression = Some(AccountDataCompression::Lz4);
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&program_id));
+
+    let script = async {
+        let subscription_a = harness.geysers[0].next_subscription().await;
+        let subscription_b = harness.geysers[1].next_subscription().await;
+        subscription_a.send_slot(1000, None, Finalized).await;
+        subscription_b.send_slot(1000, None, Finalized).await;
+
+        let zstd_data = zstd::encode_all(&[1, 2, 3][..], 0).unwrap();
+        subscription_a
+            .send_account(1001, account_info(&account, &program_id, 10, zstd_data))
+            .await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!((write.write_version, write.data), (1, vec![1, 2, 3]));
+
+        subscription_b
+            .send_account(
+                1001,
+                account_info(&account, &program_id, 77, lz4_compress(&[1, 2, 3])),
+            )
+            .await;
+        subscription_b
+            .send_account(
+                1001,
+                account_info(&account, &program_id, 78, lz4_compress(&[4, 5])),
+            )
+            .await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!((write.write_version, write.data), (2, vec![4, 5]));
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn undecodable_account_data_is_skipped_and_counted() {
+    let mut harness = Harness::new(1).await;
+    harness.config.grpc_sources[0].account_data_compression = Some(AccountDataCompression::Zstd);
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&program_id));
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        subscription.send_slot(1000, None, Finalized).await;
+        subscription
+            .send_account(1001, account_info(&account, &program_id, 10, vec![1, 2, 3]))
+            .await;
+        let zstd_data = zstd::encode_all(&[4][..], 0).unwrap();
+        subscription
+            .send_account(1001, account_info(&account, &program_id, 11, zstd_data))
+            .await;
+
+        // the connection stays up and the next write is delivered
+        let write = recv(&queues.account_writes).await;
+        assert_eq!((write.write_version, write.data), (1, vec![4]));
+        assert!(!subscription.is_closed());
+        let invalid_updates = harness.metrics.register_u64(
+            "grpc_source_fake0_errors_invalid_update".into(),
+            MetricType::Counter,
+        );
+        assert_eq!(invalid_updates.value(), 1);
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn added_program_is_snapshotted_without_reconnecting() {
+    let harness = Harness::new(1).await;
+    let rpc = &harness.rpc;
+    let program_a = Pubkey::new_unique();
+    let program_b = Pubkey::new_unique();
+    let account_a = Pubkey::new_unique();
+    let account_b = Pubkey::new_unique();
+    rpc.set_account(account_a, program_account(program_a, vec![1]));
+    rpc.set_account(account_b, program_account(program_b, vec![2]));
+    let (filter_handle, queues, source) = harness.start(&program_filter(&program_a));
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        subscription.send_slot(1000, None, Finalized).await;
+        rpc.set_slot(1040);
+        subscription.send_slot(1032, None, Finalized).await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!((write.pubkey, write.slot), (account_a, 1040));
+
+        filter_handle.add_program_ids(&[program_b.to_string()]);
+        let request = subscription.next_request().await;
+        assert_eq!(
+            request.accounts["client"].owner,
+            vec![program_a.to_string(), program_b.to_string()]
+        );
+
+        // writes of the new program are dropped until a slot after the next rooted one
+        subscription
+            .send_account(1041, account_info(&account_b, &program_b, 200, vec![3]))
+            .await;
+        subscription.send_slot(1041, None, Finalized).await;
+        subscription
+            .send_account(1042, account_info(&account_b, &program_b, 201, vec![4]))
+            .await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.pubkey, write.slot, write.write_version, write.data),
+            (account_b, 1042, 1, vec![4])
+        );
+
+        // only the new program is snapshotted
+        rpc.set_slot(1080);
+        subscription.send_slot(1073, None, Finalized).await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.pubkey, write.slot, write.write_version, write.data),
+            (account_b, 1080, 0, vec![2])
+        );
+        assert_eq!(rpc.request_count("getProgramAccounts"), 2);
+        assert!(!subscription.is_closed());
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn slot_gap_triggers_resnapshot() {
+    let harness = Harness::new(1).await;
+    let rpc = &harness.rpc;
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    rpc.set_account(account, program_account(program_id, vec![9]));
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&program_id));
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        subscription.send_slot(1000, None, Finalized).await;
+        rpc.set_slot(1001);
+        subscription.send_slot(1032, None, Finalized).await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!((write.slot, write.data), (1001, vec![9]));
+
+        // slot 1034 was never reported, writes to it may have been missed
+        subscription.send_slot(1033, Some(1032), Processed).await;
+        subscription.send_slot(1035, Some(1034), Processed).await;
+        rpc.set_account(account, program_account(program_id, vec![8]));
+        rpc.set_slot(1034);
+
+        // the new snapshot needs to cover the missing slot
+        subscription.send_slot(1064, None, Finalized).await;
+        subscription.send_ping().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(rpc.request_count("getProgramAccounts"), 1);
+
+        subscription.send_slot(1065, None, Finalized).await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(rpc.request_count("getProgramAccounts"), 2);
+        assert_eq!((write.slot, write.data), (1034, vec![8]));
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn account_data_is_sliced() {
+    let harness = Harness::new(1).await;
+    let rpc = &harness.rpc;
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    let data_slice = DataSlice {
+        offset: 1,
+        length: 2,
+    };
+    let filter_config = FilterConfig {
+        data_slice: Some(data_slice),
+        ..program_filter(&program_id)
+    };
+    rpc.set_account(account, program_account(program_id, vec![1, 2, 3, 4]));
+    let (_filter_handle, queues, source) = harness.start(&filter_config);
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        subscription.send_slot(1000, None, Finalized).await;
+        rpc.set_slot(1001);
+        subscription.send_slot(1032, None, Finalized).await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.data, write.data_slice),
+            (vec![2, 3], Some(data_slice))
+        );
+
+        subscription
+            .send_account(
+                1033,
+                account_info(&account, &program_id, 100, vec![5, 6, 7, 8]),
+            )
+            .await;
+        let write = recv(&queues.account_writes).await;
+        assert_eq!(
+            (write.data, write.data_slice),
+            (vec![6, 7], Some(data_slice))
+        );
+    };
+    run(source, script).await;
+}
+
+#[tokio::test]
+async fn cancelling_stops_the_source() {
+    let harness = Harness::new(1).await;
+    let (_filter_handle, queues, source) = harness.start(&program_filter(&Pubkey::new_unique()));
+    tokio::pin!(source);
+
+    let subscription = tokio::select! {
+        _ = &mut source => panic!("source stopped"),
+        subscription = harness.geysers[0].next_subscription() => subscription,
+    };
+    subscription.send_slot(1000, None, Finalized).await;
+
+    harness.shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(10), source)
+        .await
+        .expect("source stopped in time")
+        .expect("source stopped without an error");
+    // the queue senders were dropped with the source
+    assert!(queues.account_writes.recv().await.is_err());
+}
+
+#[tokio::test]
+async fn removed_account_stops_receiving_writes() {
+    let harness = Harness::new(1).await;
+    let owner = Pubkey::new_unique();
+    let kept = Pubkey::new_unique();
+    let removed = Pubkey::new_unique();
+    let filter_config = FilterConfig {
+        program_ids: vec![],
+        account_ids: vec![kept.to_string(), removed.to_string()],
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
+    };
+    let (filter_handle, queues, source) = harness.start(&filter_config);
+
+    let script = async {
+        let subscription = harness.geysers[0].next_subscription().await;
+        subscription.send_slot(1000, None, Finalized).await;
+        subscription
+            .send_account(1001, account_info(&removed, &owner, 10, vec![1]))
+            .await;
+        assert_eq!(recv(&queues.account_writes).await.pubkey, removed);
+
+        // the filters can't change beyond the ids
+        let mut sliced = filter_handle.filter_config();
+        sliced.data_slice = Some(DataSlice {
+            offset: 0,
+            length: 1,
+        });
+        assert!(filter_handle.set_filter_config(sliced).is_err());
+
+        filter_handle.remove_account_ids(&[removed.to_string()]);
+        let request = subscription.next_request().await;
+        assert_eq!(request.accounts["client"].account, vec![kept.to_string()]);
+
+        // writes the plugin sent before applying the request are dropped
+        subscription
+            .send_account(1001, account_info(&removed, &owner, 11, vec![2]))
+            .await;
+        subscription
+            .send_account(1001, account_info(&kept, &owner, 12, vec![3]))
+            .await;
+        assert_eq!(recv(&queues.account_writes).await.pubkey, kept);
+        assert!(queues.account_writes.is_empty());
+    };
+    run(source, script).await;
+}
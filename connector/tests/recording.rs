@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use mango_feeds_connector::{
+    chain_data::SlotStatus,
+    recording_sink, replay_source,
+    shutdown::CancellationToken,
+    solana_sdk::pubkey::Pubkey,
+    test_support::{metrics, source_config, RpcStub},
+    AccountWrite, DataSlice, FilterConfig, QueuePolicy, RecordingConfig, ReplaySourceConfig,
+    SlotUpdate,
+};
+
+fn account_write(slot: u64, data: Vec<u8>, data_slice: Option<DataSlice>) -> AccountWrite {
+    AccountWrite {
+        pubkey: Pubkey::new_unique(),
+        slot,
+        write_version: slot * 10,
+        lamports: 5,
+        owner: Pubkey::new_unique(),
+        executable: false,
+        rent_epoch: 3,
+        data,
+        data_slice,
+        is_selected: true,
+    }
+}
+
+#[tokio::test]
+async fn replay_returns_what_was_recorded() {
+    let path = std::env::temp_dir().join(format!("connector-recording-{}", std::process::id()));
+    let path = path.to_str().unwrap().to_owned();
+
+    let writes = vec![
+        account_write(10, vec![1, 2, 3], None),
+        account_write(
+            11,
+            vec![4, 5],
+            Some(DataSlice {
+                offset: 8,
+                length: 2,
+            }),
+        ),
+    ];
+    let slots = vec![
+        (10, None, SlotStatus::Processed),
+        (11, Some(10), SlotStatus::Confirmed),
+        (12, Some(11), SlotStatus::Rooted),
+    ];
+
+    // record, the recording ends once the senders are dropped
+    let (account_write_sender, account_write_receiver) = async_channel::unbounded();
+    let (slot_sender, slot_receiver) = async_channel::unbounded();
+    let (recorded_account_write_sender, recorded_slot_sender) = recording_sink::init(
+        &RecordingConfig { path: path.clone() },
+        account_write_sender,
+        slot_sender,
+        &QueuePolicy::Unbounded,
+        metrics(),
+    )
+    .unwrap();
+    for write in &writes {
+        recorded_account_write_sender
+            .send(write.clone())
+            .await
+            .unwrap();
+    }
+    for &(slot, parent, status) in &slots {
+        recorded_slot_sender
+            .send(SlotUpdate {
+                slot,
+                parent,
+                status,
+            })
+            .await
+            .unwrap();
+    }
+    drop(recorded_account_write_sender);
+    drop(recorded_slot_sender);
+
+    // everything is passed on, and the recording task closes the channels when done
+    let forwarded: Vec<AccountWrite> = tokio::time::timeout(Duration::from_secs(10), async {
+        let mut forwarded = vec![];
+        while let Ok(write) = account_write_receiver.recv().await {
+            forwarded.push(write);
+        }
+        while slot_receiver.recv().await.is_ok() {}
+        forwarded
+    })
+    .await
+    .expect("recording finished in time");
+    assert_eq!(forwarded, writes);
+
+    // replay
+    let mut config = source_config(&[], &RpcStub::start());
+    config.replay = Some(ReplaySourceConfig {
+        path: path.clone(),
+        speed: None,
+    });
+    let filter_config = FilterConfig {
+        program_ids: vec![],
+        account_ids: vec![],
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
+    };
+    let (account_write_sender, account_write_receiver) = async_channel::unbounded();
+    let (slot_sender, slot_receiver) = async_channel::unbounded();
+    let (_filter_handle, replay) = replay_source::process_events(
+        &config,
+        &filter_config,
+        account_write_sender,
+        slot_sender,
+        None,
+        None,
+        metrics(),
+        CancellationToken::new(),
+    );
+    replay.await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut replayed = vec![];
+    while let Ok(write) = account_write_receiver.try_recv() {
+        replayed.push(write);
+    }
+    assert_eq!(replayed, writes);
+    let mut replayed = vec![];
+    while let Ok(update) = slot_receiver.try_recv() {
+        replayed.push((update.slot, update.parent, update.status));
+    }
+    assert_eq!(replayed, slots);
+}
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use mango_feeds_connector::{
+    rpc_connection::RpcConnection,
+    snapshot::get_snapshot,
+    solana_sdk::{account::Account, pubkey::Pubkey},
+    test_support::RpcStub,
+    AccountFilter, FilterConfig, RpcConnectionConfig, SnapshotSourceConfig,
+};
+
+fn account(owner: Pubkey, data: Vec<u8>) -> Account {
+    Account {
+        lamports: 1,
+        data,
+        owner,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+#[tokio::test]
+async fn large_account_lists_are_requested_in_chunks() {
+    let rpc = RpcStub::start();
+    rpc.set_slot(1000);
+    let owner = Pubkey::new_unique();
+    let pubkeys: Vec<Pubkey> = (0..250).map(|_| Pubkey::new_unique()).collect();
+    for (i, pubkey) in pubkeys.iter().enumerate() {
+        // leave some accounts missing
+        if i % 10 != 0 {
+            rpc.set_account(
+                *pubkey,
+                Account {
+                    lamports: 1,
+                    data: vec![i as u8],
+                    owner,
+                    executable: false,
+                    rent_epoch: 0,
+                },
+            );
+        }
+    }
+    let filter_config = FilterConfig {
+        program_ids: vec![],
+        account_ids: pubkeys.iter().map(|p| p.to_string()).collect(),
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
+    };
+
+    let (slot, accounts) = get_snapshot(rpc.connection(), &filter_config)
+        .await
+        .unwrap();
+    assert_eq!(slot, 1000);
+    assert_eq!(rpc.request_count("getMultipleAccounts"), 3);
+    assert_eq!(accounts.len(), 250);
+    for (i, (pubkey, (key, account))) in pubkeys.iter().zip(accounts).enumerate() {
+        assert_eq!(key, pubkey.to_string());
+        let account: Option<Account> = account.map(|a| a.decode().unwrap());
+        assert_eq!(
+            account.map(|a| a.data),
+            (i % 10 != 0).then(|| vec![i as u8])
+        );
+    }
+}
+
+#[tokio::test]
+async fn snapshots_send_the_auth_header() {
+    let rpc = RpcStub::start();
+    rpc.set_slot(1000);
+    rpc.require_header("authorization", "Bearer secret-token");
+    let pubkey = Pubkey::new_unique();
+    rpc.set_account(
+        pubkey,
+        Account {
+            lamports: 1,
+            data: vec![1],
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+    let filter_config = FilterConfig {
+        program_ids: vec![],
+        account_ids: vec![pubkey.to_string()],
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
+    };
+
+    assert!(get_snapshot(rpc.connection(), &filter_config)
+        .await
+        .is_err());
+
+    let config = SnapshotSourceConfig {
+        rpc_http_url: format!("{}/secret-path", rpc.url()),
+        rpc_http_connection: RpcConnectionConfig {
+            token: Some("secret-token".into()),
+            ..RpcConnectionConfig::default()
+        },
+        resnapshot_interval_secs: None,
+    };
+    let connection = RpcConnection::new(&rpc.url(), &config.rpc_http_connection).unwrap();
+    let (slot, accounts) = get_snapshot(connection, &filter_config).await.unwrap();
+    assert_eq!(slot, 1000);
+    assert_eq!(accounts.len(), 1);
+
+    // neither the token nor the url path show up in logs
+    let logged = format!("{:?}", config);
+    assert!(!logged.contains("secret"), "{}", logged);
+}
+
+#[tokio::test]
+async fn later_chunks_are_pinned_to_the_slot_of_the_first() {
+    let rpc = RpcStub::start();
+    rpc.set_slot(1000);
+    let owner = Pubkey::new_unique();
+    let pubkeys: Vec<Pubkey> = (0..250).map(|_| Pubkey::new_unique()).collect();
+    for pubkey in &pubkeys {
+        rpc.set_account(*pubkey, account(owner, vec![1]));
+    }
+    let filter_config = FilterConfig {
+        program_ids: vec![],
+        account_ids: pubkeys.iter().map(|p| p.to_string()).collect(),
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
+    };
+
+    // a chunk answered by a node behind the first one fails the snapshot
+    rpc.queue_slots(&[1000]);
+    rpc.set_slot(990);
+    assert!(get_snapshot(rpc.connection(), &filter_config)
+        .await
+        .is_err());
+    // the other chunk was requested along with the failed one
+    while rpc.request_count("getMultipleAccounts") < 3 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // chunks from nodes ahead are fine, the snapshot is at the oldest slot
+    rpc.queue_slots(&[1002]);
+    rpc.set_slot(1005);
+    let (slot, accounts) = get_snapshot(rpc.connection(), &filter_config)
+        .await
+        .unwrap();
+    assert_eq!(slot, 1002);
+    assert_eq!(accounts.len(), 250);
+}
+
+#[tokio::test]
+async fn program_snapshots_only_hold_accounts_matching_the_filters() {
+    let rpc = RpcStub::start();
+    rpc.set_slot(1000);
+    let program_id = Pubkey::new_unique();
+    let matching = Pubkey::new_unique();
+    rpc.set_account(matching, account(program_id, vec![1, 2, 3, 4]));
+    rpc.set_account(Pubkey::new_unique(), account(program_id, vec![9, 2, 3, 4]));
+    rpc.set_account(Pubkey::new_unique(), account(program_id, vec![1, 2, 3]));
+    let filter_config = FilterConfig {
+        program_ids: vec![program_id.to_string()],
+        account_ids: vec![],
+        account_filters: vec![
+            AccountFilter::Memcmp {
+                offset: 0,
+                bytes: bs58::encode([1, 2]).into_string(),
+            },
+            AccountFilter::DataSize(4),
+        ],
+        transaction_filter: None,
+        data_slice: None,
+    };
+
+    let (slot, accounts) = get_snapshot(rpc.connection(), &filter_config)
+        .await
+        .unwrap();
+    assert_eq!(slot, 1000);
+    let keys: Vec<String> = accounts.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![matching.to_string()]);
+}
@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+use mango_feeds_connector::{
+    shutdown::CancellationToken,
+    solana_sdk::{account::Account, pubkey::Pubkey},
+    test_support::{metrics, source_config, PubsubStub, RpcStub},
+    websocket_source, AccountWrite, FilterConfig,
+};
+
+async fn recv(receiver: &async_channel::Receiver<AccountWrite>) -> AccountWrite {
+    tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+        .await
+        .expect("received in time")
+        .expect("channel open")
+}
+
+#[tokio::test]
+async fn reconnects_with_backoff_and_a_new_snapshot() {
+    let rpc = RpcStub::start();
+    let pubsub = PubsubStub::start();
+    let mut config = source_config(&[], &rpc);
+    config.rpc_ws_url = pubsub.url();
+    let program_id = Pubkey::new_unique();
+    let account = Pubkey::new_unique();
+    let set_account = |data: Vec<u8>| {
+        rpc.set_account(
+            account,
+            Account {
+                lamports: 1,
+                data,
+                owner: program_id,
+                executable: false,
+                rent_epoch: 0,
+            },
+        )
+    };
+    set_account(vec![1]);
+    rpc.set_slot(100);
+    let filter_config = FilterConfig {
+        program_ids: vec![program_id.to_string()],
+        account_ids: vec![],
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
+    };
+    let (account_write_sender, account_writes) = async_channel::unbounded();
+    let (slot_sender, _slots) = async_channel::unbounded();
+    let shutdown = CancellationToken::new();
+    let source = websocket_source::process_events(
+        &config,
+        &filter_config,
+        account_write_sender,
+        slot_sender,
+        metrics(),
+        shutdown.clone(),
+    );
+    tokio::pin!(source);
+
+    let script = async {
+        let connection = pubsub.next_connection().await;
+        let mut subscriptions = vec![
+            connection.next_subscription().await,
+            connection.next_subscription().await,
+        ];
+        subscriptions.sort();
+        assert_eq!(
+            subscriptions,
+            vec!["programSubscribe", "slotsUpdatesSubscribe"]
+        );
+        let write = recv(&account_writes).await;
+        assert_eq!((write.slot, write.data), (100, vec![1]));
+
+        // every connection starts with a new snapshot
+        set_account(vec![2]);
+        rpc.set_slot(110);
+        let closed_at = Instant::now();
+        drop(connection);
+        let connection = pubsub.next_connection().await;
+        assert!(closed_at.elapsed() >= Duration::from_secs(1));
+        let write = recv(&account_writes).await;
+        assert_eq!((write.slot, write.data), (110, vec![2]));
+
+        // the wait before reconnecting doubles
+        let closed_at = Instant::now();
+        drop(connection);
+        let _connection = pubsub.next_connection().await;
+        assert!(closed_at.elapsed() >= Duration::from_secs(2));
+        recv(&account_writes).await;
+        assert_eq!(rpc.request_count("getProgramAccounts"), 3);
+    };
+    tokio::select! {
+        result = &mut source => panic!("source stopped: {:?}", result),
+        _ = script => {}
+    }
+
+    shutdown.cancel();
+    assert!(source.await.is_ok());
+}
@@ -1,20 +1,31 @@
 use crate::{
-    chain_data::{AccountData, ChainData, SlotData},
-    AccountWrite, SlotUpdate,
+    chain_data::{AccountData, ChainData, HistoryRetention, SlotData},
+    queue,
+    shutdown::CancellationToken,
+    AccountWrite, QueuePolicy, SlotUpdate,
 };
+use log::*;
 use solana_sdk::{account::WritableAccount, clock::Epoch};
 use std::sync::{Arc, RwLock};
 
 pub async fn init(
     chain_data: Arc<RwLock<ChainData>>,
+    queue_policy: &QueuePolicy,
+    history_retention: Option<HistoryRetention>,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<(
     async_channel::Sender<AccountWrite>,
     async_channel::Sender<SlotUpdate>,
 )> {
+    chain_data
+        .write()
+        .unwrap()
+        .set_history_retention(history_retention);
+
     let (account_write_queue_sender, account_write_queue_receiver) =
-        async_channel::unbounded::<AccountWrite>();
+        queue::channel::<AccountWrite>(queue_policy);
 
-    let (slot_queue_sender, slot_queue_receiver) = async_channel::unbounded::<SlotUpdate>();
+    let (slot_queue_sender, slot_queue_receiver) = queue::channel::<SlotUpdate>(queue_policy);
 
     // update handling thread, reads both slots and account updates
     tokio::spawn(async move {
@@ -27,6 +38,7 @@ pub async fn init(
                         AccountData {
                             slot: account_write.slot,
                             write_version: account_write.write_version,
+                            data_slice: account_write.data_slice,
                             account: WritableAccount::create(
                                 account_write.lamports,
                                 account_write.data.clone(),
@@ -46,6 +58,10 @@ pub async fn init(
                         chain: 0,
                     });
                 }
+                _ = shutdown.cancelled() => {
+                    info!("shutting down memory target...");
+                    break;
+                }
             }
         }
     });
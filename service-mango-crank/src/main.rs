@@ -14,17 +14,16 @@ use log::*;
 use mango_v4_client::{Client, MangoGroupContext, TransactionBuilderConfig};
 use solana_client::nonblocking::rpc_client::RpcClient;
 use std::{
-    collections::HashSet,
-    convert::TryFrom,
-    fs::File,
-    io::Read,
-    str::FromStr,
-    sync::{atomic::AtomicBool, Arc},
+    collections::HashSet, convert::TryFrom, fs::File, io::Read, str::FromStr, sync::Arc,
     time::Duration,
 };
 
 use mango_feeds_lib::FilterConfig;
-use mango_feeds_lib::{grpc_plugin_source, metrics, websocket_source, MetricsConfig, SourceConfig};
+use mango_feeds_lib::{
+    grpc_plugin_source, metrics, secrets,
+    shutdown::{self, CancellationToken},
+    websocket_source, MetricsConfig, SourceConfig,
+};
 use serde::Deserialize;
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
@@ -40,8 +39,6 @@ pub struct Config {
 async fn main() -> anyhow::Result<()> {
     solana_logger::setup_with_default("info");
 
-    let exit: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
         error!("Please enter a config file path argument.");
@@ -55,6 +52,9 @@ async fn main() -> anyhow::Result<()> {
         toml::from_str(&contents).unwrap()
     };
 
+    let shutdown = CancellationToken::new();
+    shutdown::cancel_on_signal(&shutdown, shutdown::DEFAULT_DEADLINE);
+
     let rpc_client = Arc::new(RpcClient::new(config.rpc_http_url.clone()));
 
     let blockhash = blockhash_poller::init(rpc_client.clone()).await;
@@ -124,7 +124,10 @@ async fn main() -> anyhow::Result<()> {
             perp_queue_pks.clone(),
             serum_queue_pks.clone(),
             group_pk,
+            &config.source.queue_policy,
+            config.source.history_retention,
             metrics_tx.clone(),
+            shutdown.clone(),
         )
         .expect("init transaction builder");
 
@@ -141,7 +144,7 @@ async fn main() -> anyhow::Result<()> {
             .source
             .grpc_sources
             .iter()
-            .map(|c| c.connection_string.clone())
+            .map(|c| secrets::redact_url(&c.connection_string))
             .collect::<String>()
     );
     let use_geyser = true;
@@ -154,25 +157,32 @@ async fn main() -> anyhow::Result<()> {
     let filter_config = FilterConfig {
         program_ids: vec![],
         account_ids: all_queue_pks.iter().map(|pk| pk.to_string()).collect(),
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
     };
     if use_geyser {
-        grpc_plugin_source::process_events(
+        let (_filter_handle, source) = grpc_plugin_source::process_events(
             &config.source,
             &filter_config,
             account_write_queue_sender,
             slot_queue_sender,
+            None,
+            None,
             metrics_tx.clone(),
-            exit.clone(),
-        )
-        .await;
+            shutdown.clone(),
+        );
+        source.await?;
     } else {
         websocket_source::process_events(
             &config.source,
             &filter_config,
             account_write_queue_sender,
             slot_queue_sender,
+            metrics_tx.clone(),
+            shutdown.clone(),
         )
-        .await;
+        .await?;
     }
 
     Ok(())
@@ -7,7 +7,7 @@ use std::{
 use async_channel::Sender;
 use async_trait::async_trait;
 use log::*;
-use mango_feeds_lib::{account_write_filter::AccountWriteSink, chain_data::AccountData};
+use mango_feeds_lib::{account_write_filter::AccountWriteSink, chain_data::AccountState};
 use solana_sdk::{
     account::ReadableAccount,
     instruction::{AccountMeta, Instruction},
@@ -42,8 +42,14 @@ impl MangoV4PerpCrankSink {
 
 #[async_trait]
 impl AccountWriteSink for MangoV4PerpCrankSink {
-    async fn process(&self, pk: &Pubkey, account: &AccountData) -> Result<(), String> {
-        let account = &account.account;
+    async fn process(&self, pk: &Pubkey, account: AccountState<'_>) -> Result<(), String> {
+        let account = match account {
+            AccountState::Live(write) => &write.account,
+            AccountState::Deleted(write) => {
+                info!("event queue {} was closed in slot {}", pk, write.slot);
+                return Ok(());
+            }
+        };
         let event_queue: mango_v4::state::EventQueue =
             mango_v4::state::EventQueue::try_deserialize(account.data().borrow_mut()).unwrap();
 
@@ -7,7 +7,7 @@ use async_channel::Sender;
 use async_trait::async_trait;
 use log::*;
 use mango_feeds_lib::{
-    account_write_filter::AccountWriteSink, chain_data::AccountData, serum::SerumEventQueueHeader,
+    account_write_filter::AccountWriteSink, chain_data::AccountState, serum::SerumEventQueueHeader,
 };
 use serum_dex::{instruction::MarketInstruction, state::EventView};
 use solana_sdk::{
@@ -34,8 +34,14 @@ impl OpenbookCrankSink {
 
 #[async_trait]
 impl AccountWriteSink for OpenbookCrankSink {
-    async fn process(&self, pk: &Pubkey, account: &AccountData) -> Result<(), String> {
-        let account = &account.account;
+    async fn process(&self, pk: &Pubkey, account: AccountState<'_>) -> Result<(), String> {
+        let account = match account {
+            AccountState::Live(write) => &write.account,
+            AccountState::Deleted(write) => {
+                info!("event queue {} was closed in slot {}", pk, write.slot);
+                return Ok(());
+            }
+        };
 
         let inner_data = &account.data()[5..&account.data().len() - 7];
         let header_span = std::mem::size_of::<SerumEventQueueHeader>();
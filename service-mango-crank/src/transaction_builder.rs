@@ -1,7 +1,9 @@
 use mango_feeds_lib::{
     account_write_filter::{self, AccountWriteRoute},
+    chain_data::HistoryRetention,
     metrics::Metrics,
-    AccountWrite, SlotUpdate,
+    shutdown::CancellationToken,
+    AccountWrite, QueuePolicy, SlotUpdate,
 };
 
 use solana_sdk::{instruction::Instruction, pubkey::Pubkey};
@@ -16,7 +18,10 @@ pub fn init(
     perp_queue_pks: Vec<(Pubkey, Pubkey)>,
     serum_queue_pks: Vec<(Pubkey, Pubkey)>,
     group_pk: Pubkey,
+    queue_policy: &QueuePolicy,
+    history_retention: Option<HistoryRetention>,
     metrics_sender: Metrics,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<(
     async_channel::Sender<AccountWrite>,
     async_channel::Sender<SlotUpdate>,
@@ -28,6 +33,7 @@ pub fn init(
     let routes = vec![
         AccountWriteRoute {
             matched_pubkeys: serum_queue_pks.iter().map(|(_, evq_pk)| *evq_pk).collect(),
+            matchers: vec![],
             sink: Arc::new(OpenbookCrankSink::new(
                 serum_queue_pks,
                 instruction_sender.clone(),
@@ -36,6 +42,7 @@ pub fn init(
         },
         AccountWriteRoute {
             matched_pubkeys: perp_queue_pks.iter().map(|(_, evq_pk)| *evq_pk).collect(),
+            matchers: vec![],
             sink: Arc::new(MangoV4PerpCrankSink::new(
                 perp_queue_pks,
                 group_pk,
@@ -45,8 +52,13 @@ pub fn init(
         },
     ];
 
-    let (account_write_queue_sender, slot_queue_sender) =
-        account_write_filter::init(routes, metrics_sender)?;
+    let (account_write_queue_sender, slot_queue_sender) = account_write_filter::init(
+        routes,
+        queue_policy,
+        history_retention,
+        metrics_sender,
+        shutdown,
+    )?;
 
     Ok((
         account_write_queue_sender,
@@ -1,9 +1,14 @@
 use log::*;
 use mango_feeds_lib::{
-    chain_data::{AccountData, ChainData, ChainDataMetrics, SlotData},
+    chain_data::{
+        AccountData, AccountState, ChainData, ChainDataMetrics, ChainDataPersistence,
+        HistoryRetention, SlotData,
+    },
     metrics::{MetricType, Metrics},
+    queue,
     serum::SerumEventQueueHeader,
-    AccountWrite, MarketConfig, SlotUpdate,
+    shutdown::CancellationToken,
+    AccountWrite, ChainDataPersistenceConfig, MarketConfig, QueuePolicy, SlotUpdate,
 };
 use solana_sdk::{
     account::{ReadableAccount, WritableAccount},
@@ -15,10 +20,6 @@ use std::{
     cmp::max,
     collections::{HashMap, HashSet},
     iter::FromIterator,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
 };
 
 use crate::metrics::MetricU64;
@@ -396,8 +397,11 @@ fn publish_changes_serum(
 pub async fn init(
     perp_market_configs: Vec<(Pubkey, MarketConfig)>,
     spot_market_configs: Vec<(Pubkey, MarketConfig)>,
+    persistence_config: Option<ChainDataPersistenceConfig>,
+    queue_policy: &QueuePolicy,
+    history_retention: Option<HistoryRetention>,
     metrics_sender: Metrics,
-    exit: Arc<AtomicBool>,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<(
     async_channel::Sender<AccountWrite>,
     async_channel::Sender<SlotUpdate>,
@@ -422,11 +426,11 @@ pub async fn init(
 
     // The actual message may want to also contain a retry count, if it self-reinserts on failure?
     let (account_write_queue_sender, account_write_queue_receiver) =
-        async_channel::unbounded::<AccountWrite>();
+        queue::channel::<AccountWrite>(queue_policy);
 
     // Slot updates flowing from the outside into the single processing thread. From
     // there they'll flow into the postgres sending thread.
-    let (slot_queue_sender, slot_queue_receiver) = async_channel::unbounded::<SlotUpdate>();
+    let (slot_queue_sender, slot_queue_receiver) = queue::channel::<SlotUpdate>(queue_policy);
 
     // Fill updates can be consumed by client connections, they contain all fills for all markets
     let (fill_update_sender, fill_update_receiver) =
@@ -434,13 +438,24 @@ pub async fn init(
 
     let account_write_queue_receiver_c = account_write_queue_receiver;
 
-    let mut chain_cache = ChainData::new();
+    let mut chain_persistence =
+        persistence_config.map(|config| ChainDataPersistence::new(&config, &metrics_sender));
+    // warm-start from the saved rooted state, the live snapshot will replace it
+    let mut chain_cache = match &chain_persistence {
+        Some(persistence) => persistence.load(),
+        None => ChainData::new(),
+    };
+    chain_cache.set_history_retention(history_retention);
+    chain_cache.enable_reorg_events();
     let mut chain_data_metrics = ChainDataMetrics::new(&metrics_sender);
     let mut perp_events_cache: HashMap<String, EventQueueEvents> = HashMap::new();
     let mut serum_events_cache: HashMap<String, Vec<serum_dex::state::Event>> = HashMap::new();
     let mut seq_num_cache = HashMap::new();
     let mut head_cache = HashMap::new();
-    let mut last_evq_versions = HashMap::<String, (u64, u64)>::new();
+    // Pubkeys whose live write changed since the event queues were last processed,
+    // starting out with everything that was loaded from disk
+    let mut pending_pubkeys: HashSet<Pubkey> =
+        chain_cache.iter_accounts().map(|(pk, _)| *pk).collect();
 
     let all_market_configs = [perp_market_configs.clone(), spot_market_configs.clone()].concat();
     let perp_queue_pks: Vec<Pubkey> = perp_market_configs
@@ -457,8 +472,11 @@ pub async fn init(
     // update handling thread, reads both sloths and account updates
     tokio::spawn(async move {
         loop {
-            if exit.load(Ordering::Relaxed) {
+            if shutdown.is_cancelled() {
                 warn!("shutting down fill_event_filter...");
+                if let Some(persistence) = chain_persistence.as_mut() {
+                    persistence.save(&chain_cache).await;
+                }
                 break;
             }
             tokio::select! {
@@ -467,11 +485,12 @@ pub async fn init(
                         continue;
                     }
 
-                    chain_cache.update_account(
+                    let changed = chain_cache.update_account(
                         account_write.pubkey,
                         AccountData {
                             slot: account_write.slot,
                             write_version: account_write.write_version,
+                            data_slice: account_write.data_slice,
                             account: WritableAccount::create(
                                 account_write.lamports,
                                 account_write.data.clone(),
@@ -481,15 +500,27 @@ pub async fn init(
                             ),
                         },
                     );
+                    if changed {
+                        pending_pubkeys.insert(account_write.pubkey);
+                    }
                 }
                 Ok(slot_update) = slot_queue_receiver.recv() => {
-                    chain_cache.update_slot(SlotData {
+                    let changed = chain_cache.update_slot(SlotData {
                         slot: slot_update.slot,
                         parent: slot_update.parent,
                         status: slot_update.status,
                         chain: 0,
                     });
-
+                    pending_pubkeys.extend(changed);
+                    for reorg in chain_cache.take_reorg_events() {
+                        info!(
+                            "reorg from slot {} to {}, common ancestor {}, {} accounts changed",
+                            reorg.old_tip,
+                            reorg.new_tip,
+                            reorg.common_ancestor,
+                            reorg.accounts.len()
+                        );
+                    }
                 }
                 Err(e) = slot_queue_receiver.recv() => {
                     warn!("slot update channel err {:?}", e);
@@ -497,35 +528,36 @@ pub async fn init(
                 Err(e) = account_write_queue_receiver_c.recv() => {
                     warn!("write update channel err {:?}", e);
                 }
+                _ = shutdown.cancelled() => continue,
             }
 
             chain_data_metrics.report(&chain_cache);
+            if let Some(persistence) = chain_persistence.as_mut() {
+                persistence.save_if_due(&chain_cache);
+            }
 
             for mkt in all_market_configs.iter() {
                 let evq_pk = mkt.1.event_queue;
                 let evq_pk_string = evq_pk.to_string();
-                let last_evq_version = last_evq_versions
-                    .get(&mkt.1.event_queue.to_string())
-                    .unwrap_or(&(0, 0));
-
-                match chain_cache.account(&evq_pk) {
-                    Ok(account_info) => {
-                        // only process if the account state changed
-                        let evq_version = (account_info.slot, account_info.write_version);
-                        if evq_version == *last_evq_version {
-                            continue;
-                        }
-                        if evq_version.0 < last_evq_version.0 {
-                            debug!("evq version slot was old");
-                            continue;
-                        }
-                        if evq_version.0 == last_evq_version.0 && evq_version.1 < last_evq_version.1
-                        {
-                            info!("evq version slot was same and write version was old");
-                            continue;
-                        }
-                        last_evq_versions.insert(evq_pk_string.clone(), evq_version);
 
+                // only process if the account state changed
+                if !pending_pubkeys.contains(&evq_pk) {
+                    continue;
+                }
+
+                match chain_cache.account_state(&evq_pk) {
+                    Ok(AccountState::Deleted(account_info)) => {
+                        // the queue is gone, a recreated one starts without history
+                        info!(
+                            "event queue {} was closed in slot {}",
+                            evq_pk_string, account_info.slot
+                        );
+                        seq_num_cache.remove(&evq_pk_string);
+                        head_cache.remove(&evq_pk_string);
+                        perp_events_cache.remove(&evq_pk_string);
+                        serum_events_cache.remove(&evq_pk_string);
+                    }
+                    Ok(AccountState::Live(account_info)) => {
                         let account = &account_info.account;
                         let is_perp = mango_v4::check_id(account.owner());
                         if is_perp {
@@ -613,6 +645,7 @@ pub async fn init(
                     Err(_) => debug!("chain_cache could not find {}", mkt.1.event_queue),
                 }
             }
+            pending_pubkeys.clear();
         }
     });
 
@@ -8,21 +8,17 @@ use native_tls::{Certificate, Identity, TlsConnector};
 use postgres_native_tls::MakeTlsConnector;
 use postgres_query::Caching;
 use service_mango_fills::*;
-use std::{
-    env, fs,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
-};
+use std::{env, fs, time::Duration};
+use tokio::task::JoinHandle;
 use tokio_postgres::Client;
 
+/// Keep a postgres connection up until `fill_update_queue` is closed and empty, so that
+/// the queued updates can still be written after a disconnect during shutdown
 async fn postgres_connection(
     config: &PostgresConfig,
     metric_retries: MetricU64,
     metric_live: MetricU64,
-    exit: Arc<AtomicBool>,
+    fill_update_queue: async_channel::Receiver<FillUpdate>,
 ) -> anyhow::Result<async_channel::Receiver<Option<tokio_postgres::Client>>> {
     let (tx, rx) = async_channel::unbounded();
 
@@ -80,8 +76,8 @@ async fn postgres_connection(
     let mut metric_live = metric_live;
     tokio::spawn(async move {
         loop {
-            // don't acquire a new connection if we're shutting down
-            if exit.load(Ordering::Relaxed) {
+            // don't acquire a new connection once everything was written
+            if fill_update_queue.is_closed() && fill_update_queue.is_empty() {
                 warn!("shutting down fill_event_postgres_target...");
                 break;
             }
@@ -103,7 +99,10 @@ async fn postgres_connection(
                 }
             };
 
-            tx.send(Some(client)).await.expect("send success");
+            if tx.send(Some(client)).await.is_err() {
+                // the worker is done
+                break;
+            }
             metric_live.increment();
 
             let result = connection.await;
@@ -111,7 +110,9 @@ async fn postgres_connection(
             metric_retries.increment();
             metric_live.decrement();
 
-            tx.send(None).await.expect("send success");
+            if tx.send(None).await.is_err() {
+                break;
+            }
             warn!("postgres connection error: {:?}", result);
             tokio::time::sleep(Duration::from_secs(config.retry_connection_sleep_secs)).await;
         }
@@ -124,12 +125,14 @@ async fn update_postgres_client<'a>(
     client: &'a mut Option<postgres_query::Caching<tokio_postgres::Client>>,
     rx: &async_channel::Receiver<Option<tokio_postgres::Client>>,
     config: &PostgresConfig,
-) -> &'a postgres_query::Caching<tokio_postgres::Client> {
+) -> anyhow::Result<&'a postgres_query::Caching<tokio_postgres::Client>> {
     // get the most recent client, waiting if there's a disconnect
     while !rx.is_empty() || client.is_none() {
         tokio::select! {
             client_raw_opt = rx.recv() => {
-                *client = client_raw_opt.expect("not closed").map(postgres_query::Caching::new);
+                let client_raw_opt = client_raw_opt
+                    .map_err(|_| anyhow::anyhow!("postgres connection task stopped"))?;
+                *client = client_raw_opt.map(postgres_query::Caching::new);
             },
             _ = tokio::time::sleep(Duration::from_secs(config.fatal_connection_timeout_secs)) => {
                 error!("waited too long for new postgres client");
@@ -137,7 +140,7 @@ async fn update_postgres_client<'a>(
             },
         }
     }
-    client.as_ref().expect("must contain value")
+    Ok(client.as_ref().expect("must contain value"))
 }
 
 async fn process_update(client: &Caching<Client>, update: &FillUpdate) -> anyhow::Result<()> {
@@ -183,11 +186,14 @@ async fn process_update(client: &Caching<Client>, update: &FillUpdate) -> anyhow
     Ok(())
 }
 
+/// Start the workers that write fill updates to postgres
+///
+/// The workers keep going until every sender of the returned queue was dropped and
+/// the queue is empty, so awaiting them on shutdown writes out the queued updates.
 pub async fn init(
     config: &PostgresConfig,
     metrics_sender: Metrics,
-    exit: Arc<AtomicBool>,
-) -> anyhow::Result<async_channel::Sender<FillUpdate>> {
+) -> anyhow::Result<(async_channel::Sender<FillUpdate>, Vec<JoinHandle<()>>)> {
     // The actual message may want to also contain a retry count, if it self-reinserts on failure?
     let (fill_update_queue_sender, fill_update_queue_receiver) =
         async_channel::bounded::<FillUpdate>(config.max_queue_size);
@@ -200,12 +206,13 @@ pub async fn init(
         metrics_sender.register_u64("fills_postgres_connections_alive".into(), MetricType::Gauge);
 
     // postgres fill update sending worker threads
+    let mut workers = vec![];
     for _ in 0..config.connection_count {
         let postgres_account_writes = postgres_connection(
             config,
             metric_con_retries.clone(),
             metric_con_live.clone(),
-            exit.clone(),
+            fill_update_queue_receiver.clone(),
         )
         .await?;
         let fill_update_queue_receiver_c = fill_update_queue_receiver.clone();
@@ -213,24 +220,21 @@ pub async fn init(
         let mut metric_retries =
             metrics_sender.register_u64("fills_postgres_retries".into(), MetricType::Counter);
 
-        tokio::spawn(async move {
+        workers.push(tokio::spawn(async move {
             let mut client_opt = None;
             loop {
                 // Retrieve up to batch_size updates
                 let mut batch = Vec::new();
-                batch.push(
-                    fill_update_queue_receiver_c
-                        .recv()
-                        .await
-                        .expect("sender must stay alive"),
-                );
+                match fill_update_queue_receiver_c.recv().await {
+                    Ok(update) => batch.push(update),
+                    // closed and empty, everything was written
+                    Err(_) => break,
+                }
                 while batch.len() < config.max_batch_size {
                     match fill_update_queue_receiver_c.try_recv() {
                         Ok(update) => batch.push(update),
-                        Err(async_channel::TryRecvError::Empty) => break,
-                        Err(async_channel::TryRecvError::Closed) => {
-                            panic!("sender must stay alive")
-                        }
+                        Err(async_channel::TryRecvError::Empty)
+                        | Err(async_channel::TryRecvError::Closed) => break,
                     };
                 }
 
@@ -242,9 +246,23 @@ pub async fn init(
 
                 let mut error_count = 0;
                 loop {
-                    let client =
-                        update_postgres_client(&mut client_opt, &postgres_account_writes, &config)
-                            .await;
+                    let client = match update_postgres_client(
+                        &mut client_opt,
+                        &postgres_account_writes,
+                        &config,
+                    )
+                    .await
+                    {
+                        Ok(client) => client,
+                        Err(err) => {
+                            error!(
+                                "no postgres client, dropping {} fill updates: {:?}",
+                                batch.len(),
+                                err
+                            );
+                            return;
+                        }
+                    };
                     let mut results = futures::future::join_all(
                         batch.iter().map(|update| process_update(client, update)),
                     )
@@ -268,8 +286,9 @@ pub async fn init(
                     break;
                 }
             }
-        });
+            info!("fill update queue closed, postgres worker done");
+        }));
     }
 
-    Ok(fill_update_queue_sender)
+    Ok((fill_update_queue_sender, workers))
 }
@@ -15,8 +15,10 @@ use log::*;
 use mango_feeds_lib::{
     grpc_plugin_source, metrics,
     metrics::{MetricType, MetricU64},
-    websocket_source, FilterConfig, MarketConfig, MetricsConfig, PostgresConfig, SourceConfig,
-    StatusResponse,
+    recording_sink, replay_source, secrets,
+    shutdown::{self, CancellationToken},
+    websocket_source, ChainDataPersistenceConfig, FilterConfig, MarketConfig, MetricsConfig,
+    PostgresConfig, SourceConfig, StatusResponse,
 };
 use mango_v4_client::{Client, MangoGroupContext, TransactionBuilderConfig};
 use service_mango_fills::{Command, FillCheckpoint, FillEventFilterMessage, FillEventType};
@@ -27,24 +29,26 @@ use std::{
     io::Read,
     net::SocketAddr,
     str::FromStr,
-    sync::Arc,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Mutex,
-    },
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{
     net::{TcpListener, TcpStream},
     pin, time,
 };
-use tokio_tungstenite::tungstenite::{protocol::Message, Error};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame, Message},
+    Error,
+};
 
 use serde::Deserialize;
 
 type CheckpointMap = Arc<Mutex<HashMap<String, FillCheckpoint>>>;
 type PeerMap = Arc<Mutex<HashMap<SocketAddr, Peer>>>;
 
+// How long peers get to answer the close frame on shutdown
+const PEER_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 // jemalloc seems to be better at keeping the memory footprint reasonable over
 // longer periods of time
 #[global_allocator]
@@ -141,6 +145,24 @@ async fn handle_connection(
     Ok(())
 }
 
+/// Send a close frame to every peer and wait for them to disconnect
+async fn close_peers(peer_map: &PeerMap) {
+    for peer in peer_map.lock().unwrap().values() {
+        let _ = peer.sender.unbounded_send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "server shutting down".into(),
+        })));
+    }
+    let deadline = Instant::now() + PEER_CLOSE_TIMEOUT;
+    while Instant::now() < deadline {
+        let connected = peer_map.lock().unwrap().len();
+        if connected == 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
 fn handle_commands(
     addr: SocketAddr,
     msg: Message,
@@ -320,6 +342,7 @@ fn handle_commands(
 pub struct Config {
     pub source: SourceConfig,
     pub metrics: MetricsConfig,
+    pub persistence: Option<ChainDataPersistenceConfig>,
     pub postgres: Option<PostgresConfig>,
     pub bind_ws_addr: String,
     pub rpc_http_url: String,
@@ -329,7 +352,7 @@ pub struct Config {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args: Vec<String> = std::env::args().collect();
-    let exit: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let shutdown = CancellationToken::new();
 
     if args.len() < 2 {
         eprintln!("Please enter a config file path argument.");
@@ -345,6 +368,7 @@ async fn main() -> anyhow::Result<()> {
     };
 
     solana_logger::setup_with_default("info");
+    shutdown::cancel_on_signal(&shutdown, shutdown::DEFAULT_DEADLINE);
 
     let metrics_tx = metrics::start(config.metrics, "fills".into());
 
@@ -462,19 +486,23 @@ async fn main() -> anyhow::Result<()> {
         .collect();
     let market_pubkey_strings: HashMap<String, String> = [b].concat().into_iter().collect();
 
-    let postgres_update_sender = match config.postgres {
-        Some(postgres_config) => Some(
-            fill_event_postgres_target::init(&postgres_config, metrics_tx.clone(), exit.clone())
-                .await?,
-        ),
-        None => None,
+    let (postgres_update_sender, postgres_workers) = match config.postgres {
+        Some(postgres_config) => {
+            let (sender, workers) =
+                fill_event_postgres_target::init(&postgres_config, metrics_tx.clone()).await?;
+            (Some(sender), workers)
+        }
+        None => (None, vec![]),
     };
 
     let (account_write_queue_sender, slot_queue_sender, fill_receiver) = fill_event_filter::init(
         perp_market_configs.clone(),
         spot_market_configs.clone(),
+        config.persistence.clone(),
+        &config.source.queue_policy,
+        config.source.history_retention,
         metrics_tx.clone(),
-        exit.clone(),
+        shutdown.clone(),
     )
     .await?;
 
@@ -484,12 +512,12 @@ async fn main() -> anyhow::Result<()> {
     let checkpoints_ref_thread = checkpoints.clone();
     let peers_ref_thread = peers.clone();
     let peers_ref_thread1 = peers.clone();
+    let peers_ref_shutdown = peers.clone();
 
-    // filleventfilter websocket sink
-    tokio::spawn(async move {
+    // filleventfilter websocket sink, ends once the filter stopped
+    let fill_sink = tokio::spawn(async move {
         pin!(fill_receiver);
-        loop {
-            let message = fill_receiver.recv().await.unwrap();
+        while let Ok(message) = fill_receiver.recv().await {
             match message {
                 FillEventFilterMessage::Update(update) => {
                     debug!(
@@ -557,9 +585,17 @@ async fn main() -> anyhow::Result<()> {
     let try_socket = TcpListener::bind(&config.bind_ws_addr).await;
     let listener = try_socket.expect("Failed to bind");
     {
+        let shutdown = shutdown.clone();
         tokio::spawn(async move {
             // Let's spawn the handling of each connection in a separate task.
-            while let Ok((stream, addr)) = listener.accept().await {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(_) => break,
+                    },
+                    _ = shutdown.cancelled() => break,
+                };
                 tokio::spawn(handle_connection_error(
                     checkpoints.clone(),
                     peers.clone(),
@@ -592,23 +628,13 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
-    // handle sigint
-    {
-        let exit = exit.clone();
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.unwrap();
-            info!("Received SIGINT, shutting down...");
-            exit.store(true, Ordering::Relaxed);
-        });
-    }
-
     info!(
         "rpc connect: {}",
         config
             .source
             .grpc_sources
             .iter()
-            .map(|c| c.connection_string.clone())
+            .map(|c| secrets::redact_url(&c.connection_string))
             .collect::<String>()
     );
     let use_geyser = true;
@@ -617,26 +643,65 @@ async fn main() -> anyhow::Result<()> {
     let filter_config = FilterConfig {
         program_ids: vec![],
         account_ids: relevant_pubkeys,
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
     };
-    if use_geyser {
-        grpc_plugin_source::process_events(
+    let (account_write_queue_sender, slot_queue_sender) = match &config.source.recording {
+        Some(recording_config) => recording_sink::init(
+            recording_config,
+            account_write_queue_sender,
+            slot_queue_sender,
+            &config.source.queue_policy,
+            metrics_tx.clone(),
+        )?,
+        None => (account_write_queue_sender, slot_queue_sender),
+    };
+    if config.source.replay.is_some() {
+        let (_filter_handle, source) = replay_source::process_events(
             &config.source,
             &filter_config,
             account_write_queue_sender,
             slot_queue_sender,
+            None,
+            None,
             metrics_tx.clone(),
-            exit.clone(),
-        )
-        .await;
+            shutdown.clone(),
+        );
+        source.await?;
+    } else if use_geyser {
+        let (_filter_handle, source) = grpc_plugin_source::process_events(
+            &config.source,
+            &filter_config,
+            account_write_queue_sender,
+            slot_queue_sender,
+            None,
+            None,
+            metrics_tx.clone(),
+            shutdown.clone(),
+        );
+        source.await?;
     } else {
         websocket_source::process_events(
             &config.source,
             &filter_config,
             account_write_queue_sender,
             slot_queue_sender,
+            metrics_tx.clone(),
+            shutdown.clone(),
         )
-        .await;
+        .await?;
+    }
+
+    // The source only returns on shutdown, or when a replay finished
+    shutdown.cancel();
+    close_peers(&peers_ref_shutdown).await;
+    // the postgres queue closes once the fill sink is done
+    fill_sink.await?;
+    for worker in postgres_workers {
+        worker.await?;
     }
+    info!("shutdown complete");
 
     Ok(())
 }
@@ -20,20 +20,22 @@ use std::{
     io::Read,
     net::SocketAddr,
     str::FromStr,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{
     net::{TcpListener, TcpStream},
     pin, time,
 };
-use tokio_tungstenite::tungstenite::{protocol::Message, Error};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame, Message},
+    Error,
+};
 
 use mango_feeds_lib::{
-    grpc_plugin_source, metrics, websocket_source, MarketConfig, MetricsConfig, SourceConfig,
+    grpc_plugin_source, metrics, recording_sink, replay_source, secrets,
+    shutdown::{self, CancellationToken},
+    websocket_source, ChainDataPersistenceConfig, MarketConfig, MetricsConfig, SourceConfig,
 };
 use mango_feeds_lib::{
     metrics::{MetricType, MetricU64},
@@ -47,6 +49,9 @@ type LevelCheckpointMap = Arc<Mutex<HashMap<String, LevelCheckpoint>>>;
 type BookCheckpointMap = Arc<Mutex<HashMap<String, BookCheckpoint>>>;
 type PeerMap = Arc<Mutex<HashMap<SocketAddr, Peer>>>;
 
+// How long peers get to answer the close frame on shutdown
+const PEER_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag = "command")]
 pub enum Command {
@@ -100,6 +105,7 @@ pub struct Peer {
 pub struct Config {
     pub source: SourceConfig,
     pub metrics: MetricsConfig,
+    pub persistence: Option<ChainDataPersistenceConfig>,
     pub bind_ws_addr: String,
     pub rpc_http_url: String,
     pub mango_group: String,
@@ -190,6 +196,24 @@ async fn handle_connection(
     Ok(())
 }
 
+/// Send a close frame to every peer and wait for them to disconnect
+async fn close_peers(peer_map: &PeerMap) {
+    for peer in peer_map.lock().unwrap().values() {
+        let _ = peer.sender.unbounded_send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "server shutting down".into(),
+        })));
+    }
+    let deadline = Instant::now() + PEER_CLOSE_TIMEOUT;
+    while Instant::now() < deadline {
+        let connected = peer_map.lock().unwrap().len();
+        if connected == 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
 fn handle_commands(
     addr: SocketAddr,
     msg: Message,
@@ -315,7 +339,8 @@ where
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     solana_logger::setup_with_default("info");
-    let exit: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let shutdown = CancellationToken::new();
+    shutdown::cancel_on_signal(&shutdown, shutdown::DEFAULT_DEADLINE);
 
     // load config
     let args: Vec<String> = std::env::args().collect();
@@ -428,8 +453,11 @@ async fn main() -> anyhow::Result<()> {
         orderbook_filter::init(
             market_configs.clone(),
             serum_market_configs.clone(),
+            config.persistence.clone(),
+            &config.source.queue_policy,
+            config.source.history_retention,
             metrics_tx.clone(),
-            exit.clone(),
+            shutdown.clone(),
         )
         .await?;
 
@@ -437,21 +465,14 @@ async fn main() -> anyhow::Result<()> {
     let book_checkpoints = BookCheckpointMap::new(Mutex::new(HashMap::new()));
     let peers = PeerMap::new(Mutex::new(HashMap::new()));
 
-    // orderbook receiver
-    {
+    // orderbook receiver, ends once the filter stopped
+    let orderbook_sink = {
         let level_checkpoints = level_checkpoints.clone();
         let book_checkpoints = book_checkpoints.clone();
         let peers = peers.clone();
-        let exit = exit.clone();
         tokio::spawn(async move {
             pin!(orderbook_receiver);
-            loop {
-                if exit.load(Ordering::Relaxed) {
-                    warn!("shutting down orderbook receiver...");
-                    break;
-                }
-
-                let message: OrderbookFilterMessage = orderbook_receiver.recv().await.unwrap();
+            while let Ok(message) = orderbook_receiver.recv().await {
                 match message {
                     OrderbookFilterMessage::LevelUpdate(update) => {
                         debug!("ws level update {} {:?}", update.market, update.side);
@@ -505,23 +526,30 @@ async fn main() -> anyhow::Result<()> {
                     }
                 }
             }
-        });
-    }
+            warn!("shutting down orderbook receiver...");
+        })
+    };
 
     // websocket server
     {
         info!("ws listen: {}", config.bind_ws_addr);
         let try_socket = TcpListener::bind(&config.bind_ws_addr).await;
         let listener = try_socket.expect("Failed to bind");
-        let exit = exit.clone();
+        let shutdown = shutdown.clone();
         let peers = peers.clone();
         tokio::spawn(async move {
             // Let's spawn the handling of each connection in a separate task.
-            while let Ok((stream, addr)) = listener.accept().await {
-                if exit.load(Ordering::Relaxed) {
-                    warn!("shutting down websocket server...");
-                    break;
-                }
+            loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(_) => break,
+                    },
+                    _ = shutdown.cancelled() => {
+                        warn!("shutting down websocket server...");
+                        break;
+                    }
+                };
                 tokio::spawn(handle_connection_error(
                     level_checkpoints.clone(),
                     book_checkpoints.clone(),
@@ -538,18 +566,19 @@ async fn main() -> anyhow::Result<()> {
 
     // keepalive
     {
-        let exit = exit.clone();
+        let shutdown = shutdown.clone();
         let peers = peers.clone();
         tokio::spawn(async move {
             let mut write_interval = time::interval(time::Duration::from_secs(30));
 
             loop {
-                if exit.load(Ordering::Relaxed) {
-                    warn!("shutting down keepalive...");
-                    break;
+                tokio::select! {
+                    _ = write_interval.tick() => {}
+                    _ = shutdown.cancelled() => {
+                        warn!("shutting down keepalive...");
+                        break;
+                    }
                 }
-
-                write_interval.tick().await;
                 let peers_copy = peers.lock().unwrap().clone();
                 for (addr, peer) in peers_copy.iter() {
                     let pl = Vec::new();
@@ -562,23 +591,13 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
-    // handle sigint
-    {
-        let exit = exit.clone();
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.unwrap();
-            info!("Received SIGINT, shutting down...");
-            exit.store(true, Ordering::Relaxed);
-        });
-    }
-
     info!(
         "rpc connect: {}",
         config
             .source
             .grpc_sources
             .iter()
-            .map(|c| c.connection_string.clone())
+            .map(|c| secrets::redact_url(&c.connection_string))
             .collect::<String>()
     );
 
@@ -598,27 +617,63 @@ async fn main() -> anyhow::Result<()> {
         ]
         .concat()
         .to_vec(),
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
     };
     let use_geyser = true;
-    if use_geyser {
-        grpc_plugin_source::process_events(
+    let (account_write_queue_sender, slot_queue_sender) = match &config.source.recording {
+        Some(recording_config) => recording_sink::init(
+            recording_config,
+            account_write_queue_sender,
+            slot_queue_sender,
+            &config.source.queue_policy,
+            metrics_tx.clone(),
+        )?,
+        None => (account_write_queue_sender, slot_queue_sender),
+    };
+    if config.source.replay.is_some() {
+        let (_filter_handle, source) = replay_source::process_events(
             &config.source,
             &filter_config,
             account_write_queue_sender,
             slot_queue_sender,
+            None,
+            None,
             metrics_tx.clone(),
-            exit.clone(),
-        )
-        .await;
+            shutdown.clone(),
+        );
+        source.await?;
+    } else if use_geyser {
+        let (_filter_handle, source) = grpc_plugin_source::process_events(
+            &config.source,
+            &filter_config,
+            account_write_queue_sender,
+            slot_queue_sender,
+            None,
+            None,
+            metrics_tx.clone(),
+            shutdown.clone(),
+        );
+        source.await?;
     } else {
         websocket_source::process_events(
             &config.source,
             &filter_config,
             account_write_queue_sender,
             slot_queue_sender,
+            metrics_tx.clone(),
+            shutdown.clone(),
         )
-        .await;
+        .await?;
     }
 
+    // The source only returns on shutdown, or when a replay finished
+    shutdown.cancel();
+    close_peers(&peers).await;
+    // the orderbook filter saved its state once the receiver is done
+    orderbook_sink.await?;
+    info!("shutdown complete");
+
     Ok(())
 }
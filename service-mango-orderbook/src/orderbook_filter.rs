@@ -8,9 +8,14 @@ use mango_feeds_lib::{
     OrderbookSide,
 };
 use mango_feeds_lib::{
-    chain_data::{AccountData, ChainData, ChainDataMetrics, SlotData},
+    chain_data::{
+        AccountData, AccountState, ChainData, ChainDataMetrics, ChainDataPersistence,
+        HistoryRetention, SlotData,
+    },
     metrics::{MetricType, Metrics},
-    AccountWrite, SlotUpdate,
+    queue,
+    shutdown::CancellationToken,
+    AccountWrite, ChainDataPersistenceConfig, QueuePolicy, SlotUpdate,
 };
 use mango_v4::accounts_zerocopy::{AccountReader, KeyedAccountReader};
 use mango_v4::state::OracleConfigParams;
@@ -33,10 +38,6 @@ use std::borrow::BorrowMut;
 use std::{
     collections::{HashMap, HashSet},
     mem::size_of,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -238,8 +239,11 @@ fn publish_changes(
 pub async fn init(
     market_configs: Vec<(Pubkey, MarketConfig)>,
     serum_market_configs: Vec<(Pubkey, MarketConfig)>,
+    persistence_config: Option<ChainDataPersistenceConfig>,
+    queue_policy: &QueuePolicy,
+    history_retention: Option<HistoryRetention>,
     metrics_sender: Metrics,
-    exit: Arc<AtomicBool>,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<(
     async_channel::Sender<AccountWrite>,
     async_channel::Sender<SlotUpdate>,
@@ -252,21 +256,28 @@ pub async fn init(
 
     // The actual message may want to also contain a retry count, if it self-reinserts on failure?
     let (account_write_queue_sender, account_write_queue_receiver) =
-        async_channel::unbounded::<AccountWrite>();
+        queue::channel::<AccountWrite>(queue_policy);
 
     // Slot updates flowing from the outside into the single processing thread. From
     // there they'll flow into the postgres sending thread.
-    let (slot_queue_sender, slot_queue_receiver) = async_channel::unbounded::<SlotUpdate>();
+    let (slot_queue_sender, slot_queue_receiver) = queue::channel::<SlotUpdate>(queue_policy);
 
     // Book updates can be consumed by client connections, they contain L2 and L3 updates for all markets
     let (book_update_sender, book_update_receiver) =
         async_channel::unbounded::<OrderbookFilterMessage>();
 
-    let mut chain_cache = ChainData::new();
+    let mut chain_persistence =
+        persistence_config.map(|config| ChainDataPersistence::new(&config, &metrics_sender));
+    // warm-start from the saved rooted state, the live snapshot will replace it
+    let mut chain_cache = match &chain_persistence {
+        Some(persistence) => persistence.load(),
+        None => ChainData::new(),
+    };
+    chain_cache.set_history_retention(history_retention);
+    chain_cache.enable_reorg_events();
     let mut chain_data_metrics = ChainDataMetrics::new(&metrics_sender);
     let mut bookside_cache: HashMap<String, Vec<Order>> = HashMap::new();
     let mut serum_bookside_cache: HashMap<String, Vec<Order>> = HashMap::new();
-    let mut last_write_versions = HashMap::<String, (u64, u64)>::new();
 
     let mut relevant_pubkeys = [market_configs.clone(), serum_market_configs.clone()]
         .concat()
@@ -277,11 +288,20 @@ pub async fn init(
     relevant_pubkeys.extend(market_configs.iter().map(|(_, cfg)| cfg.oracle));
 
     info!("relevant_pubkeys {:?}", relevant_pubkeys);
+
+    // Pubkeys whose live write changed since the books were last processed, starting
+    // out with everything that was loaded from disk
+    let mut pending_pubkeys: HashSet<Pubkey> =
+        chain_cache.iter_accounts().map(|(pk, _)| *pk).collect();
+
     // update handling thread, reads both slots and account updates
     tokio::spawn(async move {
         loop {
-            if exit.load(Ordering::Relaxed) {
+            if shutdown.is_cancelled() {
                 warn!("shutting down orderbook_filter...");
+                if let Some(persistence) = chain_persistence.as_mut() {
+                    persistence.save(&chain_cache).await;
+                }
                 break;
             }
             tokio::select! {
@@ -289,11 +309,12 @@ pub async fn init(
                     if !relevant_pubkeys.contains(&account_write.pubkey) {
                         continue;
                     }
-                    chain_cache.update_account(
+                    let changed = chain_cache.update_account(
                         account_write.pubkey,
                         AccountData {
                             slot: account_write.slot,
                             write_version: account_write.write_version,
+                            data_slice: account_write.data_slice,
                             account: WritableAccount::create(
                                 account_write.lamports,
                                 account_write.data.clone(),
@@ -303,19 +324,35 @@ pub async fn init(
                             ),
                         },
                     );
+                    if changed {
+                        pending_pubkeys.insert(account_write.pubkey);
+                    }
                 }
                 Ok(slot_update) = slot_queue_receiver.recv() => {
-                    chain_cache.update_slot(SlotData {
+                    let changed = chain_cache.update_slot(SlotData {
                         slot: slot_update.slot,
                         parent: slot_update.parent,
                         status: slot_update.status,
                         chain: 0,
                     });
-
+                    pending_pubkeys.extend(changed);
+                    for reorg in chain_cache.take_reorg_events() {
+                        info!(
+                            "reorg from slot {} to {}, common ancestor {}, {} accounts changed",
+                            reorg.old_tip,
+                            reorg.new_tip,
+                            reorg.common_ancestor,
+                            reorg.accounts.len()
+                        );
+                    }
                 }
+                _ = shutdown.cancelled() => continue,
             }
 
             chain_data_metrics.report(&chain_cache);
+            if let Some(persistence) = chain_persistence.as_mut() {
+                persistence.save_if_due(&chain_cache);
+            }
 
             for mkt in market_configs.iter() {
                 for side in 0..2 {
@@ -323,39 +360,42 @@ pub async fn init(
                     let side_pk = if side == 0 { mkt.1.bids } else { mkt.1.asks };
                     let other_side_pk = if side == 0 { mkt.1.asks } else { mkt.1.bids };
                     let oracle_pk = mkt.1.oracle;
-                    let last_side_write_version = last_write_versions
-                        .get(&side_pk.to_string())
-                        .unwrap_or(&(0, 0));
-                    let last_oracle_write_version = last_write_versions
-                        .get(&oracle_pk.to_string())
-                        .unwrap_or(&(0, 0));
+                    if !pending_pubkeys.contains(&side_pk) && !pending_pubkeys.contains(&oracle_pk)
+                    {
+                        // neither bookside nor oracle was updated
+                        continue;
+                    }
 
                     match (
-                        chain_cache.account(&side_pk),
+                        chain_cache.account_state(&side_pk),
                         chain_cache.account(&oracle_pk),
                     ) {
-                        (Ok(side_info), Ok(oracle_info)) => {
+                        (Ok(AccountState::Deleted(side_info)), _) => {
+                            info!("bookside {} was closed in slot {}", side_pk, side_info.slot);
                             let side_pk_string = side_pk.to_string();
-                            let oracle_pk_string = oracle_pk.to_string();
-
-                            if !side_info
-                                .is_newer_than(last_side_write_version.0, last_side_write_version.1)
-                                && !oracle_info.is_newer_than(
-                                    last_oracle_write_version.0,
-                                    last_oracle_write_version.1,
-                                )
-                            {
-                                // neither bookside nor oracle was updated
-                                continue;
+                            // peers still hold the orders of the closed side, publish it as empty
+                            if let Some(old_bookside) = bookside_cache.get(&side_pk_string) {
+                                publish_changes(
+                                    side_info.slot,
+                                    side_info.write_version,
+                                    mkt,
+                                    if side == 0 {
+                                        OrderbookSide::Bid
+                                    } else {
+                                        OrderbookSide::Ask
+                                    },
+                                    &Vec::new(),
+                                    old_bookside,
+                                    bookside_cache.get(&other_side_pk.to_string()),
+                                    &book_update_sender,
+                                    &mut metric_book_events_new,
+                                    &mut metric_level_events_new,
+                                );
                             }
-                            last_write_versions.insert(
-                                side_pk_string.clone(),
-                                (side_info.slot, side_info.write_version),
-                            );
-                            last_write_versions.insert(
-                                oracle_pk_string.clone(),
-                                (oracle_info.slot, oracle_info.write_version),
-                            );
+                            bookside_cache.insert(side_pk_string, Vec::new());
+                        }
+                        (Ok(AccountState::Live(side_info)), Ok(oracle_info)) => {
+                            let side_pk_string = side_pk.to_string();
 
                             let keyed_account = KeyedSharedDataAccountReader {
                                 key: oracle_pk,
@@ -444,20 +484,41 @@ pub async fn init(
                 for side in 0..2 {
                     let side_pk = if side == 0 { mkt.1.bids } else { mkt.1.asks };
                     let other_side_pk = if side == 0 { mkt.1.asks } else { mkt.1.bids };
-                    let last_write_version = last_write_versions
-                        .get(&side_pk.to_string())
-                        .unwrap_or(&(0, 0));
+                    if !pending_pubkeys.contains(&side_pk) {
+                        continue;
+                    }
 
-                    match chain_cache.account(&side_pk) {
-                        Ok(account_info) => {
+                    match chain_cache.account_state(&side_pk) {
+                        Ok(AccountState::Deleted(account_info)) => {
+                            info!(
+                                "bookside {} was closed in slot {}",
+                                side_pk, account_info.slot
+                            );
                             let side_pk_string = side_pk.to_string();
-
-                            let write_version = (account_info.slot, account_info.write_version);
-                            // todo: should this be <= so we don't overwrite with old data received late?
-                            if write_version <= *last_write_version {
-                                continue;
+                            // peers still hold the orders of the closed side, publish it as empty
+                            if let Some(old_bookside) = serum_bookside_cache.get(&side_pk_string) {
+                                publish_changes(
+                                    account_info.slot,
+                                    account_info.write_version,
+                                    mkt,
+                                    if side == 0 {
+                                        OrderbookSide::Bid
+                                    } else {
+                                        OrderbookSide::Ask
+                                    },
+                                    &Vec::new(),
+                                    old_bookside,
+                                    serum_bookside_cache.get(&other_side_pk.to_string()),
+                                    &book_update_sender,
+                                    &mut metric_book_events_new,
+                                    &mut metric_level_events_new,
+                                );
                             }
-                            last_write_versions.insert(side_pk_string.clone(), write_version);
+                            serum_bookside_cache.insert(side_pk_string, Vec::new());
+                        }
+                        Ok(AccountState::Live(account_info)) => {
+                            let side_pk_string = side_pk.to_string();
+
                             debug!("W {}", mkt.1.name);
                             let account = &mut account_info.account.clone();
                             let data = account.data_as_mut_slice();
@@ -519,6 +580,7 @@ pub async fn init(
                     }
                 }
             }
+            pending_pubkeys.clear();
         }
     });
 
@@ -1,6 +1,7 @@
 use {
     log::*,
-    mango_feeds_lib::chain_data::ChainData,
+    mango_feeds_lib::chain_data::{ChainData, ChainDataPersistence},
+    mango_feeds_lib::shutdown::CancellationToken,
     mango_feeds_lib::*,
     serde_derive::{Deserialize, Serialize},
     solana_sdk::pubkey::Pubkey,
@@ -9,7 +10,7 @@ use {
         fs::File,
         io::Read,
         mem::size_of,
-        sync::{atomic::AtomicBool, Arc, RwLock},
+        sync::{Arc, RwLock},
         time::Duration,
     },
 };
@@ -43,6 +44,7 @@ pub struct Config {
     pub metrics: MetricsConfig,
     pub pnl: PnlConfig,
     pub jsonrpc_server: JsonRpcConfig,
+    pub persistence: Option<ChainDataPersistenceConfig>,
 }
 
 type PnlData = Vec<(Pubkey, Vec<(PerpMarketIndex, I80F48)>)>;
@@ -224,7 +226,7 @@ fn start_jsonrpc_server(
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let exit: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let shutdown = CancellationToken::new();
 
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
@@ -240,6 +242,7 @@ async fn main() -> anyhow::Result<()> {
     };
 
     solana_logger::setup_with_default("info");
+    shutdown::cancel_on_signal(&shutdown, shutdown::DEFAULT_DEADLINE);
     info!("startup");
 
     let rpc_url = config.snapshot_source.rpc_http_url;
@@ -278,7 +281,16 @@ async fn main() -> anyhow::Result<()> {
     let metrics_pnls_tracked = metrics_tx.register_u64("pnl_num_tracked".into(), MetricType::Gauge);
 
     // BUG: This shadows the previous chain_data and means this can't actually get data!
-    let chain_data = Arc::new(RwLock::new(ChainData::new()));
+    let chain_persistence = config
+        .persistence
+        .as_ref()
+        .map(|persistence_config| ChainDataPersistence::new(persistence_config, &metrics_tx));
+    let chain_data = Arc::new(RwLock::new(match &chain_persistence {
+        Some(persistence) => persistence.load(),
+        None => ChainData::new(),
+    }));
+    let persist_job = chain_persistence
+        .map(|persistence| persistence.spawn_persist_job(chain_data.clone(), shutdown.clone()));
     let pnl_data = Arc::new(RwLock::new(PnlData::new()));
 
     start_pnl_updater(
@@ -299,20 +311,60 @@ async fn main() -> anyhow::Result<()> {
     )?;
 
     // start filling chain_data from the grpc plugin source
-    let (account_write_queue_sender, slot_queue_sender) = memory_target::init(chain_data).await?;
-    let filter_config = FilterConfig {
+    let (account_write_queue_sender, slot_queue_sender) = memory_target::init(
+        chain_data,
+        &config.source.queue_policy,
+        config.source.history_retention,
+        shutdown.clone(),
+    )
+    .await?;
+    // Account filters apply to the listed accounts as well, so the group account can't
+    // be part of the filter for mango accounts: it gets a second one.
+    let mango_account_filter = FilterConfig {
         program_ids: vec!["4MangoMjqJ2firMokCjjGgoK8d4MXcrgL7XJaL3w6fVg".into()],
         account_ids: vec![],
+        // only subscribe to mango accounts, the pnl updater ignores everything else
+        account_filters: vec![AccountFilter::Memcmp {
+            offset: 0,
+            bytes: bs58::encode(MangoAccount::discriminator()).into_string(),
+        }],
+        transaction_filter: None,
+        data_slice: None,
+    };
+    let group_filter = FilterConfig {
+        program_ids: vec![],
+        account_ids: vec![config.pnl.mango_group.clone()],
+        account_filters: vec![],
+        transaction_filter: None,
+        data_slice: None,
     };
-    grpc_plugin_source::process_events(
+    let (_mango_account_filter_handle, mango_account_source) = grpc_plugin_source::process_events(
+        &config.source,
+        &mango_account_filter,
+        account_write_queue_sender.clone(),
+        slot_queue_sender.clone(),
+        None,
+        None,
+        metrics_tx.clone(),
+        shutdown.clone(),
+    );
+    let (_group_filter_handle, group_source) = grpc_plugin_source::process_events(
         &config.source,
-        &filter_config,
+        &group_filter,
         account_write_queue_sender,
         slot_queue_sender,
+        None,
+        None,
         metrics_tx.clone(),
-        exit.clone(),
-    )
-    .await;
+        shutdown.clone(),
+    );
+    tokio::try_join!(mango_account_source, group_source)?;
+
+    // the sources only return on shutdown, wait for the last save
+    if let Some(persist_job) = persist_job {
+        persist_job.await?;
+    }
+    info!("shutdown complete");
 
     Ok(())
 }